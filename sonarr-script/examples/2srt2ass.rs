@@ -3,7 +3,6 @@ use std::path::PathBuf;
 
 use anyhow::Result;
 use aspasia::AssSubtitle;
-use aspasia::SubRipSubtitle;
 use aspasia::Subtitle;
 use aspasia::TextEventInterface;
 use aspasia::TextSubtitle;
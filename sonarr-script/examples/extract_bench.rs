@@ -0,0 +1,88 @@
+use std::path::PathBuf;
+use std::process::Command;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::Result;
+use anyhow::bail;
+use clap::Parser;
+
+/// Compares extracting subtitle streams with one ffmpeg run per stream against
+/// a single ffmpeg run with one output per stream.
+#[derive(Debug, Parser)]
+struct Cli {
+    /// Media file to extract from
+    media_file: PathBuf,
+
+    /// Stream IDs to extract, eg. `0:2`
+    #[clap(required = true)]
+    streams: Vec<String>,
+
+    /// Directory to write extracted subtitles to
+    #[clap(long, default_value = "/tmp/extract_bench")]
+    output_dir: PathBuf,
+}
+
+fn ffmpeg(args: &[String]) -> Result<()> {
+    let status = Command::new("ffmpeg")
+        .args(["-nostdin", "-y", "-loglevel", "error"])
+        .args(args)
+        .status()?;
+    if !status.success() {
+        bail!("ffmpeg failed: {status}");
+    }
+    Ok(())
+}
+
+fn output(cli: &Cli, stream: &str) -> String {
+    let name = format!("{}.srt", stream.replace(':', "_"));
+    cli.output_dir.join(name).to_string_lossy().into_owned()
+}
+
+fn per_stream(cli: &Cli) -> Result<Duration> {
+    let started = Instant::now();
+    for stream in &cli.streams {
+        ffmpeg(&[
+            "-i".into(),
+            cli.media_file.to_string_lossy().into_owned(),
+            "-map".into(),
+            stream.clone(),
+            "-c:s".into(),
+            "srt".into(),
+            output(cli, stream),
+        ])?;
+    }
+    Ok(started.elapsed())
+}
+
+fn single_pass(cli: &Cli) -> Result<Duration> {
+    let started = Instant::now();
+    let mut args = vec!["-i".into(), cli.media_file.to_string_lossy().into_owned()];
+    for stream in &cli.streams {
+        args.extend([
+            "-map".into(),
+            stream.clone(),
+            "-c:s".into(),
+            "srt".into(),
+            output(cli, stream),
+        ]);
+    }
+    ffmpeg(&args)?;
+    Ok(started.elapsed())
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    std::fs::create_dir_all(&cli.output_dir)?;
+
+    let per_stream = per_stream(&cli)?;
+    println!("per stream:  {per_stream:?}");
+    let single_pass = single_pass(&cli)?;
+    println!("single pass: {single_pass:?}");
+    println!(
+        "speedup:     {:.1}x",
+        per_stream.as_secs_f64() / single_pass.as_secs_f64()
+    );
+
+    Ok(())
+}
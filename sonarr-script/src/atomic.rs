@@ -0,0 +1,89 @@
+//! Writes files so that readers only ever see the old or the new contents,
+//! never a partly written file, even if the process dies halfway.
+
+use std::fs::File;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use anyhow::Result;
+
+/// Temporary path that `replace_with` has the new contents written to. It is
+/// in the same directory, so the final rename cannot cross file systems.
+pub fn temp_path(path: &Path) -> Result<PathBuf> {
+    let file_name = path
+        .file_name()
+        .with_context(|| format!("{} has no file name", path.display()))?;
+    Ok(path.with_file_name(format!(
+        ".{}.{}.tmp",
+        file_name.to_string_lossy(),
+        std::process::id()
+    )))
+}
+
+/// Replaces the file at `path` with whatever `write` writes to the temporary
+/// path it is given. The new file is synced to disk before it is renamed
+/// over the old one, and the temporary file is removed if anything fails.
+pub fn replace_with<T>(path: &Path, write: impl FnOnce(&Path) -> Result<T>) -> Result<T> {
+    let tmp = temp_path(path)?;
+    let result = write(&tmp).and_then(|value| {
+        File::open(&tmp)?.sync_all()?;
+        std::fs::rename(&tmp, path)
+            .with_context(|| format!("failed replacing {}", path.display()))?;
+        Ok(value)
+    });
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+        return result;
+    }
+    // Makes the rename itself durable. Not every platform can open a
+    // directory, and the file is in place either way.
+    if let Some(dir) = path.parent()
+        && let Ok(dir) = File::open(if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        })
+    {
+        let _ = dir.sync_all();
+    }
+    result
+}
+
+/// Atomic `std::fs::write`.
+pub fn write(path: &Path, contents: impl AsRef<[u8]>) -> Result<()> {
+    replace_with(path, |tmp| Ok(std::fs::write(tmp, contents)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replace_with_keeps_old_contents_on_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("episode.en.srt");
+        write(&path, "old").unwrap();
+
+        let result: Result<()> = replace_with(&path, |tmp| {
+            std::fs::write(tmp, "half")?;
+            anyhow::bail!("crashed halfway")
+        });
+
+        assert!(result.is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "old");
+        let files: Vec<_> = std::fs::read_dir(dir.path()).unwrap().collect();
+        assert_eq!(files.len(), 1, "temporary file left behind");
+    }
+
+    #[test]
+    fn test_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("episode.en.srt");
+
+        write(&path, "old").unwrap();
+        write(&path, "new").unwrap();
+
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new");
+    }
+}
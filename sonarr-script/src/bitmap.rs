@@ -0,0 +1,639 @@
+//! Decoders for bitmap subtitles, Blu-ray PGS and DVD VobSub, into images
+//! that can be fed to OCR.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use anyhow::Result;
+use anyhow::bail;
+
+use crate::matroska::BitmapBlock;
+
+/// How long an event lasts when nothing says when it ends
+const DEFAULT_EVENT_DURATION_MS: i64 = 5000;
+
+/// Blank border around images, which OCR engines need to find text near the
+/// edges
+const PADDING: usize = 10;
+
+/// Largest PGS video size accepted, above any Blu-ray resolution
+const MAX_PGS_VIDEO_SIZE: usize = 4096;
+
+/// A subtitle image with the luma and alpha of every pixel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitmap {
+    pub width: usize,
+    pub height: usize,
+    /// `(luma, alpha)`, row by row
+    pub pixels: Vec<(u8, u8)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BitmapEvent {
+    /// Milliseconds from the start of the file
+    pub start: i64,
+    pub end: i64,
+    pub bitmap: Bitmap,
+}
+
+impl Bitmap {
+    fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![(0, 0); width * height],
+        }
+    }
+
+    /// Encodes the image as a binary PGM with dark text on a white
+    /// background, which is what OCR engines read best. Subtitles are mostly
+    /// light text with a dark outline, so the brighter and more opaque a
+    /// pixel is, the darker it becomes.
+    pub fn to_pgm(&self) -> Vec<u8> {
+        let width = self.width + 2 * PADDING;
+        let height = self.height + 2 * PADDING;
+        let mut pgm = format!("P5\n{width} {height}\n255\n").into_bytes();
+        let header = pgm.len();
+        pgm.resize(header + width * height, 255);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let (luma, alpha) = self.pixels[y * self.width + x];
+                let ink = luma as u32 * alpha as u32 / 255;
+                pgm[header + (y + PADDING) * width + x + PADDING] = 255 - ink as u8;
+            }
+        }
+        pgm
+    }
+}
+
+/// Decodes PGS display sets into events. A display set without objects
+/// clears the screen, ending the event before it.
+pub fn decode_pgs(blocks: &[BitmapBlock]) -> Result<Vec<BitmapEvent>> {
+    let mut palettes: HashMap<u8, [(u8, u8); 256]> = HashMap::new();
+    let mut objects: HashMap<u16, PgsObject> = HashMap::new();
+    let mut events: Vec<BitmapEvent> = Vec::new();
+    let mut showing: Option<(i64, Option<i64>, Bitmap)> = None;
+
+    for block in blocks {
+        let mut composition = None;
+        let mut data = block.data.as_slice();
+        while data.len() >= 3 {
+            let kind = data[0];
+            let size = u16::from_be_bytes([data[1], data[2]]) as usize;
+            let Some(segment) = data.get(3..3 + size) else {
+                bail!("truncated PGS segment at {}ms", block.start);
+            };
+            data = &data[3 + size..];
+            match kind {
+                0x14 => read_pgs_palette(segment, &mut palettes)?,
+                0x15 => read_pgs_object(segment, &mut objects)?,
+                0x16 => composition = Some(read_pgs_composition(segment)?),
+                // Windows and the end of the display set
+                _ => {}
+            }
+        }
+
+        let Some(composition) = composition else {
+            continue;
+        };
+        if let Some((start, duration, bitmap)) = showing.take() {
+            let end = duration.map_or(block.start, |d| block.start.min(start + d));
+            events.push(BitmapEvent { start, end, bitmap });
+        }
+        if composition.objects.is_empty() {
+            continue;
+        }
+        let palette = palettes
+            .get(&composition.palette_id)
+            .copied()
+            .unwrap_or([(0, 0); 256]);
+        let bitmap = compose_pgs(&composition, &objects, &palette)?;
+        showing = Some((block.start, block.duration, bitmap));
+    }
+    if let Some((start, duration, bitmap)) = showing {
+        let end = start + duration.unwrap_or(DEFAULT_EVENT_DURATION_MS);
+        events.push(BitmapEvent { start, end, bitmap });
+    }
+
+    Ok(events)
+}
+
+struct PgsComposition {
+    /// Size of the video the objects are placed on
+    width: usize,
+    height: usize,
+    palette_id: u8,
+    /// Object IDs and where they are placed on screen
+    objects: Vec<(u16, usize, usize)>,
+}
+
+struct PgsObject {
+    width: usize,
+    height: usize,
+    rle: Vec<u8>,
+}
+
+fn read_pgs_composition(segment: &[u8]) -> Result<PgsComposition> {
+    if segment.len() < 11 {
+        bail!("truncated PGS composition");
+    }
+    let width = u16::from_be_bytes([segment[0], segment[1]]) as usize;
+    let height = u16::from_be_bytes([segment[2], segment[3]]) as usize;
+    if width > MAX_PGS_VIDEO_SIZE || height > MAX_PGS_VIDEO_SIZE {
+        bail!("PGS video size {width}x{height} is too large");
+    }
+    let palette_id = segment[9];
+    let count = segment[10] as usize;
+    let mut objects = Vec::new();
+    let mut rest = &segment[11..];
+    for _ in 0..count {
+        if rest.len() < 8 {
+            bail!("truncated PGS composition object");
+        }
+        let id = u16::from_be_bytes([rest[0], rest[1]]);
+        let cropped = rest[3] & 0x40 != 0;
+        let x = u16::from_be_bytes([rest[4], rest[5]]) as usize;
+        let y = u16::from_be_bytes([rest[6], rest[7]]) as usize;
+        objects.push((id, x, y));
+        rest = rest.get(if cropped { 16 } else { 8 }..).unwrap_or_default();
+    }
+    Ok(PgsComposition {
+        width,
+        height,
+        palette_id,
+        objects,
+    })
+}
+
+fn read_pgs_palette(segment: &[u8], palettes: &mut HashMap<u8, [(u8, u8); 256]>) -> Result<()> {
+    if segment.len() < 2 {
+        bail!("truncated PGS palette");
+    }
+    let palette = palettes.entry(segment[0]).or_insert([(0, 0); 256]);
+    // Entries are ID, Y, Cr, Cb and alpha
+    for entry in segment[2..].chunks_exact(5) {
+        palette[entry[0] as usize] = (entry[1], entry[4]);
+    }
+    Ok(())
+}
+
+fn read_pgs_object(segment: &[u8], objects: &mut HashMap<u16, PgsObject>) -> Result<()> {
+    if segment.len() < 4 {
+        bail!("truncated PGS object");
+    }
+    let id = u16::from_be_bytes([segment[0], segment[1]]);
+    let first = segment[3] & 0x80 != 0;
+    if first {
+        if segment.len() < 11 {
+            bail!("truncated PGS object");
+        }
+        objects.insert(
+            id,
+            PgsObject {
+                width: u16::from_be_bytes([segment[7], segment[8]]) as usize,
+                height: u16::from_be_bytes([segment[9], segment[10]]) as usize,
+                rle: segment[11..].to_vec(),
+            },
+        );
+    } else if let Some(object) = objects.get_mut(&id) {
+        object.rle.extend(&segment[4..]);
+    }
+    Ok(())
+}
+
+/// Draws the objects of a composition onto one image just big enough to hold
+/// them all. Objects must fit on the video, which bounds the image size.
+fn compose_pgs(
+    composition: &PgsComposition,
+    objects: &HashMap<u16, PgsObject>,
+    palette: &[(u8, u8); 256],
+) -> Result<Bitmap> {
+    let placed: Vec<_> = composition
+        .objects
+        .iter()
+        .filter_map(|(id, x, y)| Some((objects.get(id)?, *x, *y)))
+        .collect();
+    for (object, x, y) in &placed {
+        if x + object.width > composition.width || y + object.height > composition.height {
+            bail!(
+                "PGS object of {}x{} at ({x}, {y}) does not fit on {}x{} video",
+                object.width,
+                object.height,
+                composition.width,
+                composition.height
+            );
+        }
+    }
+    let Some(left) = placed.iter().map(|(_, x, _)| *x).min() else {
+        bail!("PGS composition refers to unknown objects");
+    };
+    let top = placed.iter().map(|(_, _, y)| *y).min().unwrap_or(0);
+    let right = placed
+        .iter()
+        .map(|(o, x, _)| x + o.width)
+        .max()
+        .unwrap_or(0);
+    let bottom = placed
+        .iter()
+        .map(|(o, _, y)| y + o.height)
+        .max()
+        .unwrap_or(0);
+
+    let mut bitmap = Bitmap::new(right - left, bottom - top);
+    for (object, x, y) in placed {
+        let indices = decode_pgs_rle(&object.rle, object.width, object.height);
+        for row in 0..object.height {
+            for column in 0..object.width {
+                let index = indices[row * object.width + column];
+                let pixel = (y - top + row) * bitmap.width + x - left + column;
+                bitmap.pixels[pixel] = palette[index as usize];
+            }
+        }
+    }
+    Ok(bitmap)
+}
+
+/// Expands PGS run-length encoded pixels into palette indices. Malformed data
+/// leaves the rest of the image transparent rather than failing.
+fn decode_pgs_rle(rle: &[u8], width: usize, height: usize) -> Vec<u8> {
+    let mut indices = vec![0; width * height];
+    let (mut x, mut y) = (0, 0);
+    let mut bytes = rle.iter().copied();
+    while y < height {
+        let Some(byte) = bytes.next() else {
+            break;
+        };
+        let (length, color) = if byte != 0 {
+            (1, byte)
+        } else {
+            let Some(flags) = bytes.next() else {
+                break;
+            };
+            let short = (flags & 0x3F) as usize;
+            let length = match flags & 0x40 {
+                0 => short,
+                _ => (short << 8) | bytes.next().unwrap_or(0) as usize,
+            };
+            let color = match flags & 0x80 {
+                0 => 0,
+                _ => bytes.next().unwrap_or(0),
+            };
+            if length == 0 {
+                // End of line
+                x = 0;
+                y += 1;
+                continue;
+            }
+            (length, color)
+        };
+        for _ in 0..length {
+            if x < width && y < height {
+                indices[y * width + x] = color;
+            }
+            x += 1;
+        }
+    }
+    indices
+}
+
+/// Decodes VobSub packets into events, using the palette from the `.idx`
+/// header kept in the track's codec private data.
+pub fn decode_vobsub(idx: &[u8], blocks: &[BitmapBlock]) -> Result<Vec<BitmapEvent>> {
+    let palette = vobsub_palette(&String::from_utf8_lossy(idx));
+    let mut events = Vec::new();
+    for block in blocks {
+        let Some(packet) = decode_vobsub_packet(&block.data, &palette)? else {
+            continue;
+        };
+        let start = block.start + packet.start;
+        let end = match packet.end {
+            Some(end) => block.start + end,
+            None => start + block.duration.unwrap_or(DEFAULT_EVENT_DURATION_MS),
+        };
+        events.push(BitmapEvent {
+            start,
+            end,
+            bitmap: packet.bitmap,
+        });
+    }
+    Ok(events)
+}
+
+/// Luma of the 16 colours in the `palette:` line of a `.idx` header.
+fn vobsub_palette(idx: &str) -> [u8; 16] {
+    let mut palette = [0; 16];
+    let colors = idx
+        .lines()
+        .find_map(|line| line.strip_prefix("palette:"))
+        .unwrap_or_default();
+    for (i, color) in colors.split(',').take(16).enumerate() {
+        let rgb = u32::from_str_radix(color.trim(), 16).unwrap_or(0);
+        let (r, g, b) = ((rgb >> 16) & 0xFF, (rgb >> 8) & 0xFF, rgb & 0xFF);
+        palette[i] = ((299 * r + 587 * g + 114 * b) / 1000) as u8;
+    }
+    palette
+}
+
+struct VobSubPacket {
+    /// Milliseconds after the packet's timestamp
+    start: i64,
+    end: Option<i64>,
+    bitmap: Bitmap,
+}
+
+fn decode_vobsub_packet(data: &[u8], palette: &[u8; 16]) -> Result<Option<VobSubPacket>> {
+    let u16_at = |pos: usize| -> Result<usize> {
+        match data.get(pos..pos + 2) {
+            Some(bytes) => Ok(u16::from_be_bytes([bytes[0], bytes[1]]) as usize),
+            None => bail!("truncated VobSub packet"),
+        }
+    };
+    let byte_at = |pos: usize| -> Result<u8> {
+        match data.get(pos) {
+            Some(byte) => Ok(*byte),
+            None => bail!("truncated VobSub packet"),
+        }
+    };
+    // Delays are in units of 1024 ticks of a 90kHz clock
+    let to_ms = |delay: usize| (delay * 1024 / 90) as i64;
+
+    let mut start = None;
+    let mut end = None;
+    let mut colors = [0u8; 4];
+    let mut alphas = [0u8; 4];
+    let mut area = None;
+    let mut fields = None;
+
+    let mut control = u16_at(2)?;
+    loop {
+        let delay = to_ms(u16_at(control)?);
+        let next = u16_at(control + 2)?;
+        let mut i = control + 4;
+        loop {
+            let command = byte_at(i)?;
+            i += 1;
+            match command {
+                0x00 | 0x01 => start = Some(delay),
+                0x02 => end = Some(delay),
+                0x03 | 0x04 => {
+                    let (high, low) = (byte_at(i)?, byte_at(i + 1)?);
+                    let nibbles = [low & 0xF, low >> 4, high & 0xF, high >> 4];
+                    match command {
+                        0x03 => colors = nibbles,
+                        _ => alphas = nibbles,
+                    }
+                    i += 2;
+                }
+                0x05 => {
+                    let b: Vec<usize> = (i..i + 6)
+                        .map(|pos| byte_at(pos).map(usize::from))
+                        .collect::<Result<_>>()?;
+                    let x1 = (b[0] << 4) | (b[1] >> 4);
+                    let x2 = ((b[1] & 0xF) << 8) | b[2];
+                    let y1 = (b[3] << 4) | (b[4] >> 4);
+                    let y2 = ((b[4] & 0xF) << 8) | b[5];
+                    area = Some((x2.saturating_sub(x1) + 1, y2.saturating_sub(y1) + 1));
+                    i += 6;
+                }
+                0x06 => {
+                    fields = Some((u16_at(i)?, u16_at(i + 2)?));
+                    i += 4;
+                }
+                0xFF => break,
+                other => bail!("unknown VobSub command {other:#x}"),
+            }
+        }
+        // The last sequence points to itself, and each one comes after the
+        // one before, so a loop back is malformed
+        match next.cmp(&control) {
+            Ordering::Equal => break,
+            Ordering::Less => bail!("VobSub control sequence at {control} points back to {next}"),
+            Ordering::Greater => control = next,
+        }
+    }
+
+    let (Some(start), Some((width, height)), Some((top, bottom))) = (start, area, fields) else {
+        return Ok(None);
+    };
+    let mut bitmap = Bitmap::new(width, height);
+    // Even lines come from the top field and odd lines from the bottom one
+    for (field, offset) in [top, bottom].into_iter().enumerate() {
+        let mut nibbles = Nibbles {
+            data,
+            pos: offset * 2,
+        };
+        for y in (field..height).step_by(2) {
+            let mut x = 0;
+            while x < width {
+                let (length, color) = nibbles.run()?;
+                let length = if length == 0 { width - x } else { length };
+                let pixel = (palette[colors[color] as usize], alphas[color] * 17);
+                for _ in 0..length.min(width - x) {
+                    bitmap.pixels[y * width + x] = pixel;
+                    x += 1;
+                }
+            }
+            nibbles.align();
+        }
+    }
+
+    Ok(Some(VobSubPacket { start, end, bitmap }))
+}
+
+struct Nibbles<'a> {
+    data: &'a [u8],
+    /// Position in nibbles
+    pos: usize,
+}
+
+impl Nibbles<'_> {
+    fn next(&mut self) -> Result<usize> {
+        let Some(byte) = self.data.get(self.pos / 2) else {
+            bail!("truncated VobSub image");
+        };
+        let nibble = match self.pos % 2 {
+            0 => byte >> 4,
+            _ => byte & 0xF,
+        };
+        self.pos += 1;
+        Ok(nibble as usize)
+    }
+
+    /// Reads a run of 1 to 4 nibbles, returning its length, 0 meaning up to
+    /// the end of the line, and its colour.
+    fn run(&mut self) -> Result<(usize, usize)> {
+        let mut value = self.next()?;
+        for threshold in [0x4, 0x10, 0x40] {
+            if value >= threshold {
+                break;
+            }
+            value = (value << 4) | self.next()?;
+        }
+        Ok((value >> 2, value & 0x3))
+    }
+
+    /// Lines start on a byte boundary.
+    fn align(&mut self) {
+        self.pos += self.pos % 2;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(kind: u8, payload: &[u8]) -> Vec<u8> {
+        let mut segment = vec![kind];
+        segment.extend((payload.len() as u16).to_be_bytes());
+        segment.extend(payload);
+        segment
+    }
+
+    /// A display set showing a 4x2 object at (100, 900), or clearing the
+    /// screen when `show` is false.
+    fn display_set(show: bool) -> Vec<u8> {
+        let mut composition = vec![0x07, 0x80, 0x04, 0x38, 0x10, 0x00, 0x01, 0x80, 0x00, 0x00];
+        if show {
+            composition.extend([1, 0x00, 0x00, 0x00, 0x00, 0x00, 100, 0x03, 0x84]);
+        } else {
+            composition.push(0);
+        }
+        let mut set = segment(0x16, &composition);
+        if show {
+            // White opaque text on a transparent background
+            set.extend(segment(0x14, &[0, 0, 1, 235, 128, 128, 255]));
+            let rle = [
+                0x01, 0x01, 0x00, 0x82, 0x00, 0x00, 0x00, 0x00, 0x84, 0x01, 0x00, 0x00,
+            ];
+            let mut object = vec![
+                0x00, 0x00, 0x00, 0xC0, 0x00, 0x00, 16, 0x00, 0x04, 0x00, 0x02,
+            ];
+            object.extend(rle);
+            set.extend(segment(0x15, &object));
+        }
+        set.extend(segment(0x80, &[]));
+        set
+    }
+
+    #[test]
+    fn test_decode_pgs() {
+        let blocks = [
+            BitmapBlock {
+                start: 1000,
+                duration: None,
+                data: display_set(true),
+            },
+            BitmapBlock {
+                start: 3000,
+                duration: None,
+                data: display_set(false),
+            },
+        ];
+
+        let events = decode_pgs(&blocks).unwrap();
+
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].start, events[0].end), (1000, 3000));
+        let bitmap = &events[0].bitmap;
+        assert_eq!((bitmap.width, bitmap.height), (4, 2));
+        let opaque: Vec<bool> = bitmap.pixels.iter().map(|(_, a)| *a == 255).collect();
+        assert_eq!(opaque, [true, true, false, false, true, true, true, true]);
+    }
+
+    #[test]
+    fn test_decode_pgs_rejects_object_off_video() {
+        let mut data = display_set(true);
+        // Shrink the video to 100x1080, leaving the object at x 100 off it
+        data[3..5].copy_from_slice(&100u16.to_be_bytes());
+        let blocks = [BitmapBlock {
+            start: 1000,
+            duration: None,
+            data,
+        }];
+
+        let error = decode_pgs(&blocks).unwrap_err();
+
+        assert!(error.to_string().contains("does not fit on 100x1080 video"));
+    }
+
+    #[test]
+    fn test_decode_pgs_rejects_huge_video() {
+        let mut data = display_set(true);
+        data[3..7].copy_from_slice(&[0xFF; 4]);
+        let blocks = [BitmapBlock {
+            start: 1000,
+            duration: None,
+            data,
+        }];
+
+        let error = decode_pgs(&blocks).unwrap_err();
+
+        assert!(error.to_string().contains("too large"));
+    }
+
+    #[test]
+    fn test_decode_vobsub() {
+        let idx = b"size: 720x480\npalette: 000000, ffffff, 000000, 000000\n";
+        // 4x2 image: one white pixel then three transparent ones on the top
+        // line, four white pixels on the bottom line
+        let image = [0x5C, 0x11];
+        let mut packet = vec![0, 0, 0, 0];
+        packet.extend(image);
+        let control = packet.len();
+        packet.extend([0x00, 0x00]);
+        packet.extend((control as u16).to_be_bytes());
+        packet.extend([0x01, 0x03, 0x00, 0x10, 0x04, 0x00, 0xF0]);
+        packet.extend([0x05, 0x00, 0x00, 0x03, 0x00, 0x00, 0x01]);
+        packet.extend([0x06, 0x00, 0x04, 0x00, 0x05, 0xFF]);
+        packet[2..4].copy_from_slice(&(control as u16).to_be_bytes());
+        let blocks = [BitmapBlock {
+            start: 2000,
+            duration: Some(1500),
+            data: packet,
+        }];
+
+        let events = decode_vobsub(idx, &blocks).unwrap();
+
+        assert_eq!(events.len(), 1);
+        assert_eq!((events[0].start, events[0].end), (2000, 3500));
+        let bitmap = &events[0].bitmap;
+        assert_eq!((bitmap.width, bitmap.height), (4, 2));
+        let white: Vec<bool> = bitmap.pixels.iter().map(|p| *p == (255, 255)).collect();
+        assert_eq!(white, [true, false, false, false, true, true, true, true]);
+    }
+
+    #[test]
+    fn test_decode_vobsub_rejects_control_loop() {
+        let idx = b"palette: 000000, ffffff\n";
+        // Two control sequences, the second pointing back to the first
+        let mut packet = vec![0, 0, 0, 4];
+        packet.extend([0x00, 0x00, 0x00, 10, 0x01, 0xFF]);
+        packet.extend([0x00, 0x10, 0x00, 4, 0x02, 0xFF]);
+        let blocks = [BitmapBlock {
+            start: 2000,
+            duration: None,
+            data: packet,
+        }];
+
+        let error = decode_vobsub(idx, &blocks).unwrap_err();
+
+        assert!(error.to_string().contains("points back to 4"));
+    }
+
+    #[test]
+    fn test_to_pgm() {
+        let bitmap = Bitmap {
+            width: 2,
+            height: 1,
+            pixels: vec![(255, 255), (255, 0)],
+        };
+
+        let pgm = bitmap.to_pgm();
+
+        let header = b"P5\n22 21\n255\n";
+        assert!(pgm.starts_with(header));
+        assert_eq!(pgm.len(), header.len() + 22 * 21);
+        let first = header.len() + PADDING * 22 + PADDING;
+        assert_eq!(&pgm[first..first + 2], [0, 255]);
+    }
+}
@@ -0,0 +1,417 @@
+//! Where subtitles extracted from media files are kept between runs.
+//!
+//! Each media file gets a directory of its own, with a manifest recording
+//! which file the subtitles came from and what it looked like, so that
+//! importing the same file again does not extract them again.
+
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use anyhow::Result;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::info;
+use tracing::warn;
+use walkdir::WalkDir;
+
+use crate::atomic;
+use crate::hash::fnv1a;
+use crate::jobs::Fingerprint;
+use crate::jobs::now;
+
+/// Name of the directories next to media files that hold their subtitles,
+/// when there is no central cache
+pub const LOCAL_DIR: &str = ".subtitles";
+
+const MANIFEST: &str = "manifest.json";
+
+/// Record of the sidecars written, which tells them apart from the user's own
+const SIDECAR_RECORD: &str = "sidecars.json";
+
+/// Directory of the central cache holding the sidecar records, which are not
+/// kept in entries as those change along with the media file
+const SIDECAR_RECORDS_DIR: &str = "sidecars";
+
+/// Files in a cache entry that outlive re-extraction
+const KEPT_FILES: [&str; 1] = [SIDECAR_RECORD];
+
+/// How much of each end of a media file is hashed to identify it
+const HASHED_CHUNK: u64 = 64 * 1024;
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct CacheArgs {
+    /// Directory to keep extracted subtitles in, keyed by a hash of the media
+    /// file so that renamed files keep theirs. Defaults to a hidden
+    /// `.subtitles` directory next to each media file
+    #[clap(long, env = "CACHE_DIR", global = true)]
+    pub cache_dir: Option<PathBuf>,
+}
+
+/// What a cache entry was extracted from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub media_file: PathBuf,
+    #[serde(flatten)]
+    pub fingerprint: Fingerprint,
+    /// Hash of the start and end of the media file
+    pub hash: String,
+    /// Unix time the subtitles were extracted
+    pub extracted_at: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SubtitleCache {
+    /// Central cache directory, if not next to the media files
+    root: Option<PathBuf>,
+}
+
+impl SubtitleCache {
+    pub fn new(args: &CacheArgs) -> Self {
+        Self {
+            root: args.cache_dir.clone(),
+        }
+    }
+
+    /// Directory of the cache entry for the media file.
+    pub fn entry_dir(&self, media_file: &Path) -> Result<PathBuf> {
+        match &self.root {
+            Some(root) => Ok(root.join(quick_hash(media_file)?)),
+            None => local_entry_dir(media_file),
+        }
+    }
+
+    /// Record of the sidecars written next to the media file. It is found by
+    /// where the sidecars go, named after the media file's stem, rather than
+    /// by what the media file holds, so that an upgraded file still knows
+    /// the sidecars written for the one it replaced.
+    pub fn sidecar_record(&self, media_file: &Path) -> Result<PathBuf> {
+        match &self.root {
+            Some(root) => {
+                let stem = std::path::absolute(media_file)?.with_extension("");
+                let key = fnv1a(stem.as_os_str().as_encoded_bytes());
+                Ok(root.join(SIDECAR_RECORDS_DIR).join(format!("{key}.json")))
+            }
+            // Local entries are already named after the stem
+            None => Ok(local_entry_dir(media_file)?.join(SIDECAR_RECORD)),
+        }
+    }
+
+    /// Whether the entry holds subtitles extracted from the media file as it
+    /// is now. A file that was only touched still counts as the same.
+    pub fn is_fresh(&self, entry_dir: &Path, media_file: &Path) -> bool {
+        let (Ok(manifest), Ok(fingerprint)) =
+            (read_manifest(entry_dir), Fingerprint::of(media_file))
+        else {
+            return false;
+        };
+        manifest.fingerprint.size == fingerprint.size
+            && (manifest.fingerprint.modified == fingerprint.modified
+                || quick_hash(media_file).is_ok_and(|hash| hash == manifest.hash))
+    }
+
+    /// Empties the entry so that it can be extracted into again.
+    pub fn reset(&self, entry_dir: &Path) -> Result<()> {
+        let entries = match std::fs::read_dir(entry_dir) {
+            Ok(entries) => entries,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(error) => return Err(error.into()),
+        };
+        for entry in entries {
+            let entry = entry?;
+            if KEPT_FILES.iter().any(|kept| entry.file_name() == *kept) {
+                continue;
+            }
+            if entry.file_type()?.is_dir() {
+                std::fs::remove_dir_all(entry.path())?;
+            } else {
+                std::fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Records that the entry holds the subtitles of the media file as it is
+    /// now, returning the entry's directory. Entries in a central cache move
+    /// along if the media file's contents changed, such as after remuxing.
+    pub fn record(&self, entry_dir: &Path, media_file: &Path) -> Result<PathBuf> {
+        let hash = quick_hash(media_file)?;
+        let mut entry_dir = entry_dir.to_owned();
+        if let Some(root) = &self.root
+            && entry_dir != root.join(&hash)
+        {
+            let moved = root.join(&hash);
+            if moved.exists() {
+                std::fs::remove_dir_all(&moved)?;
+            }
+            std::fs::rename(&entry_dir, &moved)?;
+            entry_dir = moved;
+        }
+        let manifest = Manifest {
+            media_file: std::path::absolute(media_file)?,
+            fingerprint: Fingerprint::of(media_file)?,
+            hash,
+            extracted_at: now(),
+        };
+        atomic::write(
+            &entry_dir.join(MANIFEST),
+            serde_json::to_vec_pretty(&manifest)?,
+        )?;
+        Ok(entry_dir)
+    }
+
+    /// Directories of every entry of the central cache, and of the local
+    /// entries of media files in `library_dirs`.
+    pub fn entry_dirs(&self, library_dirs: &[PathBuf]) -> Result<Vec<PathBuf>> {
+        let mut entry_dirs = Vec::new();
+        if let Some(root) = &self.root
+            && root.exists()
+        {
+            for entry in std::fs::read_dir(root)? {
+                let entry_dir = entry?.path();
+                if entry_dir.is_dir() && !entry_dir.ends_with(SIDECAR_RECORDS_DIR) {
+                    entry_dirs.push(entry_dir);
+                }
+            }
+        }
+        for library_dir in library_dirs {
+            let local_dirs = WalkDir::new(library_dir)
+                .into_iter()
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.file_type().is_dir() && entry.file_name() == LOCAL_DIR);
+            for local_dir in local_dirs {
+                for entry in std::fs::read_dir(local_dir.path())? {
+                    let entry_dir = entry?.path();
+                    if entry_dir.is_dir() {
+                        entry_dirs.push(entry_dir);
+                    }
+                }
+            }
+        }
+        Ok(entry_dirs)
+    }
+
+    /// Removes the entries whose media file is gone or has been replaced:
+    /// every entry of the central cache, and the local entries of media
+    /// files in `library_dirs`. Returns the entries removed, or the ones that
+    /// would be if `dry_run` is set.
+    pub fn gc(&self, library_dirs: &[PathBuf], dry_run: bool) -> Result<Vec<PathBuf>> {
+        let orphans: Vec<PathBuf> = self
+            .entry_dirs(library_dirs)?
+            .into_iter()
+            .filter(|entry_dir| !self.is_alive(entry_dir))
+            .collect();
+
+        for orphan in &orphans {
+            info!(entry = %orphan.display(), dry_run, "removing orphaned cache entry");
+            if dry_run {
+                continue;
+            }
+            if let Err(error) = std::fs::remove_dir_all(orphan) {
+                warn!(entry = %orphan.display(), ?error, "failed removing cache entry");
+            }
+            // Leaves no empty `.subtitles` directories behind
+            if let Some(parent) = orphan.parent()
+                && parent.file_name().is_some_and(|name| name == LOCAL_DIR)
+            {
+                let _ = std::fs::remove_dir(parent);
+            }
+        }
+        Ok(orphans)
+    }
+
+    /// Whether the entry still has its media file, as it was when extracted
+    /// for entries of the central cache, which are keyed by its hash.
+    fn is_alive(&self, entry_dir: &Path) -> bool {
+        if self
+            .root
+            .as_ref()
+            .is_none_or(|root| entry_dir.parent() != Some(root.as_path()))
+        {
+            return has_local_media_file(entry_dir);
+        }
+        let key = entry_dir.file_name().unwrap_or_default().to_string_lossy();
+        read_manifest(entry_dir)
+            .is_ok_and(|manifest| quick_hash(&manifest.media_file).is_ok_and(|hash| hash == key))
+    }
+}
+
+/// Directory next to the media file that holds its subtitles.
+fn local_entry_dir(media_file: &Path) -> Result<PathBuf> {
+    let media_file_stem = media_file
+        .file_stem()
+        .context("unable to get media file stem")?;
+    Ok(media_file
+        .parent()
+        .context("unable to get media file")?
+        .join(LOCAL_DIR)
+        .join(media_file_stem))
+}
+
+/// Whether the local entry still has its media file. Entries extracted
+/// before manifests existed are matched to media files by stem.
+fn has_local_media_file(entry_dir: &Path) -> bool {
+    if let Ok(manifest) = read_manifest(entry_dir) {
+        return manifest.media_file.exists();
+    }
+    let (Some(stem), Some(dir)) = (
+        entry_dir.file_name(),
+        entry_dir.parent().and_then(Path::parent),
+    ) else {
+        return true;
+    };
+    std::fs::read_dir(dir).is_ok_and(|mut entries| {
+        entries.any(|entry| {
+            entry.is_ok_and(|entry| {
+                let path = entry.path();
+                path.is_file() && path.file_stem() == Some(stem)
+            })
+        })
+    })
+}
+
+fn read_manifest(entry_dir: &Path) -> Result<Manifest> {
+    let content = std::fs::read_to_string(entry_dir.join(MANIFEST))?;
+    Ok(serde_json::from_str(&content)?)
+}
+
+/// Identifies a media file by its size and the start and end of its
+/// contents, which is quick even for large files and survives renames.
+fn quick_hash(media_file: &Path) -> Result<String> {
+    let mut file = std::fs::File::open(media_file)?;
+    let size = file.metadata()?.len();
+    let mut bytes = size.to_le_bytes().to_vec();
+    file.by_ref().take(HASHED_CHUNK).read_to_end(&mut bytes)?;
+    if size > HASHED_CHUNK {
+        file.seek(SeekFrom::Start(
+            size.saturating_sub(HASHED_CHUNK).max(HASHED_CHUNK),
+        ))?;
+        file.read_to_end(&mut bytes)?;
+    }
+    Ok(fnv1a(&bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_entry_freshness() {
+        let dir = tempfile::tempdir().unwrap();
+        let media_file = dir.path().join("Show.S01E01.mkv");
+        std::fs::write(&media_file, "episode").unwrap();
+        let cache = SubtitleCache::default();
+
+        let entry_dir = cache.entry_dir(&media_file).unwrap();
+        assert_eq!(entry_dir, dir.path().join(".subtitles/Show.S01E01"));
+        std::fs::create_dir_all(&entry_dir).unwrap();
+        assert!(!cache.is_fresh(&entry_dir, &media_file));
+
+        cache.record(&entry_dir, &media_file).unwrap();
+        assert!(cache.is_fresh(&entry_dir, &media_file));
+
+        std::fs::write(&media_file, "upgraded episode").unwrap();
+        assert!(!cache.is_fresh(&entry_dir, &media_file));
+    }
+
+    #[test]
+    fn test_central_entry_follows_renames() {
+        let dir = tempfile::tempdir().unwrap();
+        let media_file = dir.path().join("episode.mkv");
+        std::fs::write(&media_file, "episode").unwrap();
+        let cache = SubtitleCache {
+            root: Some(dir.path().join("cache")),
+        };
+        let entry_dir = cache.entry_dir(&media_file).unwrap();
+        std::fs::create_dir_all(&entry_dir).unwrap();
+        cache.record(&entry_dir, &media_file).unwrap();
+
+        let renamed = dir.path().join("Show.S01E01.mkv");
+        std::fs::rename(&media_file, &renamed).unwrap();
+
+        assert_eq!(cache.entry_dir(&renamed).unwrap(), entry_dir);
+        assert!(cache.is_fresh(&entry_dir, &renamed));
+    }
+
+    #[test]
+    fn test_central_sidecar_record_outlives_upgrade() {
+        let dir = tempfile::tempdir().unwrap();
+        let media_file = dir.path().join("Show.S01E01.mkv");
+        std::fs::write(&media_file, "episode").unwrap();
+        let cache = SubtitleCache {
+            root: Some(dir.path().join("cache")),
+        };
+        let record = cache.sidecar_record(&media_file).unwrap();
+        std::fs::create_dir_all(record.parent().unwrap()).unwrap();
+        std::fs::write(&record, "{}").unwrap();
+
+        std::fs::remove_file(&media_file).unwrap();
+        let upgraded = dir.path().join("Show.S01E01.mp4");
+        std::fs::write(&upgraded, "upgraded episode").unwrap();
+
+        assert_eq!(cache.sidecar_record(&upgraded).unwrap(), record);
+        assert_ne!(
+            cache
+                .sidecar_record(&dir.path().join("Show.S01E02.mkv"))
+                .unwrap(),
+            record
+        );
+        // The records are not an entry to be collected
+        assert!(cache.gc(&[], false).unwrap().is_empty());
+        assert!(record.exists());
+    }
+
+    #[test]
+    fn test_reset_keeps_sidecar_record() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("0_2.en.ass"), "").unwrap();
+        std::fs::write(dir.path().join("sidecars.json"), "{}").unwrap();
+
+        SubtitleCache::default().reset(dir.path()).unwrap();
+
+        let left: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(left, ["sidecars.json"]);
+    }
+
+    #[test]
+    fn test_gc() {
+        let dir = tempfile::tempdir().unwrap();
+        let library = dir.path().join("tv");
+        let root = dir.path().join("cache");
+        let cache = SubtitleCache {
+            root: Some(root.clone()),
+        };
+        let kept = library.join("kept.mkv");
+        let deleted = library.join("deleted.mkv");
+        std::fs::create_dir_all(&library).unwrap();
+        for media_file in [&kept, &deleted] {
+            std::fs::write(media_file, media_file.to_string_lossy().as_bytes()).unwrap();
+            let entry_dir = cache.entry_dir(media_file).unwrap();
+            std::fs::create_dir_all(&entry_dir).unwrap();
+            cache.record(&entry_dir, media_file).unwrap();
+        }
+        let deleted_entry = cache.entry_dir(&deleted).unwrap();
+        std::fs::remove_file(&deleted).unwrap();
+        // Local entries from before manifests existed
+        std::fs::create_dir_all(library.join(".subtitles/kept")).unwrap();
+        std::fs::create_dir_all(library.join(".subtitles/renamed")).unwrap();
+
+        let dry = cache.gc(std::slice::from_ref(&library), true).unwrap();
+        let removed = cache.gc(std::slice::from_ref(&library), false).unwrap();
+
+        assert_eq!(dry, removed);
+        assert_eq!(
+            removed,
+            [deleted_entry.clone(), library.join(".subtitles/renamed")]
+        );
+        assert!(!deleted_entry.exists());
+        assert!(cache.entry_dir(&kept).unwrap().exists());
+        assert!(library.join(".subtitles/kept").exists());
+    }
+}
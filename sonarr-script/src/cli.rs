@@ -1,7 +1,17 @@
+mod cache;
 mod clean;
 mod convert;
+mod jobs;
 mod merge;
+mod report;
+mod scan;
+mod serve;
 mod sonarr_subtitle_merge;
+mod split;
+mod watch;
+
+/// File extensions treated as media files when walking or watching libraries
+const DEFAULT_MEDIA_EXTENSIONS: &str = "mkv,mp4,m4v,avi,webm,ts";
 
 /// This is a multicall binary like BusyBox. For example, if the program is
 /// symlinked to the name of a subcommand, that subcommand will be executed.
@@ -11,16 +21,23 @@ pub enum Cli {
     // Name here must be the default binary basename
     #[clap(subcommand, name = "sonarr-script")]
     Default(SubCommand),
-    SonarrSubtitleMerge(sonarr_subtitle_merge::Args),
+    SonarrSubtitleMerge(Box<sonarr_subtitle_merge::Args>),
     Merge(merge::Args),
     Convert(convert::Args),
     Clean(clean::Args),
+    Split(split::Args),
+    Serve(Box<serve::Args>),
+    Jobs(Box<jobs::Args>),
+    Watch(Box<watch::Args>),
+    Scan(Box<scan::Args>),
+    Cache(Box<cache::Args>),
+    Report(Box<report::Args>),
 }
 
 #[derive(Debug, clap::Subcommand)]
 pub enum SubCommand {
     /// Sonarr Custom Script to create dual-language subtitles
-    SonarrSubtitleMerge(sonarr_subtitle_merge::Args),
+    SonarrSubtitleMerge(Box<sonarr_subtitle_merge::Args>),
 
     /// Merge subtitle files
     Merge(merge::Args),
@@ -30,4 +47,26 @@ pub enum SubCommand {
 
     /// Clean subtitle files
     Clean(clean::Args),
+
+    /// Split a subtitle file with several languages in it into a file per
+    /// language
+    Split(split::Args),
+
+    /// Serve a webhook endpoint for Sonarr and Radarr
+    Serve(Box<serve::Args>),
+
+    /// Inspect and retry recorded processing jobs
+    Jobs(Box<jobs::Args>),
+
+    /// Watch library directories for new media files
+    Watch(Box<watch::Args>),
+
+    /// Scan a library for media files lacking sidecar subtitles
+    Scan(Box<scan::Args>),
+
+    /// Manage the cache of extracted subtitles
+    Cache(Box<cache::Args>),
+
+    /// Summarize the processing reports of a library
+    Report(Box<report::Args>),
 }
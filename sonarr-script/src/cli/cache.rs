@@ -0,0 +1,52 @@
+use std::path::PathBuf;
+
+use sonarr_script::cache::SubtitleCache;
+use sonarr_script::pipeline::PipelineArgs;
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct Args {
+    #[clap(subcommand)]
+    command: Command,
+
+    #[clap(flatten)]
+    pipeline: PipelineArgs,
+}
+
+#[derive(Debug, Clone, clap::Subcommand)]
+enum Command {
+    /// Remove cached subtitles whose media file is gone or was replaced
+    Gc {
+        /// Library directories to look for `.subtitles` directories in. The
+        /// central cache, if configured, is always collected
+        library_dirs: Vec<PathBuf>,
+
+        /// Only print what would be removed
+        #[clap(long, short = 'n')]
+        dry_run: bool,
+    },
+}
+
+impl Args {
+    pub fn run(&self) -> anyhow::Result<()> {
+        let cache = SubtitleCache::new(&self.pipeline.cache);
+
+        match &self.command {
+            Command::Gc {
+                library_dirs,
+                dry_run,
+            } => {
+                if library_dirs.is_empty() && self.pipeline.cache.cache_dir.is_none() {
+                    anyhow::bail!("no cache to collect, give library directories or set CACHE_DIR");
+                }
+                let removed = cache.gc(library_dirs, *dry_run)?;
+                let verb = if *dry_run { "would remove" } else { "removed" };
+                for entry in &removed {
+                    println!("{verb} {}", entry.display());
+                }
+                println!("{verb} {} cache entries", removed.len());
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -5,6 +5,9 @@ use aspasia::SubRipSubtitle;
 use aspasia::Subtitle;
 use camino::Utf8PathBuf;
 
+use sonarr_script::atomic;
+use sonarr_script::metrics;
+
 #[derive(Debug, Clone, clap::Args)]
 pub struct Args {
     /// Input subtitle file
@@ -15,11 +18,13 @@ pub struct Args {
     #[clap(long, short, default_value = "/dev/fd/1")]
     output: Utf8PathBuf,
 
-    /// Remove lines whose length is over this limit
+    /// Remove lines wider on screen than this limit, where East Asian
+    /// characters count twice and tags and line breaks do not count
     #[clap(long)]
     length: Option<usize>,
 
-    /// Remove lines whose characters per second (CPS) is over this limit
+    /// Remove lines whose characters per second (CPS) is over this limit.
+    /// Characters are counted as they are perceived, without tags
     #[clap(long)]
     cps: Option<usize>,
 }
@@ -33,7 +38,7 @@ impl Args {
 
         // Delete long lines
         if let Some(length_limit) = self.length {
-            events.retain(|event| event.text.len() < length_limit);
+            events.retain(|event| metrics::display_width(&event.text) < length_limit);
         }
 
         // Delete lines with CPS too high
@@ -43,14 +48,19 @@ impl Args {
                     .try_into()
                     .unwrap_or(1);
                 let duration = if duration == 0 { 1 } else { duration };
-                let characters = event.text.chars().count();
+                let characters = metrics::measure(&event.text).graphemes;
                 let cps = characters / duration;
                 cps < cps_limit
             });
         }
 
         let output = SubRipSubtitle::from_events(events).to_string();
-        fs::write(&self.output, output)?;
+        // The output is stdout by default, which cannot be renamed over
+        if fs::metadata(&self.output).is_ok_and(|m| !m.is_file()) {
+            fs::write(&self.output, output)?;
+        } else {
+            atomic::write(self.output.as_std_path(), output)?;
+        }
 
         Ok(())
     }
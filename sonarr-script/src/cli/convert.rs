@@ -6,6 +6,8 @@ use camino::Utf8PathBuf;
 use tracing::debug;
 use tracing::info;
 
+use sonarr_script::atomic;
+
 #[derive(Debug, Clone, clap::Args)]
 pub struct Args {
     input: Utf8PathBuf,
@@ -52,7 +54,8 @@ impl Args {
         match output_format {
             aspasia::Format::Ass => {
                 let o = AssSubtitle::from(input_subtitle);
-                o.export(self.output.clone().unwrap())?;
+                let output = self.output.clone().unwrap();
+                atomic::replace_with(output.as_std_path(), |tmp| Ok(o.export(tmp)?))?;
             }
             _ => todo!(),
         }
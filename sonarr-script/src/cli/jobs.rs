@@ -0,0 +1,115 @@
+use std::path::PathBuf;
+
+use tracing::info;
+use tracing::warn;
+
+use sonarr_script::jobs::JobState;
+use sonarr_script::jobs::JobStore;
+use sonarr_script::jobs::now;
+use sonarr_script::pipeline::PipelineArgs;
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct Args {
+    #[clap(subcommand)]
+    command: Command,
+
+    #[clap(flatten)]
+    pipeline: PipelineArgs,
+}
+
+#[derive(Debug, Clone, clap::Subcommand)]
+enum Command {
+    /// List recorded jobs
+    List {
+        /// Only list jobs in this state
+        #[clap(long)]
+        state: Option<JobState>,
+    },
+
+    /// Run pending jobs whose retry delay has elapsed
+    Retry {
+        /// Media files to retry regardless of their state
+        media_files: Vec<PathBuf>,
+
+        /// Also retry jobs that were given up on
+        #[clap(long)]
+        failed: bool,
+
+        /// Ignore what is left of the retry delay of pending jobs, keeping
+        /// count of their attempts
+        #[clap(long)]
+        now: bool,
+    },
+
+    /// Remove finished jobs
+    Purge {
+        /// States of the jobs to remove
+        #[clap(long, value_delimiter = ',', default_value = "succeeded,failed")]
+        state: Vec<JobState>,
+    },
+}
+
+impl Args {
+    pub fn run(&self) -> anyhow::Result<()> {
+        // Only retrying needs the whole pipeline, with its language models
+        // and notifier
+        let store = JobStore::open(&self.pipeline.jobs)?;
+
+        match &self.command {
+            Command::List { state } => {
+                let now = now();
+                for record in store.list()? {
+                    if state.is_some_and(|state| state != record.state) {
+                        continue;
+                    }
+                    let retry_in = match record.state {
+                        JobState::Pending => {
+                            format!("{}s", record.next_attempt_at.saturating_sub(now))
+                        }
+                        _ => "-".to_owned(),
+                    };
+                    println!(
+                        "{:<9} attempts={} retry_in={} {} {}",
+                        format!("{:?}", record.state).to_lowercase(),
+                        record.attempts,
+                        retry_in,
+                        record.job.media_file.display(),
+                        record.last_error.as_deref().unwrap_or_default(),
+                    );
+                }
+            }
+            Command::Retry {
+                media_files,
+                failed,
+                now,
+            } => {
+                let pipeline = self.pipeline.build()?;
+                let jobs = if *failed || !media_files.is_empty() {
+                    store.reset(media_files, *failed)?
+                } else if *now {
+                    store.expedite()?
+                } else {
+                    store.due()?
+                };
+                info!(count = jobs.len(), "retrying jobs");
+
+                let mut errors = 0;
+                for job in jobs {
+                    if let Err(error) = pipeline.run(&job) {
+                        warn!(media_file = %job.media_file.display(), ?error, "retry failed");
+                        errors += 1;
+                    }
+                }
+                if errors > 0 {
+                    anyhow::bail!("{errors} jobs failed");
+                }
+            }
+            Command::Purge { state } => {
+                let removed = store.purge(state)?;
+                info!(removed, "purged jobs");
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -2,7 +2,7 @@ use std::path::PathBuf;
 
 use tracing::info;
 
-use crate::subtitle;
+use sonarr_script::subtitle;
 
 #[derive(Debug, Clone, clap::Args)]
 pub struct Args {
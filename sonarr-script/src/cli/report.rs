@@ -0,0 +1,58 @@
+use std::path::PathBuf;
+
+use tracing::warn;
+
+use sonarr_script::cache::CacheArgs;
+use sonarr_script::cache::SubtitleCache;
+use sonarr_script::report::ProcessingReport;
+use sonarr_script::report::REPORT_FILE;
+use sonarr_script::report::ReportSummary;
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct Args {
+    /// Library directories to read the reports kept in `.subtitles`
+    /// directories from, or files of reports written with `--report`. The
+    /// reports in the central cache, if configured, are always read
+    paths: Vec<PathBuf>,
+
+    /// Print the summary as JSON
+    #[clap(long)]
+    json: bool,
+
+    #[clap(flatten)]
+    cache: CacheArgs,
+}
+
+impl Args {
+    pub fn run(&self) -> anyhow::Result<()> {
+        if self.paths.is_empty() && self.cache.cache_dir.is_none() {
+            anyhow::bail!("no reports to summarize, give library directories or set CACHE_DIR");
+        }
+        let (library_dirs, report_files): (Vec<PathBuf>, Vec<PathBuf>) =
+            self.paths.iter().cloned().partition(|path| path.is_dir());
+
+        let mut reports = Vec::new();
+        for report_file in &report_files {
+            reports.extend(ProcessingReport::load_lines(report_file)?);
+        }
+        let cache = SubtitleCache::new(&self.cache);
+        for entry_dir in cache.entry_dirs(&library_dirs)? {
+            // Entries extracted before reports were kept have none
+            if !entry_dir.join(REPORT_FILE).exists() {
+                continue;
+            }
+            match ProcessingReport::load(&entry_dir) {
+                Ok(report) => reports.push(report),
+                Err(error) => warn!(entry = %entry_dir.display(), ?error, "failed reading report"),
+            }
+        }
+
+        let summary = ReportSummary::new(reports);
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(&summary)?);
+        } else {
+            print!("{summary}");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,269 @@
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use globset::Glob;
+use globset::GlobSet;
+use globset::GlobSetBuilder;
+use tracing::info;
+use tracing::warn;
+use walkdir::WalkDir;
+
+use crate::cli::DEFAULT_MEDIA_EXTENSIONS;
+use sonarr_script::cache;
+use sonarr_script::cache::SubtitleCache;
+use sonarr_script::naming::SidecarNaming;
+use sonarr_script::pipeline::ImportJob;
+use sonarr_script::pipeline::PipelineArgs;
+use sonarr_script::process::SystemRunner;
+use sonarr_script::remux::OutputMode;
+use sonarr_script::report::ProcessingReport;
+use sonarr_script::subtitle;
+use sonarr_script::subtitle::LanguageCheck;
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct Args {
+    /// Library directory to scan
+    dir: PathBuf,
+
+    /// Only print what would be done
+    #[clap(long, short = 'n')]
+    dry_run: bool,
+
+    /// Only scan media files whose path relative to the library matches one
+    /// of these globs
+    #[clap(long)]
+    include: Vec<String>,
+
+    /// Skip media files whose path relative to the library matches one of
+    /// these globs
+    #[clap(long)]
+    exclude: Vec<String>,
+
+    /// Maximum directory depth to descend into
+    #[clap(long)]
+    max_depth: Option<usize>,
+
+    /// File extensions considered media files
+    #[clap(long, value_delimiter = ',', default_value = DEFAULT_MEDIA_EXTENSIONS)]
+    extensions: Vec<String>,
+
+    #[clap(flatten)]
+    pipeline: PipelineArgs,
+}
+
+impl Args {
+    pub fn run(&self) -> anyhow::Result<()> {
+        let naming = self.pipeline.naming()?;
+        let cache = SubtitleCache::new(&self.pipeline.cache);
+        let include = build_globset(&self.include)?;
+        let exclude = build_globset(&self.exclude)?;
+
+        let mut walker = WalkDir::new(&self.dir).sort_by_file_name();
+        if let Some(max_depth) = self.max_depth {
+            walker = walker.max_depth(max_depth);
+        }
+        let media_files: Vec<PathBuf> = walker
+            .into_iter()
+            .filter_entry(|entry| entry.file_name() != cache::LOCAL_DIR)
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_file())
+            .map(|entry| entry.into_path())
+            .filter(|path| self.is_media_file(path))
+            .filter(|path| {
+                let relative = path.strip_prefix(&self.dir).unwrap_or(path);
+                (self.include.is_empty() || include.is_match(relative))
+                    && !exclude.is_match(relative)
+            })
+            .filter(|path| {
+                lacks_outputs(&naming, self.pipeline.output, path, || {
+                    is_embedded(&cache, path)
+                })
+            })
+            .collect();
+        info!(
+            count = media_files.len(),
+            "found media files lacking subtitles"
+        );
+
+        if self.dry_run {
+            for media_file in &media_files {
+                if let Err(error) = report(
+                    &naming,
+                    &cache,
+                    media_file,
+                    self.pipeline.ocr,
+                    self.pipeline.language_check,
+                ) {
+                    warn!(media_file = %media_file.display(), ?error, "failed probing media file");
+                }
+            }
+            return Ok(());
+        }
+
+        let pipeline = self.pipeline.build()?;
+        let mut errors = 0;
+        for media_file in media_files {
+            let job = ImportJob {
+                media_file,
+                series_id: None,
+                movie_id: None,
+            };
+            if let Err(error) = pipeline.run(&job) {
+                warn!(media_file = %job.media_file.display(), ?error, "failed processing media file");
+                errors += 1;
+            }
+        }
+        if errors > 0 {
+            anyhow::bail!("{errors} media files failed");
+        }
+
+        Ok(())
+    }
+
+    fn is_media_file(&self, path: &Path) -> bool {
+        path.extension().is_some_and(|extension| {
+            self.extensions
+                .iter()
+                .any(|e| extension.eq_ignore_ascii_case(e))
+        })
+    }
+}
+
+fn build_globset(patterns: &[String]) -> anyhow::Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern).with_context(|| format!("invalid glob: {pattern}"))?);
+    }
+    Ok(builder.build()?)
+}
+
+/// Whether the media file lacks any of the outputs processing writes: the
+/// English sidecar and at least one bilingual one, or the merged subtitles
+/// embedded into it, depending on the output mode.
+fn lacks_outputs(
+    naming: &SidecarNaming,
+    output: OutputMode,
+    media_file: &Path,
+    embedded: impl FnOnce() -> bool,
+) -> bool {
+    let exists = |lang| naming.path(media_file, lang).exists();
+    let lacks_sidecars = !exists("en") || !subtitle::paired_sidecar_languages().any(exists);
+    (output.sidecars() && lacks_sidecars) || (output.embeds() && !embedded())
+}
+
+/// Whether merged subtitles were embedded into the media file as it is now,
+/// going by the report of its last processing, which lists the media file
+/// among its outputs if they were.
+fn is_embedded(cache: &SubtitleCache, media_file: &Path) -> bool {
+    let Ok(entry_dir) = cache.entry_dir(media_file) else {
+        return false;
+    };
+    cache.is_fresh(&entry_dir, media_file)
+        && ProcessingReport::load(&entry_dir)
+            .is_ok_and(|report| report.outputs.iter().any(|output| output == media_file))
+}
+
+/// Prints which streams would be extracted and which sidecars written.
+fn report(
+    naming: &SidecarNaming,
+    cache: &SubtitleCache,
+    media_file: &Path,
+    ocr: bool,
+    language_check: LanguageCheck,
+) -> anyhow::Result<()> {
+    println!("{}", media_file.display());
+
+    let streams = subtitle::get_subtitle_streams(&SystemRunner, media_file)?;
+    let wanted = subtitle::wanted_streams(&streams, ocr, language_check);
+    let is_wanted =
+        |s: &subtitle::SubtitleStream| wanted.iter().any(|w| w.stream_id == s.stream_id);
+    for s in &streams {
+        let action = match (is_wanted(s), s.is_bitmap()) {
+            (true, true) => "ocr",
+            (true, false) => "extract",
+            (false, _) => "skip",
+        };
+        println!(
+            "  stream {} {} {}: {action}",
+            s.stream_id, s.language_code, s.codec
+        );
+    }
+
+    // Simplified and traditional Chinese can only be told apart after
+    // extraction, unless a previous run left its extracted files behind.
+    // Japanese and Korean are taken at their tags until then.
+    let subtitle_dir = cache.entry_dir(media_file)?;
+    let has = |lang: &str| {
+        streams
+            .iter()
+            .any(|s| is_wanted(s) && s.language_code == lang)
+    };
+    let sidecar = |lang| naming.path(media_file, lang).display().to_string();
+    let mut outputs = Vec::new();
+    if has("en") {
+        outputs.push(sidecar("en"));
+        if subtitle_dir.exists() {
+            for lang in subtitle::paired_sidecar_languages() {
+                if subtitle::get_best_language(&subtitle_dir, lang).is_some() {
+                    outputs.push(sidecar(lang));
+                }
+            }
+        } else {
+            if has("zh") {
+                outputs.push(format!("{} or {}", sidecar("zh"), sidecar("zh-TW")));
+            }
+            for lang in ["ja", "ko"] {
+                if has(lang) {
+                    outputs.push(sidecar(lang));
+                }
+            }
+        }
+    }
+
+    if outputs.is_empty() {
+        println!("  no sidecars would be written");
+    }
+    for output in outputs {
+        println!("  would write {output}");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case(OutputMode::Sidecar, &[], false, true)]
+    #[case(OutputMode::Sidecar, &["en"], false, true)]
+    #[case(OutputMode::Sidecar, &["en", "zh-TW"], false, false)]
+    #[case(OutputMode::Sidecar, &["en", "ja"], false, false)]
+    #[case(OutputMode::Sidecar, &["en", "ko"], false, false)]
+    #[case(OutputMode::Sidecar, &["zh", "ko"], false, true)]
+    #[case(OutputMode::Embed, &[], true, false)]
+    #[case(OutputMode::Embed, &["en", "zh"], false, true)]
+    #[case(OutputMode::Both, &["en", "zh"], false, true)]
+    #[case(OutputMode::Both, &["en"], true, true)]
+    #[case(OutputMode::Both, &["en", "zh"], true, false)]
+    fn test_lacks_outputs(
+        #[case] output: OutputMode,
+        #[case] sidecars: &[&str],
+        #[case] embedded: bool,
+        #[case] should: bool,
+    ) {
+        let dir = tempfile::tempdir().unwrap();
+        let media_file = dir.path().join("episode.mkv");
+        let naming = SidecarNaming::default();
+        for lang in sidecars {
+            std::fs::write(naming.path(&media_file, lang), "").unwrap();
+        }
+
+        let got = lacks_outputs(&naming, output, &media_file, || embedded);
+
+        assert_eq!(got, should);
+    }
+}
@@ -0,0 +1,672 @@
+use std::collections::BTreeMap;
+use std::io::Cursor;
+use std::io::Read;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Result;
+use anyhow::anyhow;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use serde::Serialize;
+use serde_json::json;
+use tiny_http::Header;
+use tiny_http::Method;
+use tiny_http::Request;
+use tiny_http::Response;
+use tracing::info;
+use tracing::warn;
+
+use sonarr_script::pipeline::EventType;
+use sonarr_script::pipeline::ImportJob;
+use sonarr_script::pipeline::Pipeline;
+use sonarr_script::pipeline::PipelineArgs;
+use sonarr_script::webhook::WebhookPayload;
+
+/// Finished jobs kept around for status queries
+const MAX_FINISHED_JOBS: usize = 1000;
+
+/// How often the job store is checked for jobs due for a retry
+const RETRY_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Largest webhook body read, far more than any *arr payload
+const MAX_BODY_SIZE: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct Args {
+    /// Address to listen on
+    #[clap(long, env = "LISTEN_ADDRESS", default_value = "0.0.0.0:8080")]
+    listen: String,
+
+    /// Number of media files processed concurrently
+    #[clap(long, env = "WORKERS", default_value_t = 1)]
+    workers: usize,
+
+    /// Username webhook requests must give with basic auth, as set in the
+    /// webhook connection of Sonarr or Radarr
+    #[clap(long, env = "WEBHOOK_USERNAME")]
+    webhook_username: Option<String>,
+
+    /// Password webhook requests must give with basic auth. Requests are
+    /// not authenticated unless a username or password is set
+    #[clap(long, env = "WEBHOOK_PASSWORD", hide_env_values = true)]
+    webhook_password: Option<String>,
+
+    /// Directories media files from webhooks must be in. Files anywhere are
+    /// accepted if none are given
+    #[clap(long = "library-root", env = "LIBRARY_ROOTS", value_delimiter = ',')]
+    library_roots: Vec<PathBuf>,
+
+    #[clap(flatten)]
+    pipeline: PipelineArgs,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum JobState {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct JobStatus {
+    id: u64,
+    #[serde(flatten)]
+    job: ImportJob,
+    state: JobState,
+    error: Option<String>,
+    written: Vec<PathBuf>,
+}
+
+#[derive(Debug, Default)]
+struct Jobs {
+    next_id: u64,
+    jobs: BTreeMap<u64, JobStatus>,
+}
+
+impl Jobs {
+    fn push(&mut self, job: ImportJob) -> u64 {
+        self.next_id += 1;
+        let id = self.next_id;
+        self.jobs.insert(
+            id,
+            JobStatus {
+                id,
+                job,
+                state: JobState::Queued,
+                error: None,
+                written: Vec::new(),
+            },
+        );
+        self.prune();
+        id
+    }
+
+    /// Whether the job is already waiting for or being processed by a worker.
+    fn is_queued(&self, job: &ImportJob) -> bool {
+        self.jobs.values().any(|s| {
+            s.job.media_file == job.media_file
+                && matches!(s.state, JobState::Queued | JobState::Running)
+        })
+    }
+
+    fn start(&mut self, id: u64) -> Option<ImportJob> {
+        let status = self.jobs.get_mut(&id)?;
+        status.state = JobState::Running;
+        Some(status.job.clone())
+    }
+
+    fn finish(&mut self, id: u64, result: Result<Vec<PathBuf>>) {
+        let Some(status) = self.jobs.get_mut(&id) else {
+            return;
+        };
+        match result {
+            Ok(written) => {
+                status.state = JobState::Succeeded;
+                status.written = written;
+            }
+            Err(error) => {
+                status.state = JobState::Failed;
+                status.error = Some(format!("{error:#}"));
+            }
+        }
+    }
+
+    /// Drops the oldest finished jobs so the table does not grow forever.
+    fn prune(&mut self) {
+        let finished: Vec<u64> = self
+            .jobs
+            .values()
+            .filter(|s| matches!(s.state, JobState::Succeeded | JobState::Failed))
+            .map(|s| s.id)
+            .collect();
+        let excess = finished.len().saturating_sub(MAX_FINISHED_JOBS);
+        for id in &finished[..excess] {
+            self.jobs.remove(id);
+        }
+    }
+}
+
+type JsonResponse = Response<Cursor<Vec<u8>>>;
+
+/// Who may send requests, and which media files they may queue.
+#[derive(Debug, Default)]
+struct Access {
+    /// `username:password` that basic auth must carry, if any
+    credentials: Option<String>,
+    library_roots: Vec<PathBuf>,
+}
+
+impl Access {
+    /// Whether the `Authorization` header carries the credentials, if they
+    /// are required.
+    fn is_authorized(&self, authorization: Option<&str>) -> bool {
+        let Some(credentials) = &self.credentials else {
+            return true;
+        };
+        authorization
+            .and_then(|value| value.split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("basic"))
+            .and_then(|(_, encoded)| BASE64.decode(encoded.trim()).ok())
+            .is_some_and(|decoded| decoded == credentials.as_bytes())
+    }
+
+    /// Whether the media file is in one of the library roots. Paths that
+    /// could climb out of them with `..` never are.
+    fn allows(&self, media_file: &Path) -> bool {
+        if self.library_roots.is_empty() {
+            return true;
+        }
+        media_file.is_absolute()
+            && media_file
+                .components()
+                .all(|c| !matches!(c, Component::ParentDir | Component::CurDir))
+            && self
+                .library_roots
+                .iter()
+                .any(|root| media_file.starts_with(root))
+    }
+}
+
+impl Args {
+    pub fn run(&self) -> Result<()> {
+        let pipeline = Arc::new(self.pipeline.build()?);
+        let credentials = match (&self.webhook_username, &self.webhook_password) {
+            (None, None) => None,
+            (username, password) => Some(format!(
+                "{}:{}",
+                username.as_deref().unwrap_or_default(),
+                password.as_deref().unwrap_or_default()
+            )),
+        };
+        let access = Access {
+            credentials,
+            library_roots: self.library_roots.clone(),
+        };
+        if access.credentials.is_none() {
+            warn!(
+                "webhook requests are not authenticated, set WEBHOOK_USERNAME and WEBHOOK_PASSWORD"
+            );
+        }
+        let jobs = Arc::new(Mutex::new(Jobs::default()));
+        let (tx, rx) = mpsc::channel::<u64>();
+        let rx = Arc::new(Mutex::new(rx));
+
+        for worker in 0..self.workers.max(1) {
+            let rx = Arc::clone(&rx);
+            let jobs = Arc::clone(&jobs);
+            let pipeline = Arc::clone(&pipeline);
+            thread::spawn(move || run_worker(worker, &rx, &jobs, &pipeline));
+        }
+
+        {
+            let jobs = Arc::clone(&jobs);
+            let pipeline = Arc::clone(&pipeline);
+            let tx = tx.clone();
+            thread::spawn(move || run_retries(&jobs, &pipeline, &tx));
+        }
+
+        let server = tiny_http::Server::http(&self.listen).map_err(|e| anyhow!(e))?;
+        info!(listen = %self.listen, workers = self.workers, "serving webhook");
+
+        for mut request in server.incoming_requests() {
+            let response = handle_request(&mut request, &access, &jobs, &tx);
+            if let Err(error) = request.respond(response) {
+                warn!(?error, "failed sending response");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn run_worker(
+    worker: usize,
+    rx: &Mutex<mpsc::Receiver<u64>>,
+    jobs: &Mutex<Jobs>,
+    pipeline: &Pipeline,
+) {
+    loop {
+        // Only hold the receiver lock while waiting for the next job
+        let Ok(id) = rx.lock().expect("job queue lock poisoned").recv() else {
+            return;
+        };
+        let Some(job) = jobs.lock().expect("job table lock poisoned").start(id) else {
+            continue;
+        };
+
+        info!(worker, id, media_file = %job.media_file.to_string_lossy(), "processing job");
+        let result = catch_panic(|| Ok(pipeline.run(&job)?));
+        if let Err(error) = &result {
+            warn!(worker, id, ?error, "job failed");
+        }
+        jobs.lock()
+            .expect("job table lock poisoned")
+            .finish(id, result);
+    }
+}
+
+/// Runs the job, turning a panic into a failure so that it neither takes the
+/// worker down with it nor leaves the job running in the table forever.
+fn catch_panic(process: impl FnOnce() -> Result<Vec<PathBuf>>) -> Result<Vec<PathBuf>> {
+    match panic::catch_unwind(AssertUnwindSafe(process)) {
+        Ok(result) => result,
+        Err(payload) => {
+            let message = payload
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown cause");
+            Err(anyhow!("processing panicked: {message}"))
+        }
+    }
+}
+
+/// Periodically queues jobs from the store whose retry delay has elapsed,
+/// including ones left pending by the Custom Script or a previous run.
+fn run_retries(jobs: &Mutex<Jobs>, pipeline: &Pipeline, tx: &mpsc::Sender<u64>) {
+    loop {
+        match pipeline.store.due() {
+            Ok(due) => {
+                for job in due {
+                    let mut jobs = jobs.lock().expect("job table lock poisoned");
+                    if jobs.is_queued(&job) {
+                        continue;
+                    }
+                    info!(media_file = %job.media_file.to_string_lossy(), "queueing retry");
+                    let id = jobs.push(job);
+                    if tx.send(id).is_err() {
+                        return;
+                    }
+                }
+            }
+            Err(error) => warn!(?error, "failed reading job store"),
+        }
+        thread::sleep(RETRY_POLL_INTERVAL);
+    }
+}
+
+fn handle_request(
+    request: &mut Request,
+    access: &Access,
+    jobs: &Mutex<Jobs>,
+    tx: &mpsc::Sender<u64>,
+) -> JsonResponse {
+    let method = request.method().clone();
+    let url = request.url().to_owned();
+    let authorization = request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Authorization"))
+        .map(|header| header.value.as_str().to_owned());
+    let body = request.as_reader();
+    route(
+        &method,
+        &url,
+        authorization.as_deref(),
+        body,
+        access,
+        jobs,
+        tx,
+    )
+}
+
+/// Answers a request by its method, URL, `Authorization` header and body.
+/// Everything but the health check needs the credentials, if any are set.
+fn route(
+    method: &Method,
+    url: &str,
+    authorization: Option<&str>,
+    body: impl Read,
+    access: &Access,
+    jobs: &Mutex<Jobs>,
+    tx: &mpsc::Sender<u64>,
+) -> JsonResponse {
+    let path = url.split('?').next().unwrap_or_default().to_owned();
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+    if segments != ["health"] && !access.is_authorized(authorization) {
+        let challenge = Header::from_bytes("WWW-Authenticate", r#"Basic realm="sonarr-script""#)
+            .expect("valid header");
+        return json_response(401, json!({ "error": "unauthorized" })).with_header(challenge);
+    }
+
+    match (method, segments.as_slice()) {
+        (Method::Get, ["health"]) => json_response(200, json!({ "status": "ok" })),
+        (Method::Post, ["webhook"]) => handle_webhook(body, access, jobs, tx),
+        (Method::Get, ["jobs"]) => {
+            let jobs = jobs.lock().expect("job table lock poisoned");
+            let statuses: Vec<&JobStatus> = jobs.jobs.values().collect();
+            json_response(200, json!(statuses))
+        }
+        (Method::Get, ["jobs", id]) => {
+            let jobs = jobs.lock().expect("job table lock poisoned");
+            match id.parse().ok().and_then(|id: u64| jobs.jobs.get(&id)) {
+                Some(status) => json_response(200, json!(status)),
+                None => json_response(404, json!({ "error": "job not found" })),
+            }
+        }
+        _ => json_response(404, json!({ "error": "not found" })),
+    }
+}
+
+fn handle_webhook(
+    body: impl Read,
+    access: &Access,
+    jobs: &Mutex<Jobs>,
+    tx: &mpsc::Sender<u64>,
+) -> JsonResponse {
+    let mut bytes = Vec::new();
+    if let Err(error) = body.take(MAX_BODY_SIZE + 1).read_to_end(&mut bytes) {
+        return json_response(400, json!({ "error": error.to_string() }));
+    }
+    if bytes.len() as u64 > MAX_BODY_SIZE {
+        return json_response(413, json!({ "error": "body too large" }));
+    }
+    let payload: WebhookPayload = match serde_json::from_slice(&bytes) {
+        Ok(payload) => payload,
+        Err(error) => return json_response(400, json!({ "error": error.to_string() })),
+    };
+
+    match payload.event_type() {
+        Some(EventType::Test) => {
+            info!("test event");
+            json_response(200, json!({ "status": "ok" }))
+        }
+        Some(EventType::Import) => {
+            let job = match payload.import_job() {
+                Ok(job) => job,
+                Err(error) => return json_response(400, json!({ "error": format!("{error:#}") })),
+            };
+            if !access.allows(&job.media_file) {
+                warn!(media_file = %job.media_file.to_string_lossy(), "media file is outside the library roots");
+                return json_response(
+                    403,
+                    json!({ "error": "media file is outside the library roots" }),
+                );
+            }
+            info!(media_file = %job.media_file.to_string_lossy(), is_upgrade = payload.is_upgrade, "queueing import");
+            let id = jobs.lock().expect("job table lock poisoned").push(job);
+            if tx.send(id).is_err() {
+                return json_response(503, json!({ "error": "no workers running" }));
+            }
+            json_response(202, json!({ "id": id }))
+        }
+        _ => {
+            info!(event_type = %payload.event_type, "ignoring event");
+            json_response(202, json!({ "status": "ignored" }))
+        }
+    }
+}
+
+fn json_response(status: u16, body: serde_json::Value) -> JsonResponse {
+    let header = Header::from_bytes("Content-Type", "application/json").expect("valid header");
+    Response::from_string(body.to_string())
+        .with_status_code(status)
+        .with_header(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+    use serde_json::Value;
+
+    use super::*;
+
+    const IMPORT: &str = r#"{"eventType":"Download","series":{"id":3,"path":"/tv/Show"},"episodeFile":{"relativePath":"Season 1/episode.mkv"}}"#;
+
+    fn job(media_file: &str) -> ImportJob {
+        ImportJob {
+            media_file: media_file.into(),
+            series_id: None,
+            movie_id: None,
+        }
+    }
+
+    fn call(
+        method: Method,
+        url: &str,
+        body: &str,
+        jobs: &Mutex<Jobs>,
+        tx: &mpsc::Sender<u64>,
+    ) -> (u16, Value) {
+        call_with(&Access::default(), None, method, url, body, jobs, tx)
+    }
+
+    fn call_with(
+        access: &Access,
+        authorization: Option<&str>,
+        method: Method,
+        url: &str,
+        body: &str,
+        jobs: &Mutex<Jobs>,
+        tx: &mpsc::Sender<u64>,
+    ) -> (u16, Value) {
+        let response = route(
+            &method,
+            url,
+            authorization,
+            body.as_bytes(),
+            access,
+            jobs,
+            tx,
+        );
+        let status = response.status_code().0;
+        let body = serde_json::from_slice(&response.into_reader().into_inner()).unwrap();
+        (status, body)
+    }
+
+    #[test]
+    fn test_webhook_queues_import() {
+        let jobs = Mutex::new(Jobs::default());
+        let (tx, rx) = mpsc::channel();
+
+        let (status, body) = call(Method::Post, "/webhook", IMPORT, &jobs, &tx);
+
+        assert_eq!(status, 202);
+        assert_eq!(body["id"], 1);
+        assert_eq!(rx.try_recv().unwrap(), 1);
+        let jobs = jobs.lock().unwrap();
+        assert_eq!(
+            jobs.jobs[&1].job.media_file,
+            Path::new("/tv/Show/Season 1/episode.mkv")
+        );
+        assert_eq!(jobs.jobs[&1].state, JobState::Queued);
+    }
+
+    #[rstest]
+    #[case(Method::Get, "/health", "", 200)]
+    #[case(Method::Post, "/webhook", r#"{"eventType":"Test"}"#, 200)]
+    #[case(Method::Post, "/webhook", r#"{"eventType":"Grab"}"#, 202)]
+    #[case(Method::Post, "/webhook", "not json", 400)]
+    #[case(Method::Post, "/webhook", r#"{"eventType":"Download"}"#, 400)]
+    #[case(Method::Get, "/webhook", "", 404)]
+    #[case(Method::Get, "/jobs/1", "", 404)]
+    #[case(Method::Get, "/jobs/one", "", 404)]
+    fn test_route_queues_nothing(
+        #[case] method: Method,
+        #[case] url: &str,
+        #[case] body: &str,
+        #[case] should: u16,
+    ) {
+        let jobs = Mutex::new(Jobs::default());
+        let (tx, rx) = mpsc::channel();
+
+        let (status, _) = call(method, url, body, &jobs, &tx);
+
+        assert_eq!(status, should);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[rstest]
+    #[case(Method::Get, "/health", None, 200)]
+    #[case(Method::Get, "/jobs", None, 401)]
+    #[case(Method::Post, "/webhook", None, 401)]
+    // sonarr:hunter2 and sonarr:wrong
+    #[case(Method::Post, "/webhook", Some("Basic c29uYXJyOmh1bnRlcjI="), 202)]
+    #[case(Method::Post, "/webhook", Some("basic c29uYXJyOmh1bnRlcjI="), 202)]
+    #[case(Method::Post, "/webhook", Some("Basic c29uYXJyOndyb25n"), 401)]
+    #[case(Method::Post, "/webhook", Some("Bearer hunter2"), 401)]
+    fn test_route_checks_credentials(
+        #[case] method: Method,
+        #[case] url: &str,
+        #[case] authorization: Option<&str>,
+        #[case] should: u16,
+    ) {
+        let access = Access {
+            credentials: Some("sonarr:hunter2".to_owned()),
+            library_roots: Vec::new(),
+        };
+        let jobs = Mutex::new(Jobs::default());
+        let (tx, _rx) = mpsc::channel();
+
+        let (status, _) = call_with(&access, authorization, method, url, IMPORT, &jobs, &tx);
+
+        assert_eq!(status, should);
+    }
+
+    #[rstest]
+    #[case("/tv/Show/Season 1/episode.mkv", true)]
+    #[case("/movies/Movie/movie.mkv", true)]
+    #[case("/tv/Show/../../etc/episode.mkv", false)]
+    #[case("/tvshows/episode.mkv", false)]
+    #[case("/etc/episode.mkv", false)]
+    #[case("episode.mkv", false)]
+    fn test_access_allows(#[case] media_file: &str, #[case] should: bool) {
+        let access = Access {
+            credentials: None,
+            library_roots: vec!["/tv".into(), "/movies".into()],
+        };
+
+        assert_eq!(access.allows(Path::new(media_file)), should);
+    }
+
+    #[test]
+    fn test_webhook_rejects_file_outside_library() {
+        let access = Access {
+            credentials: None,
+            library_roots: vec!["/movies".into()],
+        };
+        let jobs = Mutex::new(Jobs::default());
+        let (tx, rx) = mpsc::channel();
+
+        let (status, _) = call_with(&access, None, Method::Post, "/webhook", IMPORT, &jobs, &tx);
+
+        assert_eq!(status, 403);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_webhook_rejects_large_body() {
+        let jobs = Mutex::new(Jobs::default());
+        let (tx, _rx) = mpsc::channel();
+        let body = " ".repeat(MAX_BODY_SIZE as usize) + IMPORT;
+
+        let (status, _) = call(Method::Post, "/webhook", &body, &jobs, &tx);
+
+        assert_eq!(status, 413);
+    }
+
+    #[test]
+    fn test_jobs_reports_status() {
+        let jobs = Mutex::new(Jobs::default());
+        let (tx, _rx) = mpsc::channel();
+        {
+            let mut jobs = jobs.lock().unwrap();
+            let id = jobs.push(job("/tv/a.mkv"));
+            jobs.start(id);
+            jobs.finish(id, Err(anyhow!("ffmpeg failed")));
+            jobs.push(job("/tv/b.mkv"));
+        }
+
+        let (status, body) = call(Method::Get, "/jobs", "", &jobs, &tx);
+        assert_eq!(status, 200);
+        assert_eq!(body.as_array().unwrap().len(), 2);
+
+        let (status, body) = call(Method::Get, "/jobs/1?verbose", "", &jobs, &tx);
+        assert_eq!(status, 200);
+        assert_eq!(body["state"], "failed");
+        assert_eq!(body["error"], "ffmpeg failed");
+        assert_eq!(body["media_file"], "/tv/a.mkv");
+    }
+
+    #[test]
+    fn test_is_queued_until_finished() {
+        let mut jobs = Jobs::default();
+        let id = jobs.push(job("/tv/a.mkv"));
+        assert!(jobs.is_queued(&job("/tv/a.mkv")));
+        assert!(!jobs.is_queued(&job("/tv/b.mkv")));
+
+        jobs.start(id);
+        assert!(jobs.is_queued(&job("/tv/a.mkv")));
+
+        jobs.finish(id, Ok(vec!["/tv/a.en.srt".into()]));
+        assert!(!jobs.is_queued(&job("/tv/a.mkv")));
+        assert_eq!(jobs.jobs[&id].written, [PathBuf::from("/tv/a.en.srt")]);
+    }
+
+    #[test]
+    fn test_prune_keeps_unfinished_jobs() {
+        let mut jobs = Jobs::default();
+        let queued = jobs.push(job("/tv/queued.mkv"));
+        for i in 0..MAX_FINISHED_JOBS + 5 {
+            let id = jobs.push(job(&format!("/tv/{i}.mkv")));
+            jobs.finish(id, Ok(Vec::new()));
+        }
+
+        // Pruning happens when a job is pushed
+        jobs.push(job("/tv/last.mkv"));
+
+        let finished = jobs
+            .jobs
+            .values()
+            .filter(|s| s.state == JobState::Succeeded)
+            .count();
+        assert_eq!(finished, MAX_FINISHED_JOBS);
+        assert!(jobs.jobs.contains_key(&queued));
+        // The oldest finished jobs went first
+        assert!(!jobs.jobs.contains_key(&(queued + 1)));
+        assert!(jobs.jobs.contains_key(&(queued + 6)));
+    }
+
+    #[test]
+    fn test_catch_panic() {
+        let result = catch_panic(|| panic!("index out of bounds"));
+
+        let error = result.unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "processing panicked: index out of bounds"
+        );
+    }
+}
@@ -1,11 +1,11 @@
 use std::path::PathBuf;
 
-use anyhow::Context;
+use sonarr_script::error::Error;
+use sonarr_script::pipeline::EventType;
+use sonarr_script::pipeline::ImportJob;
+use sonarr_script::pipeline::PipelineArgs;
 use tracing::info;
 
-use crate::subtitle;
-use crate::subtitle::SubtitleMergeContext;
-
 #[derive(Debug, Clone, clap::Args)]
 pub struct Args {
     /// Sonarr event type
@@ -19,14 +19,13 @@ pub struct Args {
     /// Full path to the episode file
     #[clap(short = 'i', long, env = "sonarr_episodefile_path")]
     pub episodefile_path: Option<PathBuf>,
-}
 
-#[derive(Debug, Clone, PartialEq, Eq, clap::ValueEnum)]
-#[clap(rename_all = "pascal_case")]
-#[non_exhaustive]
-pub enum EventType {
-    Test,
-    Import,
+    /// Sonarr series ID, used to rescan the series after writing subtitles
+    #[clap(long, env = "sonarr_series_id")]
+    pub series_id: Option<u64>,
+
+    #[clap(flatten)]
+    pub pipeline: PipelineArgs,
 }
 
 impl Args {
@@ -34,6 +33,10 @@ impl Args {
         match self.eventtype {
             EventType::Test => self.handle_test(),
             EventType::Import => self.handle_import(),
+            _ => {
+                info!(eventtype = ?self.eventtype, "unsupported event, exiting");
+                Ok(())
+            }
         }
     }
 
@@ -46,10 +49,17 @@ impl Args {
         let media_file = self
             .episodefile_path
             .clone()
-            .context("sonarr_episodefile_path must be set")?;
+            .ok_or_else(|| Error::Config("sonarr_episodefile_path must be set".to_owned()))?;
 
-        let context = SubtitleMergeContext { media_file };
+        let pipeline = self.pipeline.build_one_shot()?;
 
-        subtitle::extract_and_merge(&context)
+        let job = ImportJob {
+            media_file,
+            series_id: self.series_id,
+            movie_id: None,
+        };
+        pipeline.run(&job)?;
+
+        Ok(())
     }
 }
@@ -0,0 +1,55 @@
+use camino::Utf8PathBuf;
+use lingua::IsoCode639_1;
+use lingua::Language;
+use tracing::info;
+
+use sonarr_script::detect::DEFAULT_MIN_CONFIDENCE;
+use sonarr_script::detect::DEFAULT_MIN_LETTERS;
+use sonarr_script::detect::LanguageDetection;
+use sonarr_script::sub::SubtitleTrack;
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct Args {
+    /// Input subtitle file with several languages in it
+    input: Utf8PathBuf,
+
+    /// Directory to write a file per language to, `input.en.ass` and so on.
+    /// Defaults to the directory of the input.
+    #[clap(long, short)]
+    output_dir: Option<Utf8PathBuf>,
+
+    /// ISO 639-1 codes of the languages to tell apart
+    #[clap(long, value_delimiter = ',', default_value = "en,zh")]
+    languages: Vec<IsoCode639_1>,
+}
+
+impl Args {
+    pub fn run(&self) -> anyhow::Result<()> {
+        let languages: Vec<Language> = self
+            .languages
+            .iter()
+            .map(Language::from_iso_code_639_1)
+            .collect();
+        if languages.len() < 2 {
+            anyhow::bail!("need at least two languages to split into");
+        }
+        let detection =
+            LanguageDetection::new(&languages, DEFAULT_MIN_LETTERS, DEFAULT_MIN_CONFIDENCE)?;
+
+        let track = SubtitleTrack::load(&self.input)?;
+        let output_dir = match &self.output_dir {
+            Some(output_dir) => output_dir.clone(),
+            None => self.input.parent().unwrap_or("".into()).to_owned(),
+        };
+        let stem = self.input.file_stem().unwrap_or_default();
+        let extension = self.input.extension().unwrap_or("ass");
+        for (language, part) in track.split_languages(&detection)? {
+            let output =
+                output_dir.join(format!("{stem}.{}.{extension}", language.iso_code_639_1()));
+            info!(?language, %output, "writing split subtitle file");
+            part.save_as(&output)?;
+        }
+
+        Ok(())
+    }
+}
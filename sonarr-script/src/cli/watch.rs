@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::fs::TryLockError;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::Context;
+use notify::EventKind;
+use notify::RecursiveMode;
+use notify::Watcher;
+use notify::event::AccessKind;
+use notify::event::AccessMode;
+use notify::event::ModifyKind;
+use tracing::debug;
+use tracing::info;
+use tracing::warn;
+
+use crate::cli::DEFAULT_MEDIA_EXTENSIONS;
+use sonarr_script::cache;
+use sonarr_script::pipeline::ImportJob;
+use sonarr_script::pipeline::PipelineArgs;
+
+/// How often pending files are checked for stability
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the job store is checked for jobs due for a retry
+const RETRY_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct Args {
+    /// Library roots to watch recursively
+    #[clap(required = true)]
+    roots: Vec<PathBuf>,
+
+    /// File extensions considered media files
+    #[clap(long, value_delimiter = ',', default_value = DEFAULT_MEDIA_EXTENSIONS)]
+    extensions: Vec<String>,
+
+    /// Seconds a file's size must stay unchanged before it is processed
+    #[clap(long, default_value_t = 30)]
+    settle: u64,
+
+    #[clap(flatten)]
+    pipeline: PipelineArgs,
+}
+
+/// A media file seen changing, waiting until it stops changing.
+struct Pending {
+    size: Option<u64>,
+    stable_since: Instant,
+}
+
+impl Args {
+    pub fn run(&self) -> anyhow::Result<()> {
+        let pipeline = self.pipeline.build()?;
+        let settle = Duration::from_secs(self.settle);
+
+        let (tx, rx) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(tx)?;
+        for root in &self.roots {
+            watcher
+                .watch(root, RecursiveMode::Recursive)
+                .with_context(|| format!("failed watching {}", root.display()))?;
+            info!(root = %root.display(), "watching library root");
+        }
+
+        let mut pending: HashMap<PathBuf, Pending> = HashMap::new();
+        let mut last_retry = Instant::now();
+        loop {
+            match rx.recv_timeout(POLL_INTERVAL) {
+                Ok(Ok(event)) => {
+                    // Reads, including our own, must not queue the file again
+                    if !matches!(
+                        event.kind,
+                        EventKind::Create(_)
+                            | EventKind::Modify(ModifyKind::Data(_) | ModifyKind::Name(_))
+                            | EventKind::Access(AccessKind::Close(AccessMode::Write))
+                    ) {
+                        continue;
+                    }
+                    for path in event.paths {
+                        if is_media_file(&path, &self.extensions) && !pending.contains_key(&path) {
+                            debug!(path = %path.display(), "media file changed");
+                            pending.insert(
+                                path,
+                                Pending {
+                                    size: None,
+                                    stable_since: Instant::now(),
+                                },
+                            );
+                        }
+                    }
+                }
+                Ok(Err(error)) => warn!(?error, "watch error"),
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => anyhow::bail!("watcher stopped"),
+            }
+
+            let ready: Vec<PathBuf> = pending
+                .iter_mut()
+                .filter_map(|(path, pending)| pending.poll(path, settle).then(|| path.clone()))
+                .collect();
+            for path in ready {
+                pending.remove(&path);
+                if !path.exists() {
+                    continue;
+                }
+                let job = ImportJob {
+                    media_file: path,
+                    series_id: None,
+                    movie_id: None,
+                };
+                if let Err(error) = pipeline.run(&job) {
+                    warn!(media_file = %job.media_file.display(), ?error, "failed processing media file");
+                }
+            }
+
+            if last_retry.elapsed() >= RETRY_POLL_INTERVAL {
+                last_retry = Instant::now();
+                // The journal may be mid-rewrite by another process, so try
+                // again on the next round rather than stop watching
+                let due = pipeline.store.due().unwrap_or_else(|error| {
+                    warn!(?error, "failed reading job store");
+                    Vec::new()
+                });
+                for job in due {
+                    info!(media_file = %job.media_file.display(), "retrying media file");
+                    if let Err(error) = pipeline.run(&job) {
+                        warn!(media_file = %job.media_file.display(), ?error, "failed processing media file");
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Whether the path has one of the extensions and is not one of the files
+/// kept in a subtitle cache.
+fn is_media_file(path: &Path, extensions: &[String]) -> bool {
+    let in_cache = path.components().any(|c| c.as_os_str() == cache::LOCAL_DIR);
+    let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+    !in_cache
+        && extension.is_some_and(|extension| {
+            extensions
+                .iter()
+                .any(|e| e.eq_ignore_ascii_case(&extension))
+        })
+}
+
+impl Pending {
+    /// Checks the file again, returning whether it has been stable for long
+    /// enough. Files that vanish are reported ready so they can be dropped.
+    fn poll(&mut self, path: &Path, settle: Duration) -> bool {
+        let size = std::fs::metadata(path).ok().map(|m| m.len());
+        if size.is_none() {
+            return true;
+        }
+        if size != self.size {
+            self.size = size;
+            self.stable_since = Instant::now();
+            return false;
+        }
+        self.stable_since.elapsed() >= settle && !is_locked(path)
+    }
+}
+
+/// Whether another process holds a lock on the file. This is only a best
+/// effort, as most download clients write files without locking them, which
+/// is what waiting for the size to settle is for.
+fn is_locked(path: &Path) -> bool {
+    let Ok(file) = File::open(path) else {
+        return true;
+    };
+    matches!(file.try_lock_shared(), Err(TryLockError::WouldBlock))
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case("/tv/Show/episode.mkv", true)]
+    #[case("/tv/Show/episode.MKV", true)]
+    #[case("/tv/Show/episode.en.srt", false)]
+    #[case("/tv/Show/episode", false)]
+    #[case("/tv/Show/.subtitles/episode/0_2.en.mkv", false)]
+    fn test_is_media_file(#[case] path: &str, #[case] should: bool) {
+        let extensions = ["mkv".to_owned(), "mp4".to_owned()];
+
+        assert_eq!(is_media_file(Path::new(path), &extensions), should);
+    }
+
+    fn pending() -> Pending {
+        Pending {
+            size: None,
+            stable_since: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn test_poll_waits_for_size_to_settle() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("episode.mkv");
+        std::fs::write(&path, "part").unwrap();
+        let mut pending = pending();
+
+        // The first look only learns the size
+        assert!(!pending.poll(&path, Duration::ZERO));
+        std::fs::write(&path, "partial").unwrap();
+        assert!(!pending.poll(&path, Duration::ZERO));
+        assert!(pending.poll(&path, Duration::ZERO));
+        assert!(!pending.poll(&path, Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_poll_drops_vanished_file() {
+        let dir = tempfile::tempdir().unwrap();
+
+        assert!(pending().poll(&dir.path().join("episode.mkv"), Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_poll_waits_for_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("episode.mkv");
+        std::fs::write(&path, "video").unwrap();
+        let mut pending = pending();
+        assert!(!pending.poll(&path, Duration::ZERO));
+
+        let writer = File::options().write(true).open(&path).unwrap();
+        writer.lock().unwrap();
+        assert!(!pending.poll(&path, Duration::ZERO));
+
+        drop(writer);
+        assert!(pending.poll(&path, Duration::ZERO));
+    }
+}
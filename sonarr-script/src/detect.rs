@@ -0,0 +1,224 @@
+//! Tells what language subtitle text is in.
+
+use std::str::FromStr;
+
+use anyhow::Result;
+use anyhow::bail;
+use lingua::IsoCode639_1;
+use lingua::Language;
+use lingua::LanguageDetector;
+use lingua::LanguageDetectorBuilder;
+
+/// Languages text is told apart between by default. Besides the wanted ones,
+/// these are languages subtitles are commonly in, so that they are not
+/// mistaken for a wanted one.
+pub const DEFAULT_LANGUAGES: &str = "en,zh,ja,ko,es,pt,fr,de,it,ru";
+
+/// Fewest letters text needs for its language to be told
+pub const DEFAULT_MIN_LETTERS: usize = 3;
+
+/// Least confidence, from 0 to 1, that the language of text is told with
+pub const DEFAULT_MIN_CONFIDENCE: f64 = 0.5;
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct DetectArgs {
+    /// ISO 639-1 codes of the languages subtitle text is told apart between.
+    /// Text in a language left out is mistaken for the closest one listed
+    #[clap(
+        long,
+        env = "DETECT_LANGUAGES",
+        value_delimiter = ',',
+        default_value = DEFAULT_LANGUAGES
+    )]
+    pub detect_languages: Vec<IsoCode639_1>,
+
+    /// Subtitle events with fewer letters than this are too short to tell the
+    /// language of, and are left out
+    #[clap(long, env = "DETECT_MIN_LETTERS", default_value_t = DEFAULT_MIN_LETTERS)]
+    pub detect_min_letters: usize,
+
+    /// Confidence, from 0 to 1, below which the language of a subtitle event
+    /// is taken to be unknown
+    #[clap(long, env = "DETECT_MIN_CONFIDENCE", default_value_t = DEFAULT_MIN_CONFIDENCE)]
+    pub detect_min_confidence: f64,
+}
+
+/// Detects languages with a detector that is built once, since building one
+/// is expensive.
+pub struct LanguageDetection {
+    detector: LanguageDetector,
+    min_letters: usize,
+    min_confidence: f64,
+}
+
+/// How much of a track is in a language.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LanguageShare {
+    pub language: Language,
+    /// Share of the texts considered that are in the language
+    pub share: f64,
+    /// Mean confidence the language of those texts was told with
+    pub confidence: f64,
+}
+
+/// The languages a number of texts are in.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LanguageDistribution {
+    /// Number of texts long enough to tell the language of
+    pub considered: usize,
+    /// Number of those whose language could not be told confidently
+    pub unknown: usize,
+    /// Languages by their share of the texts considered, largest first
+    pub languages: Vec<LanguageShare>,
+}
+
+impl LanguageDistribution {
+    /// The language most texts are in.
+    pub fn top(&self) -> Option<&LanguageShare> {
+        self.languages.first()
+    }
+}
+
+impl Default for LanguageDetection {
+    fn default() -> Self {
+        let languages: Vec<Language> = DEFAULT_LANGUAGES
+            .split(',')
+            .map(|code| Language::from_iso_code_639_1(&IsoCode639_1::from_str(code).unwrap()))
+            .collect();
+        Self::new(&languages, DEFAULT_MIN_LETTERS, DEFAULT_MIN_CONFIDENCE)
+            .expect("default languages are set")
+    }
+}
+
+impl LanguageDetection {
+    pub fn new(languages: &[Language], min_letters: usize, min_confidence: f64) -> Result<Self> {
+        if languages.is_empty() {
+            bail!("no languages to detect");
+        }
+        Ok(Self {
+            detector: LanguageDetectorBuilder::from_languages(languages).build(),
+            min_letters,
+            min_confidence,
+        })
+    }
+
+    pub fn from_args(args: &DetectArgs) -> Result<Self> {
+        let languages: Vec<Language> = args
+            .detect_languages
+            .iter()
+            .map(Language::from_iso_code_639_1)
+            .collect();
+        Self::new(
+            &languages,
+            args.detect_min_letters,
+            args.detect_min_confidence,
+        )
+    }
+
+    /// The language of the text along with the confidence it was told with,
+    /// unless the text is too short or it could not be told confidently.
+    pub fn detect(&self, text: &str) -> Option<(Language, f64)> {
+        if !self.is_considered(text) {
+            return None;
+        }
+        let (language, confidence) = *self
+            .detector
+            .compute_language_confidence_values(text)
+            .first()?;
+        (confidence >= self.min_confidence).then_some((language, confidence))
+    }
+
+    /// The languages the texts are in. Texts too short to tell the language
+    /// of are left out, while texts whose language could not be told count
+    /// against the share of every language.
+    pub fn distribution<'a>(
+        &self,
+        texts: impl IntoIterator<Item = &'a str>,
+    ) -> LanguageDistribution {
+        let mut distribution = LanguageDistribution::default();
+        // Number of texts and sum of confidences, by language
+        let mut detected: Vec<(Language, usize, f64)> = Vec::new();
+        for text in texts {
+            if !self.is_considered(text) {
+                continue;
+            }
+            distribution.considered += 1;
+            let Some((language, confidence)) = self.detect(text) else {
+                distribution.unknown += 1;
+                continue;
+            };
+            match detected.iter_mut().find(|(l, _, _)| *l == language) {
+                Some((_, count, sum)) => {
+                    *count += 1;
+                    *sum += confidence;
+                }
+                None => detected.push((language, 1, confidence)),
+            }
+        }
+        detected.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        distribution.languages = detected
+            .into_iter()
+            .map(|(language, count, sum)| LanguageShare {
+                language,
+                share: count as f64 / distribution.considered as f64,
+                confidence: sum / count as f64,
+            })
+            .collect();
+        distribution
+    }
+
+    /// Whether the text has enough letters to tell its language, and is not
+    /// mostly punctuation, numbers or symbols.
+    fn is_considered(&self, text: &str) -> bool {
+        let letters = text.chars().filter(|c| c.is_alphabetic()).count();
+        let visible = text.chars().filter(|c| !c.is_whitespace()).count();
+        letters >= self.min_letters && letters * 2 >= visible
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    fn detection() -> LanguageDetection {
+        LanguageDetection::new(
+            &[Language::English, Language::Chinese],
+            DEFAULT_MIN_LETTERS,
+            DEFAULT_MIN_CONFIDENCE,
+        )
+        .unwrap()
+    }
+
+    #[rstest]
+    #[case("We have to find him.", Some(Language::English))]
+    #[case("我们必须找到他。", Some(Language::Chinese))]
+    #[case("Oh", None)]
+    #[case("♪ ~ 1:23 ~ ♪", None)]
+    fn test_detect(#[case] text: &str, #[case] should: Option<Language>) {
+        let got = detection().detect(text).map(|(language, _)| language);
+
+        assert_eq!(got, should);
+    }
+
+    #[test]
+    fn test_distribution() {
+        let texts = [
+            "Curses were springing up like maggots.",
+            "We have to find him.",
+            "我们必须找到他。",
+            "...",
+        ];
+
+        let got = detection().distribution(texts);
+
+        assert_eq!(got.considered, 3);
+        assert_eq!(got.unknown, 0);
+        let top = got.top().unwrap();
+        assert_eq!(top.language, Language::English);
+        assert!((top.share - 2.0 / 3.0).abs() < 1e-9);
+        assert!(top.confidence > DEFAULT_MIN_CONFIDENCE);
+        assert_eq!(got.languages[1].language, Language::Chinese);
+    }
+}
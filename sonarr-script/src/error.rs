@@ -0,0 +1,136 @@
+//! Errors the library returns, so that callers can tell why something failed
+//! without matching on messages.
+//!
+//! The binary exits with a code telling them apart too, so that they show up
+//! in Sonarr's logs as more than a failed script:
+//!
+//! | Code | Meaning                                                    |
+//! |------|------------------------------------------------------------|
+//! | 0    | Success, including no suitable subtitles unless that fails |
+//! | 1    | Any other failure                                          |
+//! | 2    | Invalid command line arguments                             |
+//! | 3    | Invalid configuration                                      |
+//! | 4    | The media file could not be probed                         |
+//! | 5    | The media file has no suitable subtitles                   |
+//! | 6    | A subtitle file could not be parsed                        |
+//! | 7    | A file could not be read or written                        |
+//! | 8    | An external program such as ffmpeg failed                  |
+
+use std::path::PathBuf;
+
+use crate::process::ProcessError;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// ffprobe could not list the streams of a media file
+    #[error("failed probing {}: {source}", path.display())]
+    Probe { path: PathBuf, source: ProcessError },
+
+    /// The media file has no subtitles that bilingual ones can be made from,
+    /// as none of them are English
+    #[error("no suitable subtitles in {}", path.display())]
+    NoSuitableSubtitles { path: PathBuf },
+
+    /// A subtitle file is in no format that can be read, or is malformed
+    #[error("failed parsing subtitle file {}: {message}", path.display())]
+    Parse { path: PathBuf, message: String },
+
+    /// A file could not be read
+    #[error("failed reading {}: {source}", path.display())]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    /// A file could not be written
+    #[error("failed writing {}: {source:#}", path.display())]
+    Write {
+        path: PathBuf,
+        #[source]
+        source: anyhow::Error,
+    },
+
+    /// An external program other than ffprobe failed, such as ffmpeg
+    /// extracting or remuxing streams
+    #[error(transparent)]
+    Process(#[from] ProcessError),
+
+    /// Options that cannot be used, or together
+    #[error("invalid configuration: {0}")]
+    Config(String),
+
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl Error {
+    /// Code the process exits with, from the table above.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Self::Config(_) => 3,
+            Self::Probe { .. } => 4,
+            Self::NoSuitableSubtitles { .. } => 5,
+            Self::Parse { .. } => 6,
+            Self::Read { .. } | Self::Write { .. } => 7,
+            Self::Process(_) => 8,
+            Self::Other(_) => 1,
+        }
+    }
+
+    pub(crate) fn write(path: impl Into<PathBuf>, source: impl Into<anyhow::Error>) -> Self {
+        Self::Write {
+            path: path.into(),
+            source: source.into(),
+        }
+    }
+
+    pub(crate) fn parse(path: impl Into<PathBuf>, error: aspasia::Error) -> Self {
+        let path = path.into();
+        match error {
+            aspasia::Error::FileIoError(source) => Self::Read { path, source },
+            error => Self::Parse {
+                path,
+                message: error.to_string(),
+            },
+        }
+    }
+}
+
+/// Errors coming up through `anyhow` keep their kind if they are one of ours.
+impl From<anyhow::Error> for Error {
+    fn from(error: anyhow::Error) -> Self {
+        let error = match error.downcast::<Error>() {
+            Ok(error) => return error,
+            Err(error) => error,
+        };
+        match error.downcast::<ProcessError>() {
+            Ok(error) => Self::Process(error),
+            Err(error) => Self::Other(error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+
+    use super::*;
+
+    #[test]
+    fn test_from_anyhow_keeps_process_errors() {
+        let error: anyhow::Result<()> = Err(ProcessError::NotFound {
+            program: "ffmpeg".to_owned(),
+        })
+        .context("failed extracting subtitles");
+
+        let error = Error::from(error.unwrap_err());
+
+        assert!(matches!(
+            error,
+            Error::Process(ProcessError::NotFound { .. })
+        ));
+        assert_eq!(error.exit_code(), 8);
+    }
+}
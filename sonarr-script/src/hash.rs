@@ -0,0 +1,9 @@
+//! Hashing to tell files apart, not to protect them.
+
+/// FNV-1a hash of the bytes, as 16 hex digits.
+pub fn fnv1a(bytes: &[u8]) -> String {
+    let hash = bytes.iter().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    });
+    format!("{hash:016x}")
+}
@@ -0,0 +1,588 @@
+//! A journal of the media files processed, so that failed jobs can be
+//! retried and finished ones are not redone.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use anyhow::Context;
+use anyhow::Result;
+use serde::Deserialize;
+use serde::Serialize;
+use tracing::info;
+use tracing::warn;
+
+use crate::atomic;
+use crate::pipeline::ImportJob;
+
+/// Longest delay between two attempts of the same job
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Jobs left running for longer than this are assumed to belong to a process
+/// that died, and become due again
+const STALE_RUNNING: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct JobStoreArgs {
+    /// Journal file recording the processing state of each media file.
+    /// Defaults to `$XDG_STATE_HOME/sonarr-script/jobs.json`, or
+    /// `~/.local/state/sonarr-script/jobs.json`. The Custom Script goes
+    /// without one if neither is set
+    #[clap(long, env = "JOB_STORE", global = true)]
+    pub job_store: Option<PathBuf>,
+
+    /// Number of attempts before a job is given up on
+    #[clap(long, env = "JOB_MAX_ATTEMPTS", default_value_t = 5, global = true)]
+    pub job_max_attempts: u32,
+
+    /// Delay in seconds before the first retry, doubled on each retry
+    #[clap(long, env = "JOB_RETRY_DELAY", default_value_t = 60, global = true)]
+    pub job_retry_delay: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    /// Waiting for its first attempt or for a retry
+    Pending,
+    Running,
+    Succeeded,
+    /// Given up on after too many attempts
+    Failed,
+}
+
+/// Size and modification time of a media file, used to tell whether a file
+/// has changed since it was last processed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fingerprint {
+    pub size: u64,
+    pub modified: u64,
+}
+
+impl Fingerprint {
+    pub fn of(path: &Path) -> Result<Self> {
+        let metadata = std::fs::metadata(path)?;
+        let modified = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        Ok(Self {
+            size: metadata.len(),
+            modified,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobRecord {
+    #[serde(flatten)]
+    pub job: ImportJob,
+    pub state: JobState,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    /// Unix time after which a pending job may be attempted
+    pub next_attempt_at: u64,
+    /// Unix time of the last state change
+    pub updated_at: u64,
+    pub fingerprint: Option<Fingerprint>,
+    #[serde(default)]
+    pub written: Vec<PathBuf>,
+}
+
+impl JobRecord {
+    fn new(job: ImportJob) -> Self {
+        Self {
+            job,
+            state: JobState::Pending,
+            attempts: 0,
+            last_error: None,
+            next_attempt_at: 0,
+            updated_at: now(),
+            fingerprint: None,
+            written: Vec::new(),
+        }
+    }
+
+    fn is_due(&self, now: u64) -> bool {
+        match self.state {
+            JobState::Pending => self.next_attempt_at <= now,
+            JobState::Running => self.updated_at + STALE_RUNNING.as_secs() <= now,
+            JobState::Succeeded | JobState::Failed => false,
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Journal {
+    jobs: BTreeMap<PathBuf, JobRecord>,
+}
+
+/// Outcome of trying to start a job
+enum Claim {
+    Start,
+    AlreadyDone(Vec<PathBuf>),
+    AlreadyRunning,
+}
+
+/// A JSON journal of every media file processed, so failed work can be
+/// retried later and finished work is not redone.
+pub struct JobStore {
+    /// Journal file, unless jobs go unrecorded
+    path: Option<PathBuf>,
+    max_attempts: u32,
+    retry_delay: Duration,
+}
+
+impl JobStore {
+    pub fn open(args: &JobStoreArgs) -> Result<Self> {
+        let path = match &args.job_store {
+            Some(path) => path.clone(),
+            None => default_path()?,
+        };
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        Ok(Self {
+            path: Some(path),
+            max_attempts: args.job_max_attempts.max(1),
+            retry_delay: Duration::from_secs(args.job_retry_delay),
+        })
+    }
+
+    /// Same as `open`, but jobs go unrecorded if there is nowhere to keep
+    /// the journal by default, for one-shot runs that retry nothing
+    /// themselves. Every job is then processed, even if it was before.
+    pub fn open_optional(args: &JobStoreArgs) -> Result<Self> {
+        if args.job_store.is_some() || default_path().is_ok() {
+            return Self::open(args);
+        }
+        warn!("no job store location, set JOB_STORE to record jobs");
+        Ok(Self {
+            path: None,
+            max_attempts: args.job_max_attempts.max(1),
+            retry_delay: Duration::from_secs(args.job_retry_delay),
+        })
+    }
+
+    /// Processes the job unless the same media file was already processed or
+    /// is being processed right now, recording the outcome.
+    pub fn run(
+        &self,
+        job: &ImportJob,
+        process: impl FnOnce(&ImportJob) -> Result<Vec<PathBuf>>,
+    ) -> Result<Vec<PathBuf>> {
+        let media_file = job.media_file.to_string_lossy();
+        let fingerprint = Fingerprint::of(&job.media_file).ok();
+
+        let claim = self.update(|journal| {
+            let record = journal
+                .jobs
+                .entry(job.media_file.clone())
+                .or_insert_with(|| JobRecord::new(job.clone()));
+            let unchanged = fingerprint.is_some() && record.fingerprint == fingerprint;
+            match record.state {
+                JobState::Succeeded if unchanged => {
+                    return Claim::AlreadyDone(record.written.clone());
+                }
+                JobState::Running if !record.is_due(now()) => return Claim::AlreadyRunning,
+                _ => {}
+            }
+            // A replaced file deserves a fresh set of attempts
+            if record.fingerprint.is_some() && record.fingerprint != fingerprint {
+                record.attempts = 0;
+            }
+            record.job = job.clone();
+            record.state = JobState::Running;
+            record.attempts += 1;
+            record.fingerprint = fingerprint;
+            record.updated_at = now();
+            Claim::Start
+        })?;
+
+        match claim {
+            Claim::Start => {}
+            Claim::AlreadyDone(written) => {
+                info!(%media_file, "media file already processed, skipping");
+                return Ok(written);
+            }
+            Claim::AlreadyRunning => {
+                info!(%media_file, "media file is already being processed, skipping");
+                return Ok(Vec::new());
+            }
+        }
+
+        let result = process(job);
+
+        self.update(|journal| {
+            let Some(record) = journal.jobs.get_mut(&job.media_file) else {
+                return;
+            };
+            record.updated_at = now();
+            match &result {
+                Ok(written) => {
+                    record.state = JobState::Succeeded;
+                    record.last_error = None;
+                    record.written = written.clone();
+                    // Embedding subtitles replaces the file, which must not
+                    // count as a new one when it is seen again
+                    record.fingerprint = Fingerprint::of(&job.media_file).ok();
+                }
+                Err(error) if record.attempts < self.max_attempts => {
+                    let delay = self.retry_delay(record.attempts);
+                    warn!(%media_file, attempts = record.attempts, retry_in = ?delay, "job failed, will retry");
+                    record.state = JobState::Pending;
+                    record.last_error = Some(format!("{error:#}"));
+                    record.next_attempt_at = now() + delay.as_secs();
+                }
+                Err(error) => {
+                    warn!(%media_file, attempts = record.attempts, "job failed, giving up");
+                    record.state = JobState::Failed;
+                    record.last_error = Some(format!("{error:#}"));
+                }
+            }
+        })?;
+
+        result
+    }
+
+    /// Exponential backoff for the given number of attempts made so far.
+    fn retry_delay(&self, attempts: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempts.saturating_sub(1));
+        self.retry_delay.saturating_mul(factor).min(MAX_RETRY_DELAY)
+    }
+
+    pub fn list(&self) -> Result<Vec<JobRecord>> {
+        Ok(self.read()?.jobs.into_values().collect())
+    }
+
+    /// Jobs whose retry delay has elapsed.
+    pub fn due(&self) -> Result<Vec<ImportJob>> {
+        let now = now();
+        Ok(self
+            .read()?
+            .jobs
+            .into_values()
+            .filter(|record| record.is_due(now))
+            .map(|record| record.job)
+            .collect())
+    }
+
+    /// Makes pending jobs due immediately, skipping what is left of their
+    /// retry delay but keeping count of the attempts made.
+    pub fn expedite(&self) -> Result<Vec<ImportJob>> {
+        self.update(|journal| {
+            journal
+                .jobs
+                .values_mut()
+                .filter(|record| record.state == JobState::Pending)
+                .map(|record| {
+                    record.next_attempt_at = 0;
+                    record.job.clone()
+                })
+                .collect()
+        })
+    }
+
+    /// Makes matching jobs due immediately with a fresh set of attempts. When
+    /// `media_files` is empty, every pending job is matched, plus failed ones
+    /// if `include_failed` is set.
+    pub fn reset(&self, media_files: &[PathBuf], include_failed: bool) -> Result<Vec<ImportJob>> {
+        self.update(|journal| {
+            journal
+                .jobs
+                .values_mut()
+                .filter(|record| {
+                    if media_files.is_empty() {
+                        record.state == JobState::Pending
+                            || (include_failed && record.state == JobState::Failed)
+                    } else {
+                        media_files.contains(&record.job.media_file)
+                    }
+                })
+                .map(|record| {
+                    record.state = JobState::Pending;
+                    record.attempts = 0;
+                    record.next_attempt_at = 0;
+                    record.fingerprint = None;
+                    record.updated_at = now();
+                    record.job.clone()
+                })
+                .collect()
+        })
+    }
+
+    /// Removes jobs in any of the given states, returning how many were
+    /// removed.
+    pub fn purge(&self, states: &[JobState]) -> Result<usize> {
+        self.update(|journal| {
+            let before = journal.jobs.len();
+            journal
+                .jobs
+                .retain(|_, record| !states.contains(&record.state));
+            before - journal.jobs.len()
+        })
+    }
+
+    fn read(&self) -> Result<Journal> {
+        let _lock = self.lock()?;
+        self.read_unlocked()
+    }
+
+    fn read_unlocked(&self) -> Result<Journal> {
+        let Some(path) = &self.path else {
+            return Ok(Journal::default());
+        };
+        match std::fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content)
+                .with_context(|| format!("failed parsing job store {}", path.display())),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(Journal::default()),
+            Err(error) => Err(error).context("failed reading job store"),
+        }
+    }
+
+    /// Applies a change to the journal while holding the store lock, so that
+    /// the Custom Script and a running server can share the same store.
+    fn update<T>(&self, f: impl FnOnce(&mut Journal) -> T) -> Result<T> {
+        let Some(path) = &self.path else {
+            return Ok(f(&mut Journal::default()));
+        };
+        let _lock = self.lock()?;
+        let mut journal = self.read_unlocked()?;
+        let output = f(&mut journal);
+
+        atomic::replace_with(path, |tmp| {
+            let mut file = File::create(tmp)?;
+            serde_json::to_writer_pretty(&mut file, &journal)?;
+            file.flush()?;
+            Ok(())
+        })
+        .context("failed writing job store")?;
+
+        Ok(output)
+    }
+
+    fn lock(&self) -> Result<Option<File>> {
+        let Some(path) = &self.path else {
+            return Ok(None);
+        };
+        let file = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path.with_extension("lock"))?;
+        file.lock().context("failed locking job store")?;
+        Ok(Some(file))
+    }
+}
+
+fn default_path() -> Result<PathBuf> {
+    let state_home = std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state")))
+        .context("unable to pick a job store location, set JOB_STORE")?;
+    Ok(state_home.join("sonarr-script").join("jobs.json"))
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case(1, 60)]
+    #[case(2, 120)]
+    #[case(5, 960)]
+    #[case(40, 24 * 60 * 60)]
+    fn test_retry_delay(#[case] attempts: u32, #[case] should: u64) {
+        let store = JobStore {
+            path: None,
+            max_attempts: 5,
+            retry_delay: Duration::from_secs(60),
+        };
+
+        assert_eq!(store.retry_delay(attempts), Duration::from_secs(should));
+    }
+
+    fn open_store(dir: &Path, max_attempts: u32) -> JobStore {
+        JobStore::open(&JobStoreArgs {
+            job_store: Some(dir.join("state/jobs.json")),
+            job_max_attempts: max_attempts,
+            job_retry_delay: 0,
+        })
+        .unwrap()
+    }
+
+    fn media_file(dir: &Path) -> ImportJob {
+        let media_file = dir.join("episode.mkv");
+        std::fs::write(&media_file, "video").unwrap();
+        ImportJob {
+            media_file,
+            series_id: Some(3),
+            movie_id: None,
+        }
+    }
+
+    fn record(store: &JobStore, job: &ImportJob) -> JobRecord {
+        store
+            .list()
+            .unwrap()
+            .into_iter()
+            .find(|record| record.job.media_file == job.media_file)
+            .unwrap()
+    }
+
+    fn fail(_: &ImportJob) -> Result<Vec<PathBuf>> {
+        anyhow::bail!("ffmpeg failed")
+    }
+
+    #[test]
+    fn test_run_skips_processed_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = open_store(dir.path(), 5);
+        let job = media_file(dir.path());
+        let written = vec![dir.path().join("episode.en.srt")];
+
+        assert_eq!(store.run(&job, |_| Ok(written.clone())).unwrap(), written);
+        let again = store.run(&job, |_| panic!("processed again")).unwrap();
+
+        assert_eq!(again, written);
+        assert_eq!(record(&store, &job).state, JobState::Succeeded);
+    }
+
+    #[test]
+    fn test_run_skips_file_it_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = open_store(dir.path(), 5);
+        let job = media_file(dir.path());
+        let embed = |job: &ImportJob| {
+            std::fs::write(&job.media_file, "video with subtitles")?;
+            Ok(vec![job.media_file.clone()])
+        };
+
+        store.run(&job, embed).unwrap();
+        let again = store.run(&job, |_| panic!("processed again")).unwrap();
+
+        assert_eq!(again, std::slice::from_ref(&job.media_file));
+    }
+
+    #[test]
+    fn test_run_reprocesses_replaced_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = open_store(dir.path(), 5);
+        let job = media_file(dir.path());
+        store.run(&job, fail).unwrap_err();
+        store.run(&job, fail).unwrap_err();
+        assert_eq!(record(&store, &job).attempts, 2);
+
+        std::fs::write(&job.media_file, "upgraded video").unwrap();
+        store.run(&job, |_| Ok(Vec::new())).unwrap();
+
+        let record = record(&store, &job);
+        assert_eq!(record.state, JobState::Succeeded);
+        assert_eq!(record.attempts, 1);
+    }
+
+    #[test]
+    fn test_run_skips_running_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = open_store(dir.path(), 5);
+        let job = media_file(dir.path());
+
+        store
+            .run(&job, |job| {
+                assert_eq!(record(&store, job).state, JobState::Running);
+                let nested = store.run(job, |_| panic!("processed twice at once"));
+                assert!(nested.unwrap().is_empty());
+                Ok(Vec::new())
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_run_gives_up_after_max_attempts() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = open_store(dir.path(), 2);
+        let job = media_file(dir.path());
+
+        store.run(&job, fail).unwrap_err();
+        let record_after_one = record(&store, &job);
+        assert_eq!(record_after_one.state, JobState::Pending);
+        assert_eq!(
+            record_after_one.last_error.as_deref(),
+            Some("ffmpeg failed")
+        );
+        assert_eq!(store.due().unwrap(), std::slice::from_ref(&job));
+
+        store.run(&job, fail).unwrap_err();
+        assert_eq!(record(&store, &job).state, JobState::Failed);
+        assert!(store.due().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_reset_and_purge() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = open_store(dir.path(), 1);
+        let job = media_file(dir.path());
+        store.run(&job, fail).unwrap_err();
+
+        assert!(store.reset(&[], false).unwrap().is_empty());
+        assert_eq!(store.reset(&[], true).unwrap(), std::slice::from_ref(&job));
+        let reset = record(&store, &job);
+        assert_eq!(reset.state, JobState::Pending);
+        assert_eq!(reset.attempts, 0);
+
+        assert_eq!(store.purge(&[JobState::Failed]).unwrap(), 0);
+        assert_eq!(store.purge(&[JobState::Pending]).unwrap(), 1);
+        assert!(store.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_expedite_keeps_attempts() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = JobStore::open(&JobStoreArgs {
+            job_store: Some(dir.path().join("jobs.json")),
+            job_max_attempts: 5,
+            job_retry_delay: 60,
+        })
+        .unwrap();
+        let job = media_file(dir.path());
+        store.run(&job, fail).unwrap_err();
+        store.run(&job, fail).unwrap_err();
+        assert!(store.due().unwrap().is_empty());
+
+        assert_eq!(store.expedite().unwrap(), std::slice::from_ref(&job));
+
+        assert_eq!(store.due().unwrap(), std::slice::from_ref(&job));
+        assert_eq!(record(&store, &job).attempts, 2);
+    }
+
+    #[test]
+    fn test_unrecorded_store_processes_every_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = JobStore {
+            path: None,
+            max_attempts: 5,
+            retry_delay: Duration::ZERO,
+        };
+        let job = media_file(dir.path());
+
+        store.run(&job, |_| Ok(Vec::new())).unwrap();
+        store.run(&job, fail).unwrap_err();
+
+        assert!(store.list().unwrap().is_empty());
+    }
+}
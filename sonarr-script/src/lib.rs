@@ -0,0 +1,46 @@
+//! Dual-language subtitles for Sonarr and Radarr libraries.
+//!
+//! The `sonarr-script` binary is a thin command line over this library, which
+//! other tools can use to do the same work:
+//!
+//! - [`sub::SubtitleTrack`] loads subtitle files of any format, applies the
+//!   cleaning rules, detects languages and scripts, and saves them again.
+//! - [`detect::LanguageDetection`] tells the language of subtitle text.
+//! - [`subtitle`] probes media files for subtitle streams, extracts them, and
+//!   merges them into bilingual subtitles, with
+//!   [`subtitle::extract_and_merge`] doing all of it for a media file.
+//! - [`pipeline::Pipeline`] runs that for imported media files, recording jobs
+//!   and notifying media servers, configured by [`pipeline::PipelineArgs`].
+//! - [`report::ProcessingReport`] records what processing a media file found
+//!   and did, and [`report::ReportSummary`] adds reports up across a library.
+//!
+//! Failures are reported as an [`error::Error`], which tells probing, parsing,
+//! I/O and configuration problems apart. External programs such as ffmpeg are
+//! run through a [`process::CommandRunner`], so that they can be faked.
+
+pub mod atomic;
+#[cfg(feature = "ocr")]
+mod bitmap;
+pub mod cache;
+pub mod detect;
+pub mod error;
+mod hash;
+pub mod jobs;
+#[cfg(feature = "matroska")]
+mod matroska;
+pub mod metrics;
+pub mod naming;
+pub mod notifier;
+#[cfg(feature = "ocr")]
+pub mod ocr;
+pub mod overwrite;
+pub mod pinyin;
+pub mod pipeline;
+pub mod process;
+pub mod reflow;
+pub mod remux;
+pub mod report;
+pub mod script;
+pub mod sub;
+pub mod subtitle;
+pub mod webhook;
@@ -1,25 +1,39 @@
 use std::process::ExitCode;
 
 use clap::Parser;
+use sonarr_script::error::Error;
 
 use crate::cli::Cli;
 use crate::cli::SubCommand;
 
 mod cli;
-mod sub;
-mod subtitle;
 
-fn main() -> anyhow::Result<ExitCode> {
+/// Exits with a code telling what failed, as documented in
+/// [`sonarr_script::error`].
+fn main() -> ExitCode {
     let format = tracing_subscriber::fmt::format();
     tracing_subscriber::fmt().event_format(format).init();
 
-    match Cli::parse() {
+    let result = match Cli::parse() {
         Cli::SonarrSubtitleMerge(args) | Cli::Default(SubCommand::SonarrSubtitleMerge(args)) => {
             args.run()
         }
         Cli::Merge(args) | Cli::Default(SubCommand::Merge(args)) => args.run(),
         Cli::Convert(args) | Cli::Default(SubCommand::Convert(args)) => args.run(),
         Cli::Clean(args) | Cli::Default(SubCommand::Clean(args)) => args.run(),
+        Cli::Split(args) | Cli::Default(SubCommand::Split(args)) => args.run(),
+        Cli::Serve(args) | Cli::Default(SubCommand::Serve(args)) => args.run(),
+        Cli::Jobs(args) | Cli::Default(SubCommand::Jobs(args)) => args.run(),
+        Cli::Watch(args) | Cli::Default(SubCommand::Watch(args)) => args.run(),
+        Cli::Scan(args) | Cli::Default(SubCommand::Scan(args)) => args.run(),
+        Cli::Cache(args) | Cli::Default(SubCommand::Cache(args)) => args.run(),
+        Cli::Report(args) | Cli::Default(SubCommand::Report(args)) => args.run(),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("Error: {error:?}");
+            ExitCode::from(Error::from(error).exit_code())
+        }
     }
-    .map(|_| ExitCode::SUCCESS)
 }
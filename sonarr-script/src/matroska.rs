@@ -0,0 +1,959 @@
+//! Minimal Matroska demuxer that reads text subtitle tracks straight out of
+//! .mkv/.mks files, without needing ffmpeg.
+//!
+//! Only what subtitles need is parsed: track headers and the blocks of text
+//! subtitle tracks. Everything else, including video and audio blocks, is
+//! skipped over without being read.
+
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::bail;
+use aspasia::AssSubtitle;
+use aspasia::Moment;
+use aspasia::SubRipSubtitle;
+use aspasia::WebVttSubtitle;
+
+use crate::sub::SubtitleTrack;
+
+const EBML: u32 = 0x1A45DFA3;
+const SEGMENT: u32 = 0x18538067;
+const INFO: u32 = 0x1549A966;
+const TIMESTAMP_SCALE: u32 = 0x2AD7B1;
+const TRACKS: u32 = 0x1654AE6B;
+const TRACK_ENTRY: u32 = 0xAE;
+const TRACK_NUMBER: u32 = 0xD7;
+const TRACK_TYPE: u32 = 0x83;
+const FLAG_DEFAULT: u32 = 0x88;
+const FLAG_FORCED: u32 = 0x55AA;
+const NAME: u32 = 0x536E;
+const LANGUAGE: u32 = 0x22B59C;
+const CODEC_ID: u32 = 0x86;
+const CODEC_PRIVATE: u32 = 0x63A2;
+const CONTENT_ENCODINGS: u32 = 0x6D80;
+const CONTENT_ENCODING: u32 = 0x6240;
+const CONTENT_ENCODING_SCOPE: u32 = 0x5032;
+const CONTENT_ENCODING_TYPE: u32 = 0x5033;
+const CONTENT_COMPRESSION: u32 = 0x5034;
+const CONTENT_COMP_ALGO: u32 = 0x4254;
+const CONTENT_COMP_SETTINGS: u32 = 0x4255;
+const CLUSTER: u32 = 0x1F43B675;
+const CLUSTER_TIMESTAMP: u32 = 0xE7;
+const SIMPLE_BLOCK: u32 = 0xA3;
+const BLOCK_GROUP: u32 = 0xA0;
+const BLOCK: u32 = 0xA1;
+const BLOCK_DURATION: u32 = 0x9B;
+const ATTACHMENTS: u32 = 0x1941A469;
+
+/// Elements that may directly follow a cluster of unknown size, ending it
+const TOP_LEVEL: [u32; 8] = [
+    CLUSTER,
+    INFO,
+    TRACKS,
+    ATTACHMENTS,
+    0x114D9B74,
+    0x1C53BB6B,
+    0x1043A770,
+    0x1254C367,
+];
+
+const TRACK_TYPE_SUBTITLE: u64 = 0x11;
+/// Content encoding scope bit for the frames of a track
+const SCOPE_FRAMES: u64 = 1;
+/// Content compression algorithm that strips a common header off frames
+const HEADER_STRIPPING: u64 = 3;
+
+/// How long an event lasts when its block does not say
+const DEFAULT_EVENT_DURATION_MS: i64 = 5000;
+
+/// A text subtitle track read from a Matroska file.
+pub struct MatroskaSubtitle {
+    /// Position of the track among all tracks, which is the stream index
+    /// ffmpeg would give it
+    pub index: usize,
+    pub codec_id: String,
+    /// ISO 639-2 language, `eng` when the file does not say
+    pub language: String,
+    pub name: Option<String>,
+    pub default: bool,
+    pub forced: bool,
+    pub track: SubtitleTrack,
+}
+
+impl MatroskaSubtitle {
+    /// The ffmpeg codec name for the track's codec.
+    pub fn codec(&self) -> &str {
+        ffmpeg_codec(&self.codec_id)
+    }
+}
+
+/// A PGS or VobSub track read from a Matroska file, still undecoded.
+#[cfg_attr(not(feature = "ocr"), allow(dead_code))]
+pub struct MatroskaBitmapSubtitle {
+    /// Position of the track among all tracks, which is the stream index
+    /// ffmpeg would give it
+    pub index: usize,
+    pub codec_id: String,
+    /// ISO 639-2 language, `eng` when the file does not say
+    pub language: String,
+    pub name: Option<String>,
+    pub default: bool,
+    pub forced: bool,
+    /// For VobSub, the `.idx` header with the palette
+    pub codec_private: Vec<u8>,
+    pub blocks: Vec<BitmapBlock>,
+}
+
+impl MatroskaBitmapSubtitle {
+    /// The ffmpeg codec name for the track's codec.
+    pub fn codec(&self) -> &str {
+        ffmpeg_codec(&self.codec_id)
+    }
+}
+
+/// One block of a bitmap track: a PGS display set or a VobSub packet.
+#[cfg_attr(not(feature = "ocr"), allow(dead_code))]
+pub struct BitmapBlock {
+    /// Milliseconds from the start of the file
+    pub start: i64,
+    /// Milliseconds, if the file says
+    pub duration: Option<i64>,
+    pub data: Vec<u8>,
+}
+
+fn ffmpeg_codec(codec_id: &str) -> &str {
+    match codec_id {
+        "S_TEXT/UTF8" => "subrip",
+        "S_TEXT/ASS" => "ass",
+        "S_TEXT/SSA" => "ssa",
+        "S_TEXT/WEBVTT" => "webvtt",
+        "S_HDMV/PGS" => "hdmv_pgs_subtitle",
+        "S_VOBSUB" => "dvd_subtitle",
+        other => other,
+    }
+}
+
+/// Whether the file starts with an EBML header, ie. is Matroska or WebM.
+pub fn is_matroska(path: &Path) -> bool {
+    let mut magic = [0; 4];
+    File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .is_ok_and(|_| u32::from_be_bytes(magic) == EBML)
+}
+
+/// What is read out of a Matroska file.
+pub struct MatroskaFile {
+    /// Every SubRip, ASS, SSA and WebVTT track. Tracks in other codecs, such
+    /// as bitmap subtitles, are left out.
+    pub subtitles: Vec<MatroskaSubtitle>,
+    /// Every PGS and VobSub track, if asked for
+    pub bitmaps: Vec<MatroskaBitmapSubtitle>,
+}
+
+/// Reads the text subtitle tracks of the file, and the bitmap subtitle
+/// tracks if `bitmaps` is set. Bitmap tracks can be large, so
+/// they are skipped unless they will be used.
+pub fn read_matroska(path: &Path, bitmaps: bool) -> Result<MatroskaFile> {
+    let file = File::open(path).with_context(|| format!("failed opening {}", path.display()))?;
+    let mut demuxer = Demuxer::new(BufReader::new(file));
+    demuxer.bitmaps = bitmaps;
+    demuxer
+        .read()
+        .with_context(|| format!("failed demuxing {}", path.display()))?;
+
+    let mut subtitles = Vec::new();
+    let mut bitmaps = Vec::new();
+    for (index, track) in demuxer.tracks.into_iter().enumerate() {
+        if track.track_type != TRACK_TYPE_SUBTITLE {
+            continue;
+        }
+        if !track.is_text() && !bitmaps_wanted(demuxer.bitmaps, &track) {
+            continue;
+        }
+        let track = track
+            .decode_content()
+            .with_context(|| format!("failed decoding track {index} of {}", path.display()))?;
+        if bitmaps_wanted(demuxer.bitmaps, &track) {
+            let to_ms = |ticks: i64| ticks * demuxer.timestamp_scale as i64 / 1_000_000;
+            let blocks = track
+                .blocks
+                .into_iter()
+                .map(|block| BitmapBlock {
+                    start: to_ms(block.timestamp),
+                    duration: block.duration.map(|d| to_ms(d as i64)),
+                    data: block.data,
+                })
+                .collect();
+            bitmaps.push(MatroskaBitmapSubtitle {
+                index,
+                codec_id: track.codec_id,
+                language: track.language,
+                name: track.name,
+                default: track.default,
+                forced: track.forced,
+                codec_private: track.codec_private,
+                blocks,
+            });
+            continue;
+        }
+        let Some(subtitle) = track.decode(demuxer.timestamp_scale)? else {
+            continue;
+        };
+        subtitles.push(MatroskaSubtitle {
+            index,
+            codec_id: track.codec_id,
+            language: track.language,
+            name: track.name,
+            default: track.default,
+            forced: track.forced,
+            track: subtitle.into(),
+        });
+    }
+
+    Ok(MatroskaFile { subtitles, bitmaps })
+}
+
+fn bitmaps_wanted(bitmaps: bool, track: &Track) -> bool {
+    bitmaps && track.is_bitmap()
+}
+
+struct Track {
+    number: u64,
+    track_type: u64,
+    codec_id: String,
+    codec_private: Vec<u8>,
+    language: String,
+    name: Option<String>,
+    default: bool,
+    forced: bool,
+    encodings: Vec<ContentEncoding>,
+    blocks: Vec<Block>,
+}
+
+impl Default for Track {
+    fn default() -> Self {
+        // Defaults from the Matroska specification
+        Self {
+            number: 0,
+            track_type: 0,
+            codec_id: String::new(),
+            codec_private: Vec::new(),
+            language: "eng".into(),
+            name: None,
+            default: true,
+            forced: false,
+            encodings: Vec::new(),
+            blocks: Vec::new(),
+        }
+    }
+}
+
+/// How the frames or codec private data of a track were transformed when
+/// written. Only compression is described, as encryption is never undone.
+struct ContentEncoding {
+    scope: u64,
+    /// `None` for encryption
+    algorithm: Option<u64>,
+    settings: Vec<u8>,
+}
+
+impl Default for ContentEncoding {
+    fn default() -> Self {
+        // Defaults from the Matroska specification: zlib on every frame
+        Self {
+            scope: SCOPE_FRAMES,
+            algorithm: Some(0),
+            settings: Vec::new(),
+        }
+    }
+}
+
+struct Block {
+    /// In units of the segment's timestamp scale
+    timestamp: i64,
+    duration: Option<u64>,
+    data: Vec<u8>,
+}
+
+impl Track {
+    fn is_text(&self) -> bool {
+        matches!(
+            self.codec_id.as_str(),
+            "S_TEXT/UTF8" | "S_TEXT/ASS" | "S_TEXT/SSA" | "S_TEXT/WEBVTT"
+        )
+    }
+
+    fn is_bitmap(&self) -> bool {
+        matches!(self.codec_id.as_str(), "S_HDMV/PGS" | "S_VOBSUB")
+    }
+
+    /// Undoes the content encodings of the track. Only header stripping is
+    /// supported; mkvmerge compresses some subtitle tracks with zlib, which
+    /// fails here so ffmpeg can extract them instead.
+    fn decode_content(mut self) -> Result<Self> {
+        for encoding in std::mem::take(&mut self.encodings).iter().rev() {
+            match encoding.algorithm {
+                Some(HEADER_STRIPPING) => {
+                    if encoding.scope & SCOPE_FRAMES != 0 {
+                        for block in &mut self.blocks {
+                            block.data.splice(0..0, encoding.settings.iter().copied());
+                        }
+                    }
+                }
+                Some(algorithm) => {
+                    bail!("compression algorithm {algorithm} is not supported")
+                }
+                None => bail!("encrypted tracks are not supported"),
+            }
+        }
+        Ok(self)
+    }
+
+    /// Rebuilds the track as a subtitle file in its own format, then parses
+    /// it. Returns `None` for codecs that are not text.
+    fn decode(&self, timestamp_scale: u64) -> Result<Option<AssSubtitle>> {
+        let to_ms = |ticks: i64| ticks * timestamp_scale as i64 / 1_000_000;
+        let mut events: Vec<(Moment, Moment, &[u8])> = self
+            .blocks
+            .iter()
+            .map(|block| {
+                let start = to_ms(block.timestamp);
+                let end = match block.duration {
+                    Some(duration) => to_ms(block.timestamp + duration as i64),
+                    None => start + DEFAULT_EVENT_DURATION_MS,
+                };
+                (
+                    Moment::from(start),
+                    Moment::from(end),
+                    block.data.as_slice(),
+                )
+            })
+            .collect();
+        events.sort_by_key(|(start, ..)| *start);
+
+        let text = |data: &[u8]| {
+            String::from_utf8_lossy(data)
+                .trim_end_matches(['\r', '\n'])
+                .to_owned()
+        };
+        let subtitle = match self.codec_id.as_str() {
+            "S_TEXT/UTF8" => {
+                let mut srt = String::new();
+                for (i, (start, end, data)) in events.iter().enumerate() {
+                    srt.push_str(&format!(
+                        "{}\n{} --> {}\n{}\n\n",
+                        i + 1,
+                        start.as_srt_timestamp(),
+                        end.as_srt_timestamp(),
+                        text(data)
+                    ));
+                }
+                let srt = SubRipSubtitle::from_str(&srt).context("invalid SubRip track")?;
+                AssSubtitle::from(srt)
+            }
+            "S_TEXT/WEBVTT" => {
+                let mut vtt = String::from("WEBVTT\n\n");
+                for (start, end, data) in &events {
+                    vtt.push_str(&format!(
+                        "{} --> {}\n{}\n\n",
+                        start.as_vtt_timestamp(),
+                        end.as_vtt_timestamp(),
+                        text(data)
+                    ));
+                }
+                let vtt = WebVttSubtitle::from_str(&vtt).context("invalid WebVTT track")?;
+                AssSubtitle::from(vtt)
+            }
+            "S_TEXT/ASS" | "S_TEXT/SSA" => {
+                // Blocks hold `ReadOrder,Layer,Style,...,Text`, with the
+                // timing moved out into the block itself
+                let mut dialogue: Vec<(u64, String)> = Vec::new();
+                for (start, end, data) in &events {
+                    let line = text(data);
+                    let mut fields = line.splitn(3, ',');
+                    let (Some(read_order), Some(layer), Some(rest)) =
+                        (fields.next(), fields.next(), fields.next())
+                    else {
+                        bail!("invalid ASS block: {line}");
+                    };
+                    let read_order = read_order.trim().parse().unwrap_or(u64::MAX);
+                    let layer = layer.trim();
+                    let (start, end) = (
+                        start.as_substation_timestamp(),
+                        end.as_substation_timestamp(),
+                    );
+                    dialogue.push((
+                        read_order,
+                        format!("Dialogue: {layer},{start},{end},{rest}"),
+                    ));
+                }
+                // Keep the authored order for events starting together
+                dialogue.sort_by_key(|(read_order, _)| *read_order);
+
+                let mut ass = String::from_utf8_lossy(&self.codec_private).into_owned();
+                if !ass.contains("[Events]") {
+                    ass.push_str("\n[Events]\nFormat: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text\n");
+                }
+                if !ass.ends_with('\n') {
+                    ass.push('\n');
+                }
+                for (_, line) in dialogue {
+                    ass.push_str(&line);
+                    ass.push('\n');
+                }
+                AssSubtitle::from_str(&ass).context("invalid ASS track")?
+            }
+            _ => return Ok(None),
+        };
+
+        Ok(Some(subtitle))
+    }
+}
+
+struct Demuxer<R> {
+    reader: R,
+    timestamp_scale: u64,
+    tracks: Vec<Track>,
+    /// Whether to keep the blocks of bitmap tracks
+    bitmaps: bool,
+    /// An element header read while looking for the end of an element of
+    /// unknown size, which belongs to whatever comes next
+    peeked: Option<(u32, Option<u64>)>,
+    /// Length of the file, which no element can be longer than
+    len: u64,
+}
+
+impl<R: Read + Seek> Demuxer<R> {
+    fn new(reader: R) -> Self {
+        Self {
+            reader,
+            timestamp_scale: 1_000_000,
+            tracks: Vec::new(),
+            bitmaps: false,
+            peeked: None,
+            len: 0,
+        }
+    }
+
+    fn read(&mut self) -> Result<()> {
+        self.len = self.reader.seek(SeekFrom::End(0))?;
+        self.reader.seek(SeekFrom::Start(0))?;
+        let Some((EBML, Some(size))) = self.header()? else {
+            bail!("not a Matroska file");
+        };
+        self.skip(size)?;
+        let Some((SEGMENT, size)) = self.header()? else {
+            bail!("missing Matroska segment");
+        };
+        let segment_end = self.end(size)?;
+
+        while self.position()? < segment_end {
+            let Some((id, size)) = self.header()? else {
+                break;
+            };
+            match id {
+                INFO => self.read_info(size)?,
+                TRACKS => self.read_tracks(size)?,
+                CLUSTER => self.read_cluster(size)?,
+                _ => match size {
+                    Some(size) => self.skip(size)?,
+                    None => bail!("top level element {id:#x} has unknown size"),
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_info(&mut self, size: Option<u64>) -> Result<()> {
+        let end = self.end(size)?;
+        while let Some((id, size)) = self.child(end)? {
+            match id {
+                TIMESTAMP_SCALE => self.timestamp_scale = self.uint(size)?,
+                _ => self.skip_known(size)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn read_tracks(&mut self, size: Option<u64>) -> Result<()> {
+        let end = self.end(size)?;
+        while let Some((id, size)) = self.child(end)? {
+            if id != TRACK_ENTRY {
+                self.skip_known(size)?;
+                continue;
+            }
+            let entry_end = self.end(size)?;
+            let mut track = Track::default();
+            while let Some((id, size)) = self.child(entry_end)? {
+                match id {
+                    TRACK_NUMBER => track.number = self.uint(size)?,
+                    TRACK_TYPE => track.track_type = self.uint(size)?,
+                    FLAG_DEFAULT => track.default = self.uint(size)? != 0,
+                    FLAG_FORCED => track.forced = self.uint(size)? != 0,
+                    NAME => track.name = Some(self.string(size)?),
+                    LANGUAGE => track.language = self.string(size)?,
+                    CODEC_ID => track.codec_id = self.string(size)?,
+                    CODEC_PRIVATE => track.codec_private = self.bytes(size)?,
+                    CONTENT_ENCODINGS => track.encodings = self.read_content_encodings(size)?,
+                    _ => self.skip_known(size)?,
+                }
+            }
+            self.tracks.push(track);
+        }
+        Ok(())
+    }
+
+    /// Reads the content encodings of a track, in the order they were listed.
+    fn read_content_encodings(&mut self, size: Option<u64>) -> Result<Vec<ContentEncoding>> {
+        let end = self.end(size)?;
+        let mut encodings = Vec::new();
+        while let Some((id, size)) = self.child(end)? {
+            if id != CONTENT_ENCODING {
+                self.skip_known(size)?;
+                continue;
+            }
+            let encoding_end = self.end(size)?;
+            let mut encoding = ContentEncoding::default();
+            while let Some((id, size)) = self.child(encoding_end)? {
+                match id {
+                    CONTENT_ENCODING_SCOPE => encoding.scope = self.uint(size)?,
+                    CONTENT_ENCODING_TYPE => {
+                        if self.uint(size)? != 0 {
+                            encoding.algorithm = None;
+                        }
+                    }
+                    CONTENT_COMPRESSION => {
+                        let compression_end = self.end(size)?;
+                        while let Some((id, size)) = self.child(compression_end)? {
+                            match id {
+                                CONTENT_COMP_ALGO if encoding.algorithm.is_some() => {
+                                    encoding.algorithm = Some(self.uint(size)?)
+                                }
+                                CONTENT_COMP_SETTINGS => encoding.settings = self.bytes(size)?,
+                                _ => self.skip_known(size)?,
+                            }
+                        }
+                    }
+                    _ => self.skip_known(size)?,
+                }
+            }
+            encodings.push(encoding);
+        }
+        Ok(encodings)
+    }
+
+    fn read_cluster(&mut self, size: Option<u64>) -> Result<()> {
+        let end = self.end(size)?;
+        let mut cluster_timestamp = 0;
+        while let Some((id, size)) = self.child(end)? {
+            if size.is_none() && TOP_LEVEL.contains(&id) {
+                // The end of a cluster of unknown size
+                self.peeked = Some((id, size));
+                break;
+            }
+            match id {
+                CLUSTER_TIMESTAMP => cluster_timestamp = self.uint(size)? as i64,
+                SIMPLE_BLOCK => self.read_block(size, cluster_timestamp, None)?,
+                BLOCK_GROUP => {
+                    let group_end = self.end(size)?;
+                    let mut block = None;
+                    let mut duration = None;
+                    while let Some((id, size)) = self.child(group_end)? {
+                        match id {
+                            // The duration may come after the block
+                            BLOCK => block = Some((self.position()?, size)),
+                            BLOCK_DURATION => duration = Some(self.uint(size)?),
+                            _ => {}
+                        }
+                        if id != BLOCK_DURATION {
+                            self.skip_known(size)?;
+                        }
+                    }
+                    if let Some((position, size)) = block {
+                        self.reader.seek(SeekFrom::Start(position))?;
+                        self.read_block(size, cluster_timestamp, duration)?;
+                        self.reader.seek(SeekFrom::Start(group_end))?;
+                    }
+                }
+                _ => {
+                    if TOP_LEVEL.contains(&id) {
+                        self.peeked = Some((id, size));
+                        break;
+                    }
+                    self.skip_known(size)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a block, keeping it only if it belongs to a text subtitle track.
+    fn read_block(
+        &mut self,
+        size: Option<u64>,
+        cluster_timestamp: i64,
+        duration: Option<u64>,
+    ) -> Result<()> {
+        let Some(size) = size else {
+            bail!("block has unknown size");
+        };
+        let start = self.position()?;
+        let (number, _) = self.vint()?;
+        let mut header = [0; 3];
+        self.reader.read_exact(&mut header)?;
+        let relative = i16::from_be_bytes([header[0], header[1]]) as i64;
+        let header_len = self.position()? - start;
+
+        let track = self.tracks.iter().position(|track| {
+            track.number == number && (track.is_text() || bitmaps_wanted(self.bitmaps, track))
+        });
+        let Some(track) = track else {
+            self.skip(size.saturating_sub(header_len))?;
+            return Ok(());
+        };
+        // Subtitle blocks are never laced, so the rest is a single frame
+        let data = self.read_data(size.saturating_sub(header_len))?;
+        self.tracks[track].blocks.push(Block {
+            timestamp: cluster_timestamp + relative,
+            duration,
+            data,
+        });
+        Ok(())
+    }
+
+    /// Reads the next child header of an element ending at `end`.
+    fn child(&mut self, end: u64) -> Result<Option<(u32, Option<u64>)>> {
+        if self.peeked.is_some() || self.position()? >= end {
+            return Ok(None);
+        }
+        self.header()
+    }
+
+    /// Reads an element ID and size, or `None` at the end of the file. A
+    /// `None` size means the size is unknown.
+    fn header(&mut self) -> Result<Option<(u32, Option<u64>)>> {
+        if let Some(peeked) = self.peeked.take() {
+            return Ok(Some(peeked));
+        }
+        let mut first = [0; 1];
+        match self.reader.read_exact(&mut first) {
+            Ok(()) => {}
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(error) => return Err(error.into()),
+        }
+        let len = first[0].leading_zeros() as usize + 1;
+        if len > 4 {
+            bail!("invalid element ID at {}", self.position()? - 1);
+        }
+        let mut id = first[0] as u32;
+        for _ in 1..len {
+            let mut byte = [0; 1];
+            self.reader.read_exact(&mut byte)?;
+            id = (id << 8) | byte[0] as u32;
+        }
+
+        let (size, len) = self.vint()?;
+        let unknown = size == (1 << (7 * len)) - 1;
+        Ok(Some((id, (!unknown).then_some(size))))
+    }
+
+    /// Reads a variable length integer, returning its value without the
+    /// length marker and how many bytes it took.
+    fn vint(&mut self) -> Result<(u64, u32)> {
+        let mut first = [0; 1];
+        self.reader.read_exact(&mut first)?;
+        let len = first[0].leading_zeros() + 1;
+        if len > 8 {
+            bail!("invalid variable length integer");
+        }
+        let mut value = (first[0] as u64) & (0xFF >> len);
+        for _ in 1..len {
+            let mut byte = [0; 1];
+            self.reader.read_exact(&mut byte)?;
+            value = (value << 8) | byte[0] as u64;
+        }
+        Ok((value, len))
+    }
+
+    fn uint(&mut self, size: Option<u64>) -> Result<u64> {
+        let bytes = self.bytes(size)?;
+        if bytes.len() > 8 {
+            bail!("unsigned integer element is {} bytes long", bytes.len());
+        }
+        Ok(bytes
+            .iter()
+            .fold(0, |value, byte| (value << 8) | *byte as u64))
+    }
+
+    fn string(&mut self, size: Option<u64>) -> Result<String> {
+        let bytes = self.bytes(size)?;
+        let bytes = bytes.split(|b| *b == 0).next().unwrap_or_default();
+        Ok(String::from_utf8_lossy(bytes).into_owned())
+    }
+
+    fn bytes(&mut self, size: Option<u64>) -> Result<Vec<u8>> {
+        let Some(size) = size else {
+            bail!("element has unknown size");
+        };
+        self.read_data(size)
+    }
+
+    /// Reads the next `size` bytes. A corrupt size could be far larger than
+    /// the file, so it is checked against what is left of it before the
+    /// buffer is allocated.
+    fn read_data(&mut self, size: u64) -> Result<Vec<u8>> {
+        let position = self.position()?;
+        if size > self.len.saturating_sub(position) {
+            bail!("element of {size} bytes at {position} runs past the end of the file");
+        }
+        let mut data = vec![0; size as usize];
+        self.reader.read_exact(&mut data)?;
+        Ok(data)
+    }
+
+    fn skip_known(&mut self, size: Option<u64>) -> Result<()> {
+        let Some(size) = size else {
+            bail!("element has unknown size");
+        };
+        self.skip(size)
+    }
+
+    fn skip(&mut self, size: u64) -> Result<()> {
+        self.reader.seek(SeekFrom::Current(size as i64))?;
+        Ok(())
+    }
+
+    /// Where an element of the given size starting here ends.
+    fn end(&mut self, size: Option<u64>) -> Result<u64> {
+        Ok(match size {
+            Some(size) => self.position()? + size,
+            None => u64::MAX,
+        })
+    }
+
+    fn position(&mut self) -> Result<u64> {
+        Ok(self.reader.stream_position()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    const INPUT: &str = "../test/jjk_s02e01/input.mks";
+
+    #[test]
+    fn test_is_matroska() {
+        assert!(is_matroska(Path::new(INPUT)));
+        assert!(!is_matroska(Path::new(
+            "../test/jjk_s02e01/extracted.en.ass"
+        )));
+    }
+
+    #[test]
+    fn test_read_subtitle_tracks() {
+        let file = read_matroska(Path::new(INPUT), false).unwrap();
+        let tracks = file.subtitles;
+        let got: Vec<_> = tracks
+            .iter()
+            .map(|t| (t.index, t.codec(), t.language.as_str(), t.name.as_deref()))
+            .collect();
+
+        assert_eq!(
+            got,
+            [
+                (0, "ass", "eng", Some("English")),
+                (1, "ass", "chi", Some("Chinese Simplified")),
+                (2, "ass", "chi", Some("Chinese Traditional")),
+            ]
+        );
+    }
+
+    #[rstest]
+    #[case(0, "../test/jjk_s02e01/extracted.en.ass")]
+    #[case(1, "../test/jjk_s02e01/extracted.zh.ass")]
+    #[case(2, "../test/jjk_s02e01/extracted.zh-TW.ass")]
+    fn test_read_subtitle_tracks_matches_ffmpeg(#[case] index: usize, #[case] extracted: &str) {
+        let tracks = read_matroska(Path::new(INPUT), false).unwrap().subtitles;
+        let track = tracks.iter().find(|t| t.index == index).unwrap();
+        let extracted = SubtitleTrack::load(extracted).unwrap();
+
+        assert_eq!(timings(&track.track), timings(&extracted));
+    }
+
+    /// Events in presentation order, which is how ffmpeg writes them.
+    fn timings(track: &SubtitleTrack) -> Vec<(i64, i64, String)> {
+        let mut timings: Vec<_> = track
+            .events()
+            .iter()
+            .map(|e| (e.start.into(), e.end.into(), e.text.clone()))
+            .collect();
+        timings.sort();
+        timings
+    }
+
+    /// Encodes an element with a body shorter than 16 KiB.
+    fn element(id: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut element = id.to_vec();
+        match body.len() {
+            len @ ..0x7F => element.push(0x80 | len as u8),
+            len => element.extend((0x4000 | len as u16).to_be_bytes()),
+        }
+        element.extend(body);
+        element
+    }
+
+    /// An EBML header and a segment of unknown size holding the elements.
+    fn file(elements: &[Vec<u8>]) -> Vec<u8> {
+        let mut file = element(&[0x1A, 0x45, 0xDF, 0xA3], &[]);
+        file.extend([0x18, 0x53, 0x80, 0x67, 0x01]);
+        file.extend([0xFF; 7]);
+        file.extend(elements.concat());
+        file
+    }
+
+    #[test]
+    fn test_read_srt_block_group() {
+        let entry = [
+            element(&[0xD7], &[1]),
+            element(&[0x83], &[0x11]),
+            element(&[0x86], b"S_TEXT/UTF8"),
+        ];
+        let tracks = element(
+            &[0x16, 0x54, 0xAE, 0x6B],
+            &element(&[0xAE], &entry.concat()),
+        );
+        // Track 1, 10 ticks into the cluster, lasting 2000 ticks
+        let block = element(
+            &[0xA1],
+            &[[0x81, 0x00, 0x0A, 0x00].as_slice(), b"Hello"].concat(),
+        );
+        let group = element(&[0xA0], &[block, element(&[0x9B], &[0x07, 0xD0])].concat());
+        let cluster = element(
+            &[0x1F, 0x43, 0xB6, 0x75],
+            &[element(&[0xE7], &[0x03, 0xE8]), group].concat(),
+        );
+
+        let mut demuxer = Demuxer::new(io::Cursor::new(file(&[tracks, cluster])));
+        demuxer.read().unwrap();
+        let subtitle = demuxer.tracks[0].decode(1_000_000).unwrap().unwrap();
+        let track = SubtitleTrack::from(subtitle);
+
+        assert_eq!(timings(&track), [(1010, 3010, "Hello".to_owned())]);
+    }
+
+    /// An 8 byte element size of 1 TiB.
+    const HUGE_SIZE: [u8; 8] = [0x01, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00];
+
+    #[test]
+    fn test_read_rejects_oversized_element() {
+        let codec_private = [[0x63, 0xA2].as_slice(), &HUGE_SIZE, b"data"].concat();
+        let entry = [
+            element(&[0xD7], &[1]),
+            element(&[0x83], &[0x11]),
+            codec_private,
+        ];
+        let tracks = element(
+            &[0x16, 0x54, 0xAE, 0x6B],
+            &element(&[0xAE], &entry.concat()),
+        );
+
+        let mut demuxer = Demuxer::new(io::Cursor::new(file(&[tracks])));
+        let error = demuxer.read().unwrap_err();
+
+        assert!(error.to_string().contains("runs past the end of the file"));
+    }
+
+    #[test]
+    fn test_read_rejects_oversized_block() {
+        let entry = [
+            element(&[0xD7], &[1]),
+            element(&[0x83], &[0x11]),
+            element(&[0x86], b"S_TEXT/UTF8"),
+        ];
+        let tracks = element(
+            &[0x16, 0x54, 0xAE, 0x6B],
+            &element(&[0xAE], &entry.concat()),
+        );
+        let block = [
+            [0xA3].as_slice(),
+            &HUGE_SIZE,
+            &[0x81, 0x00, 0x0A, 0x00],
+            b"Hello",
+        ]
+        .concat();
+        let cluster = element(&[0x1F, 0x43, 0xB6, 0x75], &block);
+
+        let mut demuxer = Demuxer::new(io::Cursor::new(file(&[tracks, cluster])));
+        let error = demuxer.read().unwrap_err();
+
+        assert!(error.to_string().contains("runs past the end of the file"));
+    }
+
+    /// A file with one SubRip track encoded as described, holding the block.
+    fn encoded_file(encoding: &[Vec<u8>], block: &[u8]) -> Vec<u8> {
+        let encodings = element(&[0x6D, 0x80], &element(&[0x62, 0x40], &encoding.concat()));
+        let entry = [
+            element(&[0xD7], &[1]),
+            element(&[0x83], &[0x11]),
+            element(&[0x86], b"S_TEXT/UTF8"),
+            encodings,
+        ];
+        let tracks = element(
+            &[0x16, 0x54, 0xAE, 0x6B],
+            &element(&[0xAE], &entry.concat()),
+        );
+        let block = element(
+            &[0xA3],
+            &[[0x81, 0x00, 0x0A, 0x80].as_slice(), block].concat(),
+        );
+        let cluster = element(&[0x1F, 0x43, 0xB6, 0x75], &block);
+        file(&[tracks, cluster])
+    }
+
+    #[test]
+    fn test_read_matroska_restores_stripped_headers() {
+        let compression = element(
+            &[0x50, 0x34],
+            &[element(&[0x42, 0x54], &[3]), element(&[0x42, 0x55], b"Hel")].concat(),
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("input.mks");
+        std::fs::write(&path, encoded_file(&[compression], b"lo")).unwrap();
+
+        let file = read_matroska(&path, false).unwrap();
+
+        let texts: Vec<_> = timings(&file.subtitles[0].track)
+            .into_iter()
+            .map(|(_, _, text)| text)
+            .collect();
+        assert_eq!(texts, ["Hello"]);
+    }
+
+    #[rstest]
+    #[case::zlib(vec![element(&[0x50, 0x34], &element(&[0x42, 0x54], &[0]))])]
+    #[case::default_zlib(vec![element(&[0x50, 0x32], &[1])])]
+    #[case::encrypted(vec![element(&[0x50, 0x33], &[1])])]
+    fn test_read_matroska_rejects_encoded_track(#[case] encoding: Vec<Vec<u8>>) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("input.mks");
+        std::fs::write(&path, encoded_file(&encoding, &[0x78, 0x9C, 0x01])).unwrap();
+
+        let Err(error) = read_matroska(&path, false) else {
+            panic!("read an encoded track");
+        };
+
+        assert!(format!("{error:#}").contains("failed decoding track 0"));
+    }
+}
@@ -0,0 +1,88 @@
+//! Measures subtitle text the way it shows on screen: without override tags
+//! or line breaks, in user-perceived characters, and with East Asian
+//! characters twice as wide as Latin ones.
+
+use std::sync::LazyLock;
+
+use regex::Regex;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+/// ASS override blocks such as `{\an8}` and SRT tags such as `<i>`
+static TAGS: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\{[^}]*\}|</?[a-zA-Z][^<>]*>").expect("valid regex"));
+
+/// How long subtitle text is on screen.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TextMetrics {
+    /// User-perceived characters, so that a letter with combining accents
+    /// or an emoji sequence counts once
+    pub graphemes: usize,
+    /// Columns the text takes up, summed over its lines
+    pub width: usize,
+    /// Columns the widest line takes up
+    pub widest_line: usize,
+    pub lines: usize,
+}
+
+/// The lines of the text as shown, without override tags. ASS `\N` and `\n`
+/// as well as newlines break lines, and `\h` is a space.
+pub fn visible_lines(text: &str) -> Vec<String> {
+    TAGS.replace_all(text, "")
+        .replace("\\h", " ")
+        .split("\\N")
+        .flat_map(|line| line.split("\\n"))
+        .flat_map(|line| line.lines())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// The text as shown, on one line.
+pub fn visible_text(text: &str) -> String {
+    visible_lines(text).join(" ")
+}
+
+/// Columns the text takes up on screen, without override tags or line
+/// breaks.
+pub fn display_width(text: &str) -> usize {
+    measure(text).width
+}
+
+pub fn measure(text: &str) -> TextMetrics {
+    let mut metrics = TextMetrics::default();
+    for line in visible_lines(text) {
+        let width = line.width();
+        metrics.graphemes += line.graphemes(true).count();
+        metrics.width += width;
+        metrics.widest_line = metrics.widest_line.max(width);
+        metrics.lines += 1;
+    }
+    metrics
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case("We have to find him.", 20, 20)]
+    #[case("我们必须找到他。", 8, 16)]
+    #[case(r"{\an8}{\fs20}我们必须找到他。", 8, 16)]
+    #[case(r"<i>We have</i>\Nto find him.", 19, 19)]
+    #[case("ｶﾀｶﾅ", 4, 4)]
+    #[case("e\u{301}", 1, 1)]
+    fn test_measure(#[case] text: &str, #[case] graphemes: usize, #[case] width: usize) {
+        let got = measure(text);
+
+        assert_eq!((got.graphemes, got.width), (graphemes, width));
+    }
+
+    #[test]
+    fn test_visible_lines() {
+        let got = visible_lines(r"{\i1}诅咒像蛆虫\N<b>Curses</b>\hwere");
+
+        assert_eq!(got, ["诅咒像蛆虫", "Curses were"]);
+    }
+}
@@ -0,0 +1,198 @@
+//! Names of sidecar subtitle files, following the conventions of the media
+//! servers that pick them up.
+
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use anyhow::bail;
+
+/// Naming conventions of the media servers sidecars are written for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum NamingPreset {
+    /// `episode.en.srt`, `episode.zh.srt`, `episode.zh-TW.srt`,
+    /// `episode.ja.srt` and `episode.ko.srt`
+    Default,
+    /// `episode.en.srt`, `episode.zh.srt` and `episode.zh-TW.srt`
+    Plex,
+    /// `episode.zh-Hans.srt` and `episode.zh-Hant.srt`, with a `.default`
+    /// flag
+    Jellyfin,
+    /// `episode.eng.srt`, `episode.chi.srt` and so on
+    Kodi,
+    /// `episode.eng.srt`, `episode.chi.srt` and so on, with a `.default`
+    /// flag
+    Emby,
+}
+
+impl NamingPreset {
+    fn template(self) -> &'static str {
+        match self {
+            Self::Default => "{stem}.{lang}.{ext}",
+            Self::Plex | Self::Kodi => "{stem}.{lang}.{ext}",
+            Self::Jellyfin | Self::Emby => "{stem}.{lang}{.default}.{ext}",
+        }
+    }
+
+    /// How the media server spells the languages sidecars are written in:
+    /// `en`, `zh` for simplified and `zh-TW` for traditional Chinese, `ja`
+    /// and `ko`.
+    fn language_tag(self, language_code: &str) -> &str {
+        match (self, language_code) {
+            (Self::Jellyfin, "zh") => "zh-Hans",
+            (Self::Jellyfin, "zh-TW") => "zh-Hant",
+            (Self::Kodi | Self::Emby, "en") => "eng",
+            (Self::Kodi | Self::Emby, "zh") => "chi",
+            (Self::Kodi | Self::Emby, "ja") => "jpn",
+            (Self::Kodi | Self::Emby, "ko") => "kor",
+            (_, language_code) => language_code,
+        }
+    }
+}
+
+/// Names sidecars from a template such as `{stem}.{lang}{.default}.{ext}`.
+/// `{stem}` is the media file name without its extension, `{lang}` the
+/// language as the preset spells it, and `{ext}` the subtitle format.
+/// `{.default}` becomes `.default` for the sidecar in the default language,
+/// and nothing otherwise. Sidecars are always full subtitles, so there are no
+/// forced or SDH flags.
+#[derive(Debug, Clone)]
+pub struct SidecarNaming {
+    preset: NamingPreset,
+    template: String,
+    /// Language code of the sidecar flagged as the default one
+    default_language: Option<String>,
+}
+
+const PLACEHOLDERS: [&str; 4] = ["stem", "lang", "ext", ".default"];
+
+impl Default for SidecarNaming {
+    fn default() -> Self {
+        Self {
+            preset: NamingPreset::Default,
+            template: NamingPreset::Default.template().to_owned(),
+            default_language: None,
+        }
+    }
+}
+
+impl SidecarNaming {
+    /// Names sidecars after the preset, or after `template` if given, with
+    /// the preset still deciding how languages are spelled.
+    pub fn new(
+        preset: NamingPreset,
+        template: Option<&str>,
+        default_language: Option<String>,
+    ) -> Result<Self> {
+        let template = template.unwrap_or(preset.template()).to_owned();
+        let placeholders = placeholders(&template)?;
+        if let Some(placeholder) = placeholders.iter().find(|p| !PLACEHOLDERS.contains(p)) {
+            bail!("unknown placeholder {{{placeholder}}} in sidecar template {template}");
+        }
+        // Without the language every sidecar would get the same name, and
+        // without the stem every media file in the directory would
+        for required in ["stem", "lang"] {
+            if !placeholders.contains(&required) {
+                bail!("sidecar template {template} lacks {{{required}}}");
+            }
+        }
+        Ok(Self {
+            preset,
+            template,
+            default_language,
+        })
+    }
+
+    /// Path of the sidecar next to the media file. Only the media file's
+    /// last extension is dropped, so stems with dots in them are kept whole.
+    /// The language is `en`, `zh` for simplified or `zh-TW` for traditional
+    /// Chinese, `ja` or `ko`.
+    pub fn path(&self, media_file: &Path, language_code: &str) -> PathBuf {
+        let stem = media_file.file_stem().unwrap_or_default().to_string_lossy();
+        let default = self.default_language.as_deref() == Some(language_code);
+
+        let mut name = String::new();
+        let mut rest = self.template.as_str();
+        while let Some(open) = rest.find('{') {
+            name.push_str(&rest[..open]);
+            // Templates are checked to be well formed on creation
+            let close = open + rest[open..].find('}').expect("unclosed placeholder");
+            name.push_str(match &rest[open + 1..close] {
+                "stem" => &stem,
+                "lang" => self.preset.language_tag(language_code),
+                "ext" => "srt",
+                ".default" if default => ".default",
+                _ => "",
+            });
+            rest = &rest[close + 1..];
+        }
+        name.push_str(rest);
+
+        media_file.with_file_name(name)
+    }
+}
+
+/// The placeholders in the template, without their braces.
+fn placeholders(template: &str) -> Result<Vec<&str>> {
+    let mut placeholders = Vec::new();
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        let Some(close) = rest[open..].find('}') else {
+            bail!("unclosed placeholder in sidecar template {template}");
+        };
+        placeholders.push(&rest[open + 1..open + close]);
+        rest = &rest[open + close + 1..];
+    }
+    Ok(placeholders)
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case(NamingPreset::Default, "zh-TW", "Show.S01E01.zh-TW.srt")]
+    #[case(NamingPreset::Plex, "en", "Show.S01E01.en.srt")]
+    #[case(NamingPreset::Jellyfin, "zh", "Show.S01E01.zh-Hans.default.srt")]
+    #[case(NamingPreset::Jellyfin, "zh-TW", "Show.S01E01.zh-Hant.srt")]
+    #[case(NamingPreset::Kodi, "zh", "Show.S01E01.chi.srt")]
+    #[case(NamingPreset::Emby, "en", "Show.S01E01.eng.srt")]
+    #[case(NamingPreset::Kodi, "ja", "Show.S01E01.jpn.srt")]
+    fn test_preset_path(
+        #[case] preset: NamingPreset,
+        #[case] language_code: &str,
+        #[case] should: &str,
+    ) {
+        let naming = SidecarNaming::new(preset, None, Some("zh".into())).unwrap();
+
+        let got = naming.path(Path::new("/tv/Show/Show.S01E01.mkv"), language_code);
+
+        assert_eq!(got, Path::new("/tv/Show").join(should));
+    }
+
+    #[test]
+    fn test_template_path() {
+        let naming = SidecarNaming::new(
+            NamingPreset::Default,
+            Some("{stem}.{lang}{.default}.{ext}"),
+            Some("en".into()),
+        )
+        .unwrap();
+
+        let got = naming.path(Path::new("/tv/Show.S01E01.1080p.mkv"), "en");
+
+        assert_eq!(got, Path::new("/tv/Show.S01E01.1080p.en.default.srt"));
+    }
+
+    #[rstest]
+    #[case("{stem}.{language}.{ext}")]
+    #[case("{stem}.{lang.{ext}")]
+    #[case("{stem}.srt")]
+    #[case("{lang}.{ext}")]
+    #[case("{stem}.{lang}{.forced}.{ext}")]
+    fn test_invalid_template(#[case] template: &str) {
+        assert!(SidecarNaming::new(NamingPreset::Default, Some(template), None).is_err());
+    }
+}
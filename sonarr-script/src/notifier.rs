@@ -0,0 +1,551 @@
+//! Tells media servers and *arr apps to rescan once subtitles are written.
+
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::bail;
+use serde_json::Value;
+use serde_json::json;
+use tracing::info;
+use tracing::warn;
+
+/// Media servers and *arr apps to notify after sidecar subtitles are written.
+/// Each target is only enabled when its URL is set.
+#[derive(Debug, Clone, clap::Args)]
+pub struct NotifierArgs {
+    /// Sonarr base URL, used to send `RescanSeries`
+    #[clap(long, env = "SONARR_URL")]
+    pub sonarr_url: Option<String>,
+
+    /// Sonarr API key
+    #[clap(long, env = "SONARR_API_KEY", hide_env_values = true)]
+    pub sonarr_api_key: Option<String>,
+
+    /// Radarr base URL, used to send `RescanMovie`
+    #[clap(long, env = "RADARR_URL")]
+    pub radarr_url: Option<String>,
+
+    /// Radarr API key
+    #[clap(long, env = "RADARR_API_KEY", hide_env_values = true)]
+    pub radarr_api_key: Option<String>,
+
+    /// Jellyfin base URL
+    #[clap(long, env = "JELLYFIN_URL")]
+    pub jellyfin_url: Option<String>,
+
+    /// Jellyfin API key
+    #[clap(long, env = "JELLYFIN_API_KEY", hide_env_values = true)]
+    pub jellyfin_api_key: Option<String>,
+
+    /// Emby base URL
+    #[clap(long, env = "EMBY_URL")]
+    pub emby_url: Option<String>,
+
+    /// Emby API key
+    #[clap(long, env = "EMBY_API_KEY", hide_env_values = true)]
+    pub emby_api_key: Option<String>,
+
+    /// Plex base URL
+    #[clap(long, env = "PLEX_URL")]
+    pub plex_url: Option<String>,
+
+    /// Plex token
+    #[clap(long, env = "PLEX_TOKEN", hide_env_values = true)]
+    pub plex_token: Option<String>,
+
+    /// Plex library section to refresh. Looked up by path when unset
+    #[clap(long, env = "PLEX_SECTION_ID")]
+    pub plex_section_id: Option<String>,
+
+    /// Number of attempts per target before giving up. Only unreachable
+    /// targets and server errors are retried
+    #[clap(long, env = "NOTIFY_ATTEMPTS", default_value_t = 3)]
+    pub notify_attempts: u32,
+
+    /// Delay in milliseconds before the first retry, doubled on each retry
+    #[clap(long, env = "NOTIFY_BACKOFF_MS", default_value_t = 1000)]
+    pub notify_backoff_ms: u64,
+}
+
+/// Which media file changed and which *arr item it belongs to.
+#[derive(Debug, Clone)]
+pub struct RescanRequest<'a> {
+    pub media_file: &'a Path,
+    pub series_id: Option<u64>,
+    pub movie_id: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+enum Target {
+    Sonarr {
+        url: String,
+        api_key: String,
+    },
+    Radarr {
+        url: String,
+        api_key: String,
+    },
+    Jellyfin {
+        url: String,
+        api_key: String,
+    },
+    Emby {
+        url: String,
+        api_key: String,
+    },
+    Plex {
+        url: String,
+        token: String,
+        section_id: Option<String>,
+    },
+}
+
+impl Target {
+    fn name(&self) -> &'static str {
+        match self {
+            Target::Sonarr { .. } => "sonarr",
+            Target::Radarr { .. } => "radarr",
+            Target::Jellyfin { .. } => "jellyfin",
+            Target::Emby { .. } => "emby",
+            Target::Plex { .. } => "plex",
+        }
+    }
+}
+
+pub struct Notifier {
+    targets: Vec<Target>,
+    attempts: u32,
+    backoff: Duration,
+    agent: ureq::Agent,
+}
+
+impl Notifier {
+    pub fn from_args(args: &NotifierArgs) -> Result<Self> {
+        let mut targets = Vec::new();
+
+        let with_key = |url: &Option<String>, key: &Option<String>, name: &str| {
+            let Some(url) = url else {
+                return Ok(None);
+            };
+            let key = key
+                .clone()
+                .with_context(|| format!("{name} URL is set but its API key is not"))?;
+            anyhow::Ok(Some((url.trim_end_matches('/').to_owned(), key)))
+        };
+
+        if let Some((url, api_key)) = with_key(&args.sonarr_url, &args.sonarr_api_key, "Sonarr")? {
+            targets.push(Target::Sonarr { url, api_key });
+        }
+        if let Some((url, api_key)) = with_key(&args.radarr_url, &args.radarr_api_key, "Radarr")? {
+            targets.push(Target::Radarr { url, api_key });
+        }
+        if let Some((url, api_key)) =
+            with_key(&args.jellyfin_url, &args.jellyfin_api_key, "Jellyfin")?
+        {
+            targets.push(Target::Jellyfin { url, api_key });
+        }
+        if let Some((url, api_key)) = with_key(&args.emby_url, &args.emby_api_key, "Emby")? {
+            targets.push(Target::Emby { url, api_key });
+        }
+        if let Some((url, token)) = with_key(&args.plex_url, &args.plex_token, "Plex")? {
+            targets.push(Target::Plex {
+                url,
+                token,
+                section_id: args.plex_section_id.clone(),
+            });
+        }
+
+        let agent = ureq::Agent::config_builder()
+            .timeout_global(Some(Duration::from_secs(30)))
+            .build()
+            .into();
+
+        Ok(Self {
+            targets,
+            attempts: args.notify_attempts.max(1),
+            backoff: Duration::from_millis(args.notify_backoff_ms),
+            agent,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.targets.is_empty()
+    }
+
+    /// Notifies every configured target, retrying each with exponential
+    /// backoff. A failing target does not prevent the others from being
+    /// notified.
+    pub fn notify(&self, request: &RescanRequest) -> Result<()> {
+        let mut failed = Vec::new();
+        for target in &self.targets {
+            if let Err(error) = self.notify_with_retries(target, request) {
+                warn!(target = target.name(), ?error, "giving up notifying target");
+                failed.push(target.name());
+            }
+        }
+        if !failed.is_empty() {
+            bail!("failed notifying targets: {}", failed.join(", "));
+        }
+        Ok(())
+    }
+
+    fn notify_with_retries(&self, target: &Target, request: &RescanRequest) -> Result<()> {
+        let mut delay = self.backoff;
+        let mut attempt = 1;
+        loop {
+            match self.notify_target(target, request) {
+                Ok(()) => {
+                    info!(target = target.name(), attempt, "notified target");
+                    return Ok(());
+                }
+                Err(error) if attempt < self.attempts && is_transient(&error) => {
+                    warn!(
+                        target = target.name(),
+                        attempt,
+                        ?error,
+                        "failed notifying target, retrying"
+                    );
+                    std::thread::sleep(delay);
+                    delay *= 2;
+                    attempt += 1;
+                }
+                Err(error) => return Err(error),
+            }
+        }
+    }
+
+    fn notify_target(&self, target: &Target, request: &RescanRequest) -> Result<()> {
+        let media_file = request.media_file.to_string_lossy();
+        match target {
+            Target::Sonarr { url, api_key } => {
+                let Some(series_id) = request.series_id else {
+                    warn!("no series id known, skipping sonarr rescan");
+                    return Ok(());
+                };
+                self.agent
+                    .post(format!("{url}/api/v3/command"))
+                    .header("X-Api-Key", api_key)
+                    .send_json(json!({ "name": "RescanSeries", "seriesId": series_id }))?;
+            }
+            Target::Radarr { url, api_key } => {
+                let Some(movie_id) = request.movie_id else {
+                    warn!("no movie id known, skipping radarr rescan");
+                    return Ok(());
+                };
+                self.agent
+                    .post(format!("{url}/api/v3/command"))
+                    .header("X-Api-Key", api_key)
+                    .send_json(json!({ "name": "RescanMovie", "movieId": movie_id }))?;
+            }
+            Target::Jellyfin { url, api_key } => {
+                self.agent
+                    .post(format!("{url}/Library/Media/Updated"))
+                    .header(
+                        "Authorization",
+                        format!(r#"MediaBrowser Token="{api_key}""#),
+                    )
+                    .send_json(media_updated_body(&media_file))?;
+            }
+            Target::Emby { url, api_key } => {
+                self.agent
+                    .post(format!("{url}/Library/Media/Updated"))
+                    .header("X-Emby-Token", api_key)
+                    .send_json(media_updated_body(&media_file))?;
+            }
+            Target::Plex {
+                url,
+                token,
+                section_id,
+            } => {
+                let directory = request
+                    .media_file
+                    .parent()
+                    .context("unable to get media file directory")?;
+                let section_id = match section_id {
+                    Some(id) => id.clone(),
+                    None => self.find_plex_section(url, token, directory)?,
+                };
+                self.agent
+                    .get(format!("{url}/library/sections/{section_id}/refresh"))
+                    .query("path", directory.to_string_lossy())
+                    .header("X-Plex-Token", token)
+                    .call()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Finds the Plex library section with the longest location containing
+    /// the given directory.
+    fn find_plex_section(&self, url: &str, token: &str, directory: &Path) -> Result<String> {
+        let sections: Value = self
+            .agent
+            .get(format!("{url}/library/sections"))
+            .header("X-Plex-Token", token)
+            .header("Accept", "application/json")
+            .call()?
+            .body_mut()
+            .read_json()?;
+
+        let empty = Vec::new();
+        let directories = sections["MediaContainer"]["Directory"]
+            .as_array()
+            .unwrap_or(&empty);
+        directories
+            .iter()
+            .flat_map(|section| {
+                let locations = section["Location"].as_array().unwrap_or(&empty);
+                locations.iter().filter_map(move |location| {
+                    let path = location["path"].as_str()?;
+                    let key = section["key"].as_str()?;
+                    directory
+                        .starts_with(path)
+                        .then(|| (path.len(), key.to_owned()))
+                })
+            })
+            .max_by_key(|(len, _)| *len)
+            .map(|(_, key)| key)
+            .with_context(|| {
+                format!(
+                    "no plex library section contains {}",
+                    directory.to_string_lossy()
+                )
+            })
+    }
+}
+
+/// Whether a failed request could succeed if sent again: the server could not
+/// be reached or failed itself, rather than turning the request down, as it
+/// does with a wrong API key or URL.
+fn is_transient(error: &anyhow::Error) -> bool {
+    match error.downcast_ref::<ureq::Error>() {
+        Some(ureq::Error::StatusCode(status)) => *status >= 500,
+        Some(
+            ureq::Error::Io(_)
+            | ureq::Error::Timeout(_)
+            | ureq::Error::HostNotFound
+            | ureq::Error::ConnectionFailed
+            | ureq::Error::BodyStalled,
+        ) => true,
+        _ => false,
+    }
+}
+
+fn media_updated_body(path: &str) -> Value {
+    json!({ "Updates": [{ "Path": path, "UpdateType": "Modified" }] })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::mpsc;
+    use std::thread;
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct Recorded {
+        method: String,
+        url: String,
+        headers: Vec<(String, String)>,
+        body: String,
+    }
+
+    impl Recorded {
+        fn header(&self, name: &str) -> Option<&str> {
+            self.headers
+                .iter()
+                .find(|(k, _)| k.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.as_str())
+        }
+    }
+
+    /// Starts a server on a random local port that answers with the given
+    /// responses in order and records every request it receives.
+    fn mock_server(responses: Vec<(u16, &'static str)>) -> (String, mpsc::Receiver<Recorded>) {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let port = server.server_addr().to_ip().unwrap().port();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for (status, body) in responses {
+                let Ok(mut request) = server.recv() else {
+                    return;
+                };
+                let mut content = String::new();
+                request.as_reader().read_to_string(&mut content).unwrap();
+                let recorded = Recorded {
+                    method: request.method().to_string(),
+                    url: request.url().to_owned(),
+                    headers: request
+                        .headers()
+                        .iter()
+                        .map(|h| (h.field.to_string(), h.value.to_string()))
+                        .collect(),
+                    body: content,
+                };
+                tx.send(recorded).unwrap();
+                let response = tiny_http::Response::from_string(body).with_status_code(status);
+                request.respond(response).unwrap();
+            }
+        });
+        (format!("http://127.0.0.1:{port}"), rx)
+    }
+
+    fn args() -> NotifierArgs {
+        NotifierArgs {
+            sonarr_url: None,
+            sonarr_api_key: None,
+            radarr_url: None,
+            radarr_api_key: None,
+            jellyfin_url: None,
+            jellyfin_api_key: None,
+            emby_url: None,
+            emby_api_key: None,
+            plex_url: None,
+            plex_token: None,
+            plex_section_id: None,
+            notify_attempts: 3,
+            notify_backoff_ms: 1,
+        }
+    }
+
+    fn request(media_file: &Path) -> RescanRequest<'_> {
+        RescanRequest {
+            media_file,
+            series_id: Some(42),
+            movie_id: Some(7),
+        }
+    }
+
+    #[test]
+    fn test_sonarr_rescan_series() {
+        let (url, rx) = mock_server(vec![(201, "{}")]);
+        let notifier = Notifier::from_args(&NotifierArgs {
+            sonarr_url: Some(url),
+            sonarr_api_key: Some("secret".into()),
+            ..args()
+        })
+        .unwrap();
+
+        let media_file = PathBuf::from("/tv/Show/Season 1/episode.mkv");
+        notifier.notify(&request(&media_file)).unwrap();
+
+        let got = rx.recv().unwrap();
+        assert_eq!(got.method, "POST");
+        assert_eq!(got.url, "/api/v3/command");
+        assert_eq!(got.header("X-Api-Key"), Some("secret"));
+        let body: Value = serde_json::from_str(&got.body).unwrap();
+        assert_eq!(body, json!({ "name": "RescanSeries", "seriesId": 42 }));
+    }
+
+    #[test]
+    fn test_radarr_rescan_movie() {
+        let (url, rx) = mock_server(vec![(201, "{}")]);
+        let notifier = Notifier::from_args(&NotifierArgs {
+            radarr_url: Some(url),
+            radarr_api_key: Some("secret".into()),
+            ..args()
+        })
+        .unwrap();
+
+        let media_file = PathBuf::from("/movies/Movie (2020)/movie.mkv");
+        notifier.notify(&request(&media_file)).unwrap();
+
+        let body: Value = serde_json::from_str(&rx.recv().unwrap().body).unwrap();
+        assert_eq!(body, json!({ "name": "RescanMovie", "movieId": 7 }));
+    }
+
+    #[test]
+    fn test_jellyfin_retries_until_success() {
+        let (url, rx) = mock_server(vec![(500, ""), (503, ""), (204, "")]);
+        let notifier = Notifier::from_args(&NotifierArgs {
+            jellyfin_url: Some(format!("{url}/")),
+            jellyfin_api_key: Some("secret".into()),
+            ..args()
+        })
+        .unwrap();
+
+        let media_file = PathBuf::from("/tv/Show/Season 1/episode.mkv");
+        notifier.notify(&request(&media_file)).unwrap();
+
+        let requests: Vec<Recorded> = rx.iter().collect();
+        assert_eq!(requests.len(), 3);
+        let last = &requests[2];
+        assert_eq!(last.url, "/Library/Media/Updated");
+        assert_eq!(
+            last.header("Authorization"),
+            Some(r#"MediaBrowser Token="secret""#)
+        );
+        let body: Value = serde_json::from_str(&last.body).unwrap();
+        assert_eq!(body["Updates"][0]["Path"], "/tv/Show/Season 1/episode.mkv");
+    }
+
+    #[test]
+    fn test_emby_gives_up_after_attempts() {
+        let (url, rx) = mock_server(vec![(500, ""), (500, ""), (500, "")]);
+        let notifier = Notifier::from_args(&NotifierArgs {
+            emby_url: Some(url),
+            emby_api_key: Some("secret".into()),
+            ..args()
+        })
+        .unwrap();
+
+        let media_file = PathBuf::from("/tv/Show/Season 1/episode.mkv");
+        assert!(notifier.notify(&request(&media_file)).is_err());
+        assert_eq!(rx.iter().count(), 3);
+    }
+
+    #[test]
+    fn test_client_errors_are_not_retried() {
+        // Retrying would get the success after the 401
+        let (url, rx) = mock_server(vec![(401, ""), (204, "")]);
+        let notifier = Notifier::from_args(&NotifierArgs {
+            emby_url: Some(url),
+            emby_api_key: Some("wrong".into()),
+            ..args()
+        })
+        .unwrap();
+
+        let media_file = PathBuf::from("/tv/Show/Season 1/episode.mkv");
+        assert!(notifier.notify(&request(&media_file)).is_err());
+        assert_eq!(rx.try_iter().count(), 1);
+    }
+
+    #[test]
+    fn test_plex_discovers_section_by_path() {
+        let sections = r#"{"MediaContainer":{"Directory":[
+            {"key":"1","Location":[{"path":"/movies"}]},
+            {"key":"2","Location":[{"path":"/tv"},{"path":"/anime"}]}
+        ]}}"#;
+        let (url, rx) = mock_server(vec![(200, sections), (200, "")]);
+        let notifier = Notifier::from_args(&NotifierArgs {
+            plex_url: Some(url),
+            plex_token: Some("secret".into()),
+            ..args()
+        })
+        .unwrap();
+
+        let media_file = PathBuf::from("/anime/Show/Season 1/episode.mkv");
+        notifier.notify(&request(&media_file)).unwrap();
+
+        let list = rx.recv().unwrap();
+        assert_eq!(list.url, "/library/sections");
+        let refresh = rx.recv().unwrap();
+        assert_eq!(
+            refresh.url,
+            "/library/sections/2/refresh?path=%2Fanime%2FShow%2FSeason%201"
+        );
+        assert_eq!(refresh.header("X-Plex-Token"), Some("secret"));
+    }
+
+    #[test]
+    fn test_missing_api_key_is_an_error() {
+        let result = Notifier::from_args(&NotifierArgs {
+            sonarr_url: Some("http://localhost:8989".into()),
+            ..args()
+        });
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,380 @@
+//! Turns bitmap subtitles into text with Tesseract.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::bail;
+use aspasia::AssSubtitle;
+use aspasia::Moment;
+use aspasia::SubRipSubtitle;
+use tracing::info;
+use tracing::warn;
+
+use crate::bitmap;
+use crate::bitmap::BitmapEvent;
+use crate::matroska::MatroskaBitmapSubtitle;
+use crate::process::CommandRunner;
+use crate::process::ProcessCommand;
+use crate::sub::SubtitleTrack;
+use crate::subtitle::OcrOptions;
+
+/// Text recognized in one image.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OcrText {
+    pub text: String,
+    /// Mean confidence of the words, out of 100
+    pub confidence: f32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OcrReport {
+    pub events: usize,
+    /// Events dropped for being recognized with too low a confidence
+    pub dropped: usize,
+    /// Mean confidence over the events kept
+    pub mean_confidence: f32,
+}
+
+/// Tesseract languages to recognize a subtitle stream in. Chinese streams are
+/// read as both scripts, since which one they are is only known afterwards.
+pub fn tesseract_languages(language_code: &str) -> &'static str {
+    match language_code {
+        "zh" => "chi_sim+chi_tra",
+        "ja" => "jpn",
+        "ko" => "kor",
+        _ => "eng",
+    }
+}
+
+/// Decodes the images of a bitmap subtitle track and recognizes their text.
+pub(crate) fn recognize_subtitle(
+    runner: &dyn CommandRunner,
+    subtitle: &MatroskaBitmapSubtitle,
+    language_code: &str,
+    options: &OcrOptions,
+    work_dir: &Path,
+) -> Result<(SubtitleTrack, OcrReport)> {
+    let events = match subtitle.codec() {
+        "hdmv_pgs_subtitle" => bitmap::decode_pgs(&subtitle.blocks)?,
+        "dvd_subtitle" => bitmap::decode_vobsub(&subtitle.codec_private, &subtitle.blocks)?,
+        codec => bail!("unsupported bitmap subtitle codec {codec}"),
+    };
+    recognize(runner, &events, language_code, options, work_dir)
+}
+
+/// Recognizes the text of every event in a single Tesseract run, writing the
+/// images to `work_dir`, and builds a subtitle track out of it.
+pub(crate) fn recognize(
+    runner: &dyn CommandRunner,
+    events: &[BitmapEvent],
+    language_code: &str,
+    options: &OcrOptions,
+    work_dir: &Path,
+) -> Result<(SubtitleTrack, OcrReport)> {
+    if events.is_empty() {
+        let report = OcrReport {
+            events: 0,
+            dropped: 0,
+            mean_confidence: 0.0,
+        };
+        return Ok((
+            AssSubtitle::from(SubRipSubtitle::from_str("")?).into(),
+            report,
+        ));
+    }
+    let texts = run_tesseract(runner, events, language_code, options, work_dir);
+    // The images are only for Tesseract, whether or not it got through them
+    let _ = std::fs::remove_dir_all(work_dir);
+    let texts = texts?;
+
+    let mut srt = String::new();
+    let mut kept = 0;
+    let mut dropped = 0;
+    let mut confidence_sum = 0.0;
+    for (i, event) in events.iter().enumerate() {
+        // Pages are numbered from 1
+        let Some(text) = texts.get(&(i + 1)) else {
+            continue;
+        };
+        if text.confidence < options.min_confidence {
+            warn!(
+                start = %Moment::from(event.start).as_srt_timestamp(),
+                confidence = text.confidence,
+                text = %text.text,
+                "dropping event recognized with low confidence"
+            );
+            dropped += 1;
+            continue;
+        }
+        kept += 1;
+        confidence_sum += text.confidence;
+        srt.push_str(&format!(
+            "{kept}\n{} --> {}\n{}\n\n",
+            Moment::from(event.start).as_srt_timestamp(),
+            Moment::from(event.end).as_srt_timestamp(),
+            text.text
+        ));
+    }
+
+    let srt = SubRipSubtitle::from_str(&srt).context("invalid recognized subtitles")?;
+    let report = OcrReport {
+        events: kept,
+        dropped,
+        mean_confidence: if kept > 0 {
+            confidence_sum / kept as f32
+        } else {
+            0.0
+        },
+    };
+    Ok((AssSubtitle::from(srt).into(), report))
+}
+
+/// Writes the images of the events to `work_dir` and recognizes them in a
+/// single Tesseract run.
+fn run_tesseract(
+    runner: &dyn CommandRunner,
+    events: &[BitmapEvent],
+    language_code: &str,
+    options: &OcrOptions,
+    work_dir: &Path,
+) -> Result<BTreeMap<usize, OcrText>> {
+    std::fs::create_dir_all(work_dir)?;
+    let mut list = String::new();
+    for (i, event) in events.iter().enumerate() {
+        let image = work_dir.join(format!("{i:05}.pgm"));
+        std::fs::write(&image, event.bitmap.to_pgm())?;
+        list.push_str(&image.to_string_lossy());
+        list.push('\n');
+    }
+    let list_file = work_dir.join("images.txt");
+    std::fs::write(&list_file, list)?;
+
+    let mut command = ProcessCommand::new("tesseract", options.timeout)
+        .arg(&list_file)
+        .arg("stdout")
+        .args(["-l", tesseract_languages(language_code)])
+        // A single block of text, which is what subtitles are
+        .args(["--psm", "6"]);
+    if let Some(tessdata_dir) = &options.tessdata_dir {
+        command = command.arg("--tessdata-dir").arg(tessdata_dir);
+    }
+    let started = std::time::Instant::now();
+    let output = runner
+        .run(&command.arg("tsv"), &mut |_| {})
+        .context("failed running tesseract")?;
+    info!(elapsed = ?started.elapsed(), images = events.len(), "recognized subtitle images");
+    Ok(parse_tsv(&output.stdout))
+}
+
+/// Words and their confidence by block, paragraph and line number
+type Lines = BTreeMap<(u32, u32, u32), Vec<(String, f32)>>;
+
+/// Collects the words of Tesseract's TSV output into lines of text per page,
+/// with the mean confidence of the words. Pages without words are left out.
+pub fn parse_tsv(tsv: &str) -> BTreeMap<usize, OcrText> {
+    let mut pages: BTreeMap<usize, Lines> = BTreeMap::new();
+    for line in tsv.lines().skip(1) {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let [
+            level,
+            page,
+            block,
+            paragraph,
+            line,
+            _,
+            _,
+            _,
+            _,
+            _,
+            confidence,
+            text,
+        ] = fields[..]
+        else {
+            continue;
+        };
+        let text = text.trim();
+        let confidence: f32 = confidence.parse().unwrap_or(-1.0);
+        if level != "5" || text.is_empty() || confidence < 0.0 {
+            continue;
+        }
+        let (Ok(page), Ok(block), Ok(paragraph), Ok(line)) =
+            (page.parse(), block.parse(), paragraph.parse(), line.parse())
+        else {
+            continue;
+        };
+        pages
+            .entry(page)
+            .or_default()
+            .entry((block, paragraph, line))
+            .or_default()
+            .push((text.to_owned(), confidence));
+    }
+
+    pages
+        .into_iter()
+        .map(|(page, lines)| {
+            let words: Vec<f32> = lines.values().flatten().map(|(_, c)| *c).collect();
+            let confidence = words.iter().sum::<f32>() / words.len() as f32;
+            let text = lines
+                .values()
+                .map(|words| join_words(words.iter().map(|(word, _)| word.as_str())))
+                .collect::<Vec<_>>()
+                .join("\n");
+            (page, OcrText { text, confidence })
+        })
+        .collect()
+}
+
+/// Joins words with spaces, except between CJK characters, which Tesseract
+/// splits into words of their own but are written without spaces.
+fn join_words<'a>(words: impl Iterator<Item = &'a str>) -> String {
+    let mut line = String::new();
+    for word in words {
+        let adjacent = line.chars().last().zip(word.chars().next());
+        if adjacent.is_some_and(|(a, b)| !(is_cjk(a) && is_cjk(b))) {
+            line.push(' ');
+        }
+        line.push_str(word);
+    }
+    line
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3000}'..='\u{303F}' // Punctuation
+        | '\u{3040}'..='\u{30FF}' // Kana
+        | '\u{3400}'..='\u{4DBF}' | '\u{4E00}'..='\u{9FFF}' // Ideographs
+        | '\u{FF00}'..='\u{FFEF}' // Full width forms
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::bitmap::Bitmap;
+    use crate::process::FakeRunner;
+    use crate::process::ProcessError;
+    use crate::process::ProcessOutput;
+
+    const HEADER: &str = "level\tpage_num\tblock_num\tpar_num\tline_num\tword_num\tleft\ttop\twidth\theight\tconf\ttext";
+
+    fn word(page: u32, line: u32, confidence: f32, text: &str) -> String {
+        format!("5\t{page}\t1\t1\t{line}\t1\t0\t0\t10\t10\t{confidence}\t{text}")
+    }
+
+    #[test]
+    fn test_parse_tsv() {
+        let tsv = [
+            HEADER.to_owned(),
+            "1\t1\t0\t0\t0\t0\t0\t0\t100\t100\t-1\t".to_owned(),
+            word(1, 1, 90.0, "Hello"),
+            word(1, 1, 80.0, "there"),
+            word(1, 2, 70.0, "friend"),
+            word(3, 1, 96.0, "你"),
+            word(3, 1, 94.0, "好"),
+            word(3, 1, 92.0, "Jo"),
+        ]
+        .join("\n");
+
+        let texts = parse_tsv(&tsv);
+
+        assert_eq!(
+            texts,
+            BTreeMap::from([
+                (
+                    1,
+                    OcrText {
+                        text: "Hello there\nfriend".into(),
+                        confidence: 80.0
+                    }
+                ),
+                (
+                    3,
+                    OcrText {
+                        text: "你好 Jo".into(),
+                        confidence: 94.0
+                    }
+                ),
+            ])
+        );
+    }
+
+    fn event(start: i64) -> BitmapEvent {
+        BitmapEvent {
+            start,
+            end: start + 1000,
+            bitmap: Bitmap {
+                width: 1,
+                height: 1,
+                pixels: vec![(255, 255)],
+            },
+        }
+    }
+
+    fn options() -> OcrOptions {
+        OcrOptions {
+            tessdata_dir: None,
+            min_confidence: 60.0,
+            timeout: Duration::from_secs(1),
+        }
+    }
+
+    #[test]
+    fn test_recognize() {
+        let dir = tempfile::tempdir().unwrap();
+        let work_dir = dir.path().join("ocr");
+        let tsv = [
+            HEADER.to_owned(),
+            word(1, 1, 91.0, "Hello"),
+            word(2, 1, 20.0, "HeIIo"),
+        ]
+        .join("\n");
+        let runner = FakeRunner::new([Ok(ProcessOutput {
+            stdout: tsv,
+            stderr: String::new(),
+        })]);
+
+        let (track, report) = recognize(
+            &runner,
+            &[event(1000), event(3000)],
+            "en",
+            &options(),
+            &work_dir,
+        )
+        .unwrap();
+
+        let texts: Vec<_> = track.events().iter().map(|e| e.text.as_str()).collect();
+        assert_eq!(texts, ["Hello"]);
+        assert_eq!(
+            report,
+            OcrReport {
+                events: 1,
+                dropped: 1,
+                mean_confidence: 91.0
+            }
+        );
+        let command = &runner.commands.lock().unwrap()[0];
+        assert_eq!(command.program, "tesseract");
+        assert!(command.args.iter().any(|a| a == "eng"));
+        assert!(!work_dir.exists());
+    }
+
+    #[test]
+    fn test_recognize_cleans_up_after_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let work_dir = dir.path().join("ocr");
+        let runner = FakeRunner::new([Err(ProcessError::NotFound {
+            program: "tesseract".to_owned(),
+        })]);
+
+        let result = recognize(&runner, &[event(1000)], "en", &options(), &work_dir);
+
+        assert!(result.is_err());
+        assert!(!work_dir.exists());
+    }
+}
@@ -0,0 +1,165 @@
+//! Keeps sidecars the user put in place from being overwritten by accident.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use anyhow::Result;
+use tracing::info;
+use tracing::warn;
+
+use crate::atomic;
+use crate::hash::fnv1a;
+
+/// What to do when a sidecar is already there.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OverwritePolicy {
+    /// Overwrite it
+    Always,
+    /// Leave it alone
+    Never,
+    /// Overwrite it only if an earlier run wrote it and it has not been
+    /// changed since
+    IfOurs,
+}
+
+/// Writes sidecars according to the overwrite policy. The hashes of the
+/// sidecars written are recorded, so that later runs can tell them apart
+/// from sidecars that came from elsewhere.
+pub struct SidecarWriter {
+    policy: OverwritePolicy,
+    /// Whether sidecars that did not come from us are copied to `.bak` before
+    /// they are overwritten
+    backup: bool,
+    record_path: PathBuf,
+    /// Hashes of the sidecars written, by file name
+    record: BTreeMap<String, String>,
+}
+
+impl SidecarWriter {
+    /// Opens the record of sidecars written at `record_path`, which need not
+    /// exist yet.
+    pub fn open(policy: OverwritePolicy, backup: bool, record_path: PathBuf) -> Result<Self> {
+        let record = match std::fs::read_to_string(&record_path) {
+            Ok(content) => serde_json::from_str(&content).with_context(|| {
+                format!("failed parsing sidecar record {}", record_path.display())
+            })?,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(error) => return Err(error).context("failed reading sidecar record"),
+        };
+        Ok(Self {
+            policy,
+            backup,
+            record_path,
+            record,
+        })
+    }
+
+    /// Copies `source` to the sidecar at `path`, returning whether it was
+    /// written.
+    pub fn write(&mut self, source: &Path, path: &Path) -> Result<bool> {
+        let contents = std::fs::read(source)?;
+        let name = path
+            .file_name()
+            .context("sidecar has no file name")?
+            .to_string_lossy()
+            .into_owned();
+
+        match std::fs::read(path) {
+            Ok(existing) => {
+                let ours = self.record.get(&name) == Some(&fnv1a(&existing));
+                match self.policy {
+                    OverwritePolicy::Never => {
+                        info!(sidecar = %path.display(), "sidecar exists, leaving it alone");
+                        return Ok(false);
+                    }
+                    OverwritePolicy::IfOurs if !ours => {
+                        warn!(sidecar = %path.display(), "sidecar exists and was not written by us, leaving it alone");
+                        return Ok(false);
+                    }
+                    _ => {}
+                }
+                if !ours && self.backup {
+                    let backup = path.with_file_name(format!("{name}.bak"));
+                    // The first backup holds what the user had, keep it
+                    if !backup.exists() {
+                        info!(backup = %backup.display(), "backing up sidecar");
+                        atomic::write(&backup, &existing)?;
+                    }
+                }
+            }
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+            Err(error) => return Err(error).context("failed reading existing sidecar"),
+        }
+
+        atomic::write(path, &contents)?;
+        self.record.insert(name, fnv1a(&contents));
+        Ok(true)
+    }
+
+    /// Saves the record of sidecars written.
+    pub fn save(&self) -> Result<()> {
+        if let Some(dir) = self.record_path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        atomic::write(&self.record_path, serde_json::to_vec_pretty(&self.record)?)
+            .context("failed writing sidecar record")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case(OverwritePolicy::Always, true, "new", true)]
+    #[case(OverwritePolicy::Never, false, "theirs", false)]
+    #[case(OverwritePolicy::IfOurs, false, "theirs", false)]
+    fn test_write_over_user_sidecar(
+        #[case] policy: OverwritePolicy,
+        #[case] should_write: bool,
+        #[case] should_contain: &str,
+        #[case] should_back_up: bool,
+    ) {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("merged.srt");
+        std::fs::write(&source, "new").unwrap();
+        let sidecar = dir.path().join("episode.zh.srt");
+        std::fs::write(&sidecar, "theirs").unwrap();
+        let mut writer = SidecarWriter::open(policy, true, dir.path().join("record.json")).unwrap();
+
+        let written = writer.write(&source, &sidecar).unwrap();
+
+        assert_eq!(written, should_write);
+        assert_eq!(std::fs::read_to_string(&sidecar).unwrap(), should_contain);
+        let backup = dir.path().join("episode.zh.srt.bak");
+        assert_eq!(backup.exists(), should_back_up);
+        if should_back_up {
+            assert_eq!(std::fs::read_to_string(&backup).unwrap(), "theirs");
+        }
+    }
+
+    #[test]
+    fn test_write_over_own_sidecar() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("merged.srt");
+        let sidecar = dir.path().join("episode.zh.srt");
+        let record = dir.path().join("record.json");
+        std::fs::write(&source, "first").unwrap();
+        let mut writer =
+            SidecarWriter::open(OverwritePolicy::IfOurs, true, record.clone()).unwrap();
+        assert!(writer.write(&source, &sidecar).unwrap());
+        writer.save().unwrap();
+
+        std::fs::write(&source, "second").unwrap();
+        let mut writer = SidecarWriter::open(OverwritePolicy::IfOurs, true, record).unwrap();
+        let written = writer.write(&source, &sidecar).unwrap();
+
+        assert!(written);
+        assert_eq!(std::fs::read_to_string(&sidecar).unwrap(), "second");
+        assert!(!dir.path().join("episode.zh.srt.bak").exists());
+    }
+}
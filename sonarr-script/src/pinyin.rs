@@ -0,0 +1,532 @@
+//! Annotates Chinese text with its reading in pinyin or zhuyin, from a
+//! CC-CEDICT dictionary so that it works offline.
+//!
+//! Characters with more than one reading are read as part of the longest
+//! dictionary word they start, so that 行 is `háng` in 银行 but `xíng` in
+//! 行走. On their own, they are read the way they are most often read in
+//! words.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::error::Error;
+use crate::error::Result;
+use crate::script::is_han;
+
+/// How readings are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Romanization {
+    /// Pinyin with tone marks, such as `wǒmen`
+    ToneMarks,
+    /// Pinyin with tone numbers, such as `wo3men5`
+    ToneNumbers,
+    /// Zhuyin (bopomofo), such as `ㄨㄛˇ ˙ㄇㄣ`
+    Zhuyin,
+}
+
+/// Where readings go in the merged subtitles.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum AnnotationLayout {
+    /// A line above each line of Chinese, in the same event
+    Line,
+    /// Italic events of their own, shown at the same time as the Chinese
+    /// ones, which players stack above them
+    Layer,
+}
+
+/// Words and their readings, one syllable per character.
+#[derive(Debug, Default)]
+pub struct PinyinDictionary {
+    words: HashMap<String, Vec<String>>,
+    /// Most characters a word has
+    longest: usize,
+}
+
+/// How Chinese subtitles are annotated.
+#[derive(Debug, Clone)]
+pub struct PinyinAnnotation {
+    pub dictionary: Arc<PinyinDictionary>,
+    pub romanization: Romanization,
+    pub layout: AnnotationLayout,
+}
+
+impl PinyinAnnotation {
+    /// The reading of a line of text, or None if it has no Chinese in it.
+    pub fn reading(&self, line: &str) -> Option<String> {
+        if !line.chars().any(is_han) {
+            return None;
+        }
+        Some(self.dictionary.annotate(line, self.romanization))
+    }
+}
+
+impl PinyinDictionary {
+    /// Loads a dictionary in the CC-CEDICT format, which has both the
+    /// traditional and simplified form of every word.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path).map_err(|source| Error::Read {
+            path: path.to_owned(),
+            source,
+        })?;
+        let dictionary = Self::parse(&text);
+        if dictionary.words.is_empty() {
+            return Err(Error::Parse {
+                path: path.to_owned(),
+                message: "no words in pinyin dictionary".to_owned(),
+            });
+        }
+        Ok(dictionary)
+    }
+
+    /// Parses lines such as `銀行 银行 [yin2 hang2] /bank/`. Comments, and
+    /// words whose reading does not have a syllable for every character, are
+    /// left out.
+    pub fn parse(text: &str) -> Self {
+        let mut entries: Vec<(&str, Vec<String>)> = Vec::new();
+        for line in text.lines().filter(|line| !line.starts_with('#')) {
+            let Some((words, rest)) = line.split_once(" [") else {
+                continue;
+            };
+            let Some((reading, _)) = rest.split_once(']') else {
+                continue;
+            };
+            let Some((traditional, simplified)) = words.split_once(' ') else {
+                continue;
+            };
+            let syllables: Vec<String> = reading.split_whitespace().map(str::to_owned).collect();
+            for word in [traditional, simplified] {
+                if word.chars().count() == syllables.len() {
+                    entries.push((word, syllables.clone()));
+                }
+            }
+        }
+
+        // How often characters are read each way in words, which tells how
+        // to read them on their own
+        let mut usage: HashMap<(char, String), usize> = HashMap::new();
+        for (word, syllables) in &entries {
+            if word.chars().count() < 2 {
+                continue;
+            }
+            for (c, syllable) in word.chars().zip(syllables) {
+                *usage.entry((c, syllable.to_lowercase())).or_default() += 1;
+            }
+        }
+        let rank = |word: &str, syllables: &[String]| {
+            // Capitalized readings are of names
+            let common = syllables.iter().all(|s| !s.starts_with(char::is_uppercase));
+            let mut chars = word.chars();
+            let used = match (chars.next(), chars.next()) {
+                (Some(c), None) => usage
+                    .get(&(c, syllables[0].to_lowercase()))
+                    .copied()
+                    .unwrap_or_default(),
+                _ => 0,
+            };
+            (common, used)
+        };
+
+        let mut dictionary = Self::default();
+        let mut ranks = HashMap::new();
+        for (word, syllables) in entries {
+            let rank = rank(word, &syllables);
+            if ranks.get(word).is_some_and(|best| *best >= rank) {
+                continue;
+            }
+            ranks.insert(word, rank);
+            dictionary.longest = dictionary.longest.max(word.chars().count());
+            dictionary.words.insert(word.to_owned(), syllables);
+        }
+        dictionary
+    }
+
+    /// The reading of the text. Words are written together in pinyin and
+    /// apart from each other, while zhuyin is written a syllable at a time.
+    /// Text that is not Chinese is kept, with Chinese punctuation written the
+    /// Latin way in pinyin.
+    pub fn annotate(&self, text: &str, romanization: Romanization) -> String {
+        let chars: Vec<char> = text.chars().collect();
+        let mut annotated = String::new();
+        let mut last = None;
+        let mut push = |annotated: &mut String, token: Token, text: &str| {
+            if needs_space(last, token) {
+                annotated.push(' ');
+            }
+            annotated.push_str(text);
+            last = Some(token);
+        };
+        let mut i = 0;
+        while i < chars.len() {
+            if let Some((len, syllables)) = self.longest_word(&chars[i..]) {
+                push(
+                    &mut annotated,
+                    Token::Word,
+                    &romanize(syllables, romanization),
+                );
+                i += len;
+                continue;
+            }
+            let c = chars[i];
+            i += 1;
+            if c.is_whitespace() || c == '·' || c == '・' {
+                push(&mut annotated, Token::Space, "");
+                continue;
+            }
+            let c = match romanization {
+                Romanization::Zhuyin => c,
+                _ => latin_punctuation(c).unwrap_or(c),
+            };
+            let token = if OPENING.contains(c) {
+                Token::Opening
+            } else if CLOSING.contains(c) || c.is_ascii_punctuation() {
+                Token::Closing
+            } else {
+                Token::Text
+            };
+            push(&mut annotated, token, c.encode_utf8(&mut [0; 4]));
+        }
+        annotated.trim().to_owned()
+    }
+
+    /// The longest word the characters start with, and its reading.
+    fn longest_word(&self, chars: &[char]) -> Option<(usize, &[String])> {
+        if !chars.first().copied().is_some_and(is_han) {
+            return None;
+        }
+        (1..=self.longest.min(chars.len())).rev().find_map(|len| {
+            let word: String = chars[..len].iter().collect();
+            self.words
+                .get(&word)
+                .map(|syllables| (len, syllables.as_slice()))
+        })
+    }
+}
+
+/// Punctuation that attaches to the word after it
+const OPENING: &str = "(“‘「『（";
+
+/// Punctuation that attaches to the word before it
+const CLOSING: &str = "”’…，。！？：；、）」』";
+
+/// What annotated text is made of, which tells where spaces go.
+#[derive(Debug, Clone, Copy)]
+enum Token {
+    /// The reading of a word
+    Word,
+    /// Text that is kept, such as Latin letters
+    Text,
+    Opening,
+    Closing,
+    /// Whitespace, which is kept as a single space
+    Space,
+}
+
+fn needs_space(last: Option<Token>, next: Token) -> bool {
+    !matches!(
+        (last, next),
+        (None | Some(Token::Opening), _)
+            | (_, Token::Space | Token::Closing)
+            | (Some(Token::Text), Token::Text)
+    )
+}
+
+/// Chinese punctuation written the Latin way.
+fn latin_punctuation(c: char) -> Option<char> {
+    let latin = match c {
+        '，' | '、' => ',',
+        '。' => '.',
+        '！' => '!',
+        '？' => '?',
+        '：' => ':',
+        '；' => ';',
+        '（' => '(',
+        '）' => ')',
+        '「' | '『' => '“',
+        '」' | '』' => '”',
+        _ => return None,
+    };
+    Some(latin)
+}
+
+fn romanize(syllables: &[String], romanization: Romanization) -> String {
+    match romanization {
+        Romanization::ToneMarks => {
+            let mut word = String::new();
+            for syllable in syllables {
+                // Syllables starting with a vowel are set apart, as in `nǚ'ér`
+                let vowel = syllable.starts_with(['a', 'e', 'o', 'A', 'E', 'O']);
+                if !word.is_empty() && vowel {
+                    word.push('\'');
+                }
+                word.push_str(&tone_marked(syllable));
+            }
+            word
+        }
+        Romanization::ToneNumbers => syllables.iter().map(|s| s.replace("u:", "ü")).collect(),
+        Romanization::Zhuyin => syllables
+            .iter()
+            .map(|s| zhuyin(s).unwrap_or_else(|| tone_marked(s)))
+            .collect::<Vec<_>>()
+            .join(" "),
+    }
+}
+
+/// Splits a syllable such as `lu:4` into `lü` and its tone, which is 5 for
+/// the neutral tone and 0 if it has none.
+fn split_tone(syllable: &str) -> (String, u8) {
+    let body = syllable.replace("u:", "ü").replace("U:", "Ü");
+    match body.chars().last().and_then(|c| c.to_digit(10)) {
+        Some(tone) => (body[..body.len() - 1].to_owned(), tone as u8),
+        None => (body, 0),
+    }
+}
+
+/// Vowels along with their first to fourth tone marks
+const TONE_MARKS: [(char, [char; 4]); 9] = [
+    ('a', ['ā', 'á', 'ǎ', 'à']),
+    ('e', ['ē', 'é', 'ě', 'è']),
+    ('i', ['ī', 'í', 'ǐ', 'ì']),
+    ('o', ['ō', 'ó', 'ǒ', 'ò']),
+    ('u', ['ū', 'ú', 'ǔ', 'ù']),
+    ('ü', ['ǖ', 'ǘ', 'ǚ', 'ǜ']),
+    ('A', ['Ā', 'Á', 'Ǎ', 'À']),
+    ('E', ['Ē', 'É', 'Ě', 'È']),
+    ('O', ['Ō', 'Ó', 'Ǒ', 'Ò']),
+];
+
+/// A syllable such as `hao3` written as `hǎo`. The mark goes on `a` or `e`
+/// if there is one, on the `o` of `ou`, and on the last vowel otherwise.
+fn tone_marked(syllable: &str) -> String {
+    let (body, tone) = split_tone(syllable);
+    if !(1..=4).contains(&tone) {
+        return body;
+    }
+    let lower = body.to_lowercase();
+    let position = lower
+        .find(['a', 'e'])
+        .or_else(|| lower.find("ou"))
+        .or_else(|| lower.rfind(['i', 'o', 'u', 'ü']));
+    let Some(position) = position else {
+        return body;
+    };
+    let vowel = body[position..].chars().next().unwrap_or_default();
+    let Some((_, marks)) = TONE_MARKS.iter().find(|(v, _)| *v == vowel) else {
+        return body;
+    };
+    let mut marked = body[..position].to_owned();
+    marked.push(marks[usize::from(tone) - 1]);
+    marked.push_str(&body[position + vowel.len_utf8()..]);
+    marked
+}
+
+/// Initials, longest first so that `zh` is not taken for `z`
+const INITIALS: [(&str, &str); 21] = [
+    ("zh", "ㄓ"),
+    ("ch", "ㄔ"),
+    ("sh", "ㄕ"),
+    ("b", "ㄅ"),
+    ("p", "ㄆ"),
+    ("m", "ㄇ"),
+    ("f", "ㄈ"),
+    ("d", "ㄉ"),
+    ("t", "ㄊ"),
+    ("n", "ㄋ"),
+    ("l", "ㄌ"),
+    ("g", "ㄍ"),
+    ("k", "ㄎ"),
+    ("h", "ㄏ"),
+    ("j", "ㄐ"),
+    ("q", "ㄑ"),
+    ("x", "ㄒ"),
+    ("r", "ㄖ"),
+    ("z", "ㄗ"),
+    ("c", "ㄘ"),
+    ("s", "ㄙ"),
+];
+
+/// Finals as written after an initial, or with `y` and `w` spelled out
+const FINALS: [(&str, &str); 42] = [
+    ("", ""),
+    ("a", "ㄚ"),
+    ("o", "ㄛ"),
+    ("e", "ㄜ"),
+    ("ê", "ㄝ"),
+    ("ai", "ㄞ"),
+    ("ei", "ㄟ"),
+    ("ao", "ㄠ"),
+    ("ou", "ㄡ"),
+    ("an", "ㄢ"),
+    ("en", "ㄣ"),
+    ("ang", "ㄤ"),
+    ("eng", "ㄥ"),
+    ("er", "ㄦ"),
+    ("ong", "ㄨㄥ"),
+    ("i", "ㄧ"),
+    ("ia", "ㄧㄚ"),
+    ("io", "ㄧㄛ"),
+    ("ie", "ㄧㄝ"),
+    ("iao", "ㄧㄠ"),
+    ("iu", "ㄧㄡ"),
+    ("iou", "ㄧㄡ"),
+    ("ian", "ㄧㄢ"),
+    ("in", "ㄧㄣ"),
+    ("iang", "ㄧㄤ"),
+    ("ing", "ㄧㄥ"),
+    ("iong", "ㄩㄥ"),
+    ("u", "ㄨ"),
+    ("ua", "ㄨㄚ"),
+    ("uo", "ㄨㄛ"),
+    ("uai", "ㄨㄞ"),
+    ("ui", "ㄨㄟ"),
+    ("uei", "ㄨㄟ"),
+    ("uan", "ㄨㄢ"),
+    ("un", "ㄨㄣ"),
+    ("uen", "ㄨㄣ"),
+    ("uang", "ㄨㄤ"),
+    ("ueng", "ㄨㄥ"),
+    ("ü", "ㄩ"),
+    ("üe", "ㄩㄝ"),
+    ("üan", "ㄩㄢ"),
+    ("ün", "ㄩㄣ"),
+];
+
+/// A syllable such as `hao3` written in zhuyin, as `ㄏㄠˇ`. None if it is
+/// not a Mandarin syllable, such as a Latin letter in a word.
+fn zhuyin(syllable: &str) -> Option<String> {
+    let (body, tone) = split_tone(syllable);
+    let body = body.to_lowercase();
+    if tone == 0 {
+        return None;
+    }
+    if body == "r" {
+        // Erhua
+        return Some("ㄦ".to_owned());
+    }
+    let (initial, symbol, rest) = INITIALS
+        .iter()
+        .find_map(|(initial, symbol)| {
+            let rest = body.strip_prefix(initial)?;
+            Some((*initial, *symbol, rest.to_owned()))
+        })
+        .unwrap_or(("", "", body.clone()));
+
+    let last = if initial.is_empty() {
+        // `y` and `w` stand for the medials `i`, `ü` and `u`
+        if let Some(rest) = rest.strip_prefix("yu") {
+            format!("ü{rest}")
+        } else if let Some(rest) = rest.strip_prefix('y') {
+            if rest.starts_with('i') {
+                rest.to_owned()
+            } else {
+                format!("i{rest}")
+            }
+        } else if let Some(rest) = rest.strip_prefix('w') {
+            if rest.starts_with('u') {
+                rest.to_owned()
+            } else {
+                format!("u{rest}")
+            }
+        } else {
+            rest
+        }
+    } else if ["j", "q", "x"].contains(&initial) && rest.starts_with('u') {
+        rest.replacen('u', "ü", 1)
+    } else if ["zh", "ch", "sh", "r", "z", "c", "s"].contains(&initial) && rest == "i" {
+        // The vowel of `zhi` and `si` is not written
+        String::new()
+    } else {
+        rest
+    };
+    let (_, last) = FINALS.iter().find(|(final_, _)| *final_ == last)?;
+    if symbol.is_empty() && last.is_empty() {
+        return None;
+    }
+    let mark = match tone {
+        2 => "ˊ",
+        3 => "ˇ",
+        4 => "ˋ",
+        _ => "",
+    };
+    let neutral = if tone == 5 { "˙" } else { "" };
+    Some(format!("{neutral}{symbol}{last}{mark}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    const CEDICT: &str = "\
+# CC-CEDICT
+銀行 银行 [yin2 hang2] /bank/
+行 行 [hang2] /row/
+行 行 [xing2] /to walk/
+行走 行走 [xing2 zou3] /to walk/
+行人 行人 [xing2 ren2] /pedestrian/
+我們 我们 [wo3 men5] /we/
+我 我 [wo3] /I/
+必須 必须 [bi4 xu1] /must/
+找到 找到 [zhao3 dao4] /to find/
+他 他 [ta1] /he/
+張 张 [Zhang1] /surname Zhang/
+張 张 [zhang1] /sheet/
+女兒 女儿 [nu:3 er2] /daughter/
+一點兒 一点儿 [yi1 dian3 r5] /a bit/
+T恤 T恤 [T xu4] /T-shirt/
+";
+
+    fn dictionary() -> PinyinDictionary {
+        PinyinDictionary::parse(CEDICT)
+    }
+
+    #[rstest]
+    #[case("我们必须找到他。", Romanization::ToneMarks, "wǒmen bìxū zhǎodào tā.")]
+    #[case(
+        "我們必須找到他。",
+        Romanization::ToneNumbers,
+        "wo3men5 bi4xu1 zhao3dao4 ta1."
+    )]
+    #[case(
+        "我们必须找到他。",
+        Romanization::Zhuyin,
+        "ㄨㄛˇ ˙ㄇㄣ ㄅㄧˋ ㄒㄩ ㄓㄠˇ ㄉㄠˋ ㄊㄚ。"
+    )]
+    #[case("银行，行走", Romanization::ToneMarks, "yínháng, xíngzǒu")]
+    #[case("行", Romanization::ToneMarks, "xíng")]
+    #[case("张", Romanization::ToneMarks, "zhāng")]
+    #[case("女儿", Romanization::ToneMarks, "nǚ'ér")]
+    #[case("一点儿", Romanization::Zhuyin, "ㄧ ㄉㄧㄢˇ ㄦ")]
+    #[case("「他」Tom", Romanization::ToneMarks, "“tā” Tom")]
+    fn test_annotate(#[case] text: &str, #[case] romanization: Romanization, #[case] should: &str) {
+        assert_eq!(dictionary().annotate(text, romanization), should);
+    }
+
+    #[rstest]
+    #[case("hao3", "hǎo")]
+    #[case("gui4", "guì")]
+    #[case("liu2", "liú")]
+    #[case("lu:4", "lǜ")]
+    #[case("Ou1", "Ōu")]
+    #[case("men5", "men")]
+    fn test_tone_marked(#[case] syllable: &str, #[case] should: &str) {
+        assert_eq!(tone_marked(syllable), should);
+    }
+
+    #[rstest]
+    #[case("zhi1", Some("ㄓ"))]
+    #[case("si4", Some("ㄙˋ"))]
+    #[case("yi1", Some("ㄧ"))]
+    #[case("you3", Some("ㄧㄡˇ"))]
+    #[case("yuan2", Some("ㄩㄢˊ"))]
+    #[case("wen4", Some("ㄨㄣˋ"))]
+    #[case("qu4", Some("ㄑㄩˋ"))]
+    #[case("lu:e4", Some("ㄌㄩㄝˋ"))]
+    #[case("gui4", Some("ㄍㄨㄟˋ"))]
+    #[case("de5", Some("˙ㄉㄜ"))]
+    #[case("T", None)]
+    fn test_zhuyin(#[case] syllable: &str, #[case] should: Option<&str>) {
+        assert_eq!(zhuyin(syllable).as_deref(), should);
+    }
+}
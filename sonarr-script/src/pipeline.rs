@@ -0,0 +1,466 @@
+//! Turns imported media files into bilingual subtitles, however they are
+//! fed in: by the Custom Script, the webhook server or the directory watcher.
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tracing::info;
+use tracing::warn;
+
+use crate::cache::CacheArgs;
+use crate::cache::SubtitleCache;
+use crate::detect::DetectArgs;
+use crate::detect::LanguageDetection;
+use crate::error::Error;
+use crate::error::Result;
+use crate::jobs::JobStore;
+use crate::jobs::JobStoreArgs;
+use crate::naming::NamingPreset;
+use crate::naming::SidecarNaming;
+use crate::notifier::Notifier;
+use crate::notifier::NotifierArgs;
+use crate::notifier::RescanRequest;
+use crate::overwrite::OverwritePolicy;
+use crate::pinyin::AnnotationLayout;
+use crate::pinyin::PinyinAnnotation;
+use crate::pinyin::PinyinDictionary;
+use crate::pinyin::Romanization;
+use crate::process::CommandRunner;
+use crate::process::SystemRunner;
+use crate::reflow::ReflowOptions;
+use crate::remux::OutputMode;
+use crate::report::ProcessingReport;
+use crate::subtitle;
+use crate::subtitle::LanguageCheck;
+use crate::subtitle::OcrOptions;
+use crate::subtitle::SubtitleMergeContext;
+
+/// Configuration shared by every way of feeding media files into the
+/// pipeline: the Custom Script, the webhook server and the directory watcher.
+#[derive(Debug, Clone, clap::Args)]
+pub struct PipelineArgs {
+    #[clap(flatten)]
+    pub notifier: NotifierArgs,
+
+    #[clap(flatten)]
+    pub jobs: JobStoreArgs,
+
+    #[clap(flatten)]
+    pub cache: CacheArgs,
+
+    #[clap(flatten)]
+    pub detect: DetectArgs,
+
+    /// Seconds ffmpeg may spend extracting subtitles from one media file
+    #[clap(long, env = "EXTRACT_TIMEOUT", default_value_t = 1800)]
+    pub extract_timeout: u64,
+
+    /// ASS style names whose events are not dialogue, such as signs, and are
+    /// left out of the sidecars
+    #[clap(
+        long,
+        env = "CLEAR_STYLES",
+        value_delimiter = ',',
+        default_value = "sign,signs"
+    )]
+    pub clear_styles: Vec<String>,
+
+    /// Recognize the text of PGS and VobSub subtitles with Tesseract, for
+    /// languages without a text subtitle stream
+    #[clap(long, env = "OCR")]
+    pub ocr: bool,
+
+    /// Directory holding Tesseract's `.traineddata` files, which needs `eng`,
+    /// `chi_sim` and `chi_tra`, as well as `jpn` and `kor` for Japanese and
+    /// Korean
+    #[clap(long, env = "TESSDATA_DIR")]
+    pub tessdata_dir: Option<PathBuf>,
+
+    /// Recognized events with a lower mean confidence, out of 100, are
+    /// dropped
+    #[clap(long, env = "OCR_MIN_CONFIDENCE", default_value_t = 60.0)]
+    pub ocr_min_confidence: f32,
+
+    /// Where merged subtitles are written. Embedding remuxes the merged
+    /// bilingual subtitles into the media file, which must be mkv
+    /// or mp4, for players that ignore sidecars.
+    #[clap(long, env = "OUTPUT", default_value = "sidecar")]
+    pub output: OutputMode,
+
+    /// Make the first embedded subtitle the default subtitle stream
+    #[clap(long, env = "EMBED_DEFAULT")]
+    pub embed_default: bool,
+
+    /// Media server whose conventions sidecars are named after
+    #[clap(long, env = "SIDECAR_NAMING", default_value = "default")]
+    pub sidecar_naming: NamingPreset,
+
+    /// Sidecar name template overriding the preset's, such as
+    /// `{stem}.{lang}{.default}.{ext}`. The preset still decides how
+    /// languages are spelled.
+    #[clap(long, env = "SIDECAR_TEMPLATE")]
+    pub sidecar_template: Option<String>,
+
+    /// Language of the sidecar flagged as the default one, if the template
+    /// has `{.default}`: `en`, `zh`, `zh-TW`, `ja` or `ko`
+    #[clap(long, env = "DEFAULT_SIDECAR", value_parser = ["en", "zh", "zh-TW", "ja", "ko"])]
+    pub default_sidecar: Option<String>,
+
+    /// What to do with sidecars that are already there. Which sidecars are
+    /// ours is remembered in the subtitle directory.
+    #[clap(long, env = "OVERWRITE", default_value = "always")]
+    pub overwrite: OverwritePolicy,
+
+    /// Copy sidecars that were not written by us to `.bak` before
+    /// overwriting them
+    #[clap(long, env = "BACKUP_SIDECARS")]
+    pub backup_sidecars: bool,
+
+    /// What to do about subtitle streams whose language tag disagrees with
+    /// the language of their text, such as Chinese tagged `und`
+    #[clap(long, env = "LANGUAGE_CHECK", default_value = "override")]
+    pub language_check: LanguageCheck,
+
+    /// Remove furigana written inline after kanji, such as `漢字（かんじ）`,
+    /// from Japanese subtitles
+    #[clap(long, env = "STRIP_FURIGANA")]
+    pub strip_furigana: bool,
+
+    /// Remove lines of romaji, such as transliterated song lyrics, from
+    /// Japanese subtitles
+    #[clap(long, env = "STRIP_ROMAJI")]
+    pub strip_romaji: bool,
+
+    /// Rebreak subtitle lines to be at most this wide, in columns where East
+    /// Asian characters take two. Lines are left as they are if not set
+    #[clap(long, env = "REFLOW_WIDTH")]
+    pub reflow_width: Option<usize>,
+
+    /// Most lines a rebroken subtitle event may have, per language
+    #[clap(long, env = "REFLOW_LINES", default_value_t = 2)]
+    pub reflow_lines: usize,
+
+    /// Split rebroken subtitle events that need more lines than that into
+    /// two events, instead of letting their lines run wide
+    #[clap(long, env = "REFLOW_SPLIT")]
+    pub reflow_split: bool,
+
+    /// Annotate Chinese subtitles with their reading, in pinyin with tone
+    /// marks or numbers, or in zhuyin. Needs `--pinyin-dictionary`
+    #[clap(long, env = "PINYIN", requires = "pinyin_dictionary")]
+    pub pinyin: Option<Romanization>,
+
+    /// Where readings go in the merged subtitles
+    #[clap(long, env = "PINYIN_LAYOUT", default_value = "line")]
+    pub pinyin_layout: AnnotationLayout,
+
+    /// CC-CEDICT dictionary file readings are looked up in, such as
+    /// `cedict_ts.u8` from <https://www.mdbg.net/chinese/dictionary?page=cc-cedict>
+    #[clap(long, env = "PINYIN_DICTIONARY")]
+    pub pinyin_dictionary: Option<PathBuf>,
+
+    /// Whether a media file without suitable subtitles, such as one without
+    /// English ones, counts as processed or as failed
+    #[clap(long, env = "NO_SUBTITLES", default_value = "succeed")]
+    pub no_subtitles: NoSubtitles,
+
+    /// Also write the report of what processing each media file found and
+    /// did to this file, as a line of JSON appended to it, or to stdout if
+    /// `-`. The latest report is always kept with the extracted subtitles
+    #[clap(long, env = "REPORT")]
+    pub report: Option<PathBuf>,
+}
+
+/// What a media file without suitable subtitles comes to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum NoSubtitles {
+    /// Nothing is written, but the file counts as processed, so that it is
+    /// not retried
+    Succeed,
+    /// The file fails, with its own exit code
+    Fail,
+}
+
+impl PipelineArgs {
+    pub fn naming(&self) -> Result<SidecarNaming> {
+        SidecarNaming::new(
+            self.sidecar_naming,
+            self.sidecar_template.as_deref(),
+            self.default_sidecar.clone(),
+        )
+        .map_err(config_error)
+    }
+
+    /// Loads the pinyin dictionary, if Chinese is to be annotated.
+    fn pinyin_annotation(&self) -> Result<Option<PinyinAnnotation>> {
+        let (Some(romanization), Some(path)) = (self.pinyin, &self.pinyin_dictionary) else {
+            return Ok(None);
+        };
+        let dictionary = PinyinDictionary::load(path)?;
+        info!(path = %path.display(), "loaded pinyin dictionary");
+        Ok(Some(PinyinAnnotation {
+            dictionary: Arc::new(dictionary),
+            romanization,
+            layout: self.pinyin_layout,
+        }))
+    }
+
+    pub fn build(&self) -> Result<Pipeline> {
+        self.build_with(JobStore::open(&self.jobs).map_err(config_error)?)
+    }
+
+    /// Same as `build`, but jobs go unrecorded if the job store has nowhere
+    /// to be kept, for the Custom Script which runs once per import.
+    pub fn build_one_shot(&self) -> Result<Pipeline> {
+        self.build_with(JobStore::open_optional(&self.jobs).map_err(config_error)?)
+    }
+
+    fn build_with(&self, store: JobStore) -> Result<Pipeline> {
+        if self.ocr && !cfg!(feature = "ocr") {
+            return Err(Error::Config(
+                "OCR requested, but built without the `ocr` feature".to_owned(),
+            ));
+        }
+        let detection = LanguageDetection::from_args(&self.detect).map_err(config_error)?;
+        let extract_timeout = Duration::from_secs(self.extract_timeout);
+        Ok(Pipeline {
+            notifier: Notifier::from_args(&self.notifier).map_err(config_error)?,
+            store,
+            cache: SubtitleCache::new(&self.cache),
+            extract_timeout,
+            clear_styles: self.clear_styles.iter().map(|s| s.to_lowercase()).collect(),
+            ocr: self.ocr.then(|| OcrOptions {
+                tessdata_dir: self.tessdata_dir.clone(),
+                min_confidence: self.ocr_min_confidence,
+                timeout: extract_timeout,
+            }),
+            output: self.output,
+            embed_default: self.embed_default,
+            naming: self.naming()?,
+            overwrite: self.overwrite,
+            backup_sidecars: self.backup_sidecars,
+            language_check: self.language_check,
+            detection: Arc::new(detection),
+            strip_furigana: self.strip_furigana,
+            strip_romaji: self.strip_romaji,
+            reflow: self.reflow_width.map(|max_width| ReflowOptions {
+                max_width,
+                max_lines: self.reflow_lines,
+                split_events: self.reflow_split,
+            }),
+            pinyin: self.pinyin_annotation()?,
+            no_subtitles: self.no_subtitles,
+            report: self.report.clone(),
+            runner: Arc::new(SystemRunner),
+        })
+    }
+}
+
+/// Options that are set but unusable, such as a notifier URL without its key
+/// or a job store that cannot be created, are configuration errors.
+fn config_error(error: anyhow::Error) -> Error {
+    Error::Config(format!("{error:#}"))
+}
+
+pub struct Pipeline {
+    pub notifier: Notifier,
+    pub store: JobStore,
+    pub cache: SubtitleCache,
+    pub extract_timeout: Duration,
+    pub clear_styles: HashSet<String>,
+    pub ocr: Option<OcrOptions>,
+    pub output: OutputMode,
+    pub embed_default: bool,
+    pub naming: SidecarNaming,
+    pub overwrite: OverwritePolicy,
+    pub backup_sidecars: bool,
+    pub language_check: LanguageCheck,
+    pub detection: Arc<LanguageDetection>,
+    pub strip_furigana: bool,
+    pub strip_romaji: bool,
+    pub reflow: Option<ReflowOptions>,
+    pub pinyin: Option<PinyinAnnotation>,
+    pub no_subtitles: NoSubtitles,
+    pub report: Option<PathBuf>,
+    pub runner: Arc<dyn CommandRunner>,
+}
+
+impl Pipeline {
+    /// Processes the job, recording its outcome in the job store.
+    pub fn run(&self, job: &ImportJob) -> Result<Vec<PathBuf>> {
+        Ok(self.store.run(job, |job| self.process(job))?)
+    }
+
+    /// Writes sidecar subtitles for the media file and notifies media servers
+    /// about them, returning the paths of the sidecars written.
+    fn process(&self, job: &ImportJob) -> anyhow::Result<Vec<PathBuf>> {
+        let context = SubtitleMergeContext {
+            media_file: job.media_file.clone(),
+            runner: Arc::clone(&self.runner),
+            cache: self.cache.clone(),
+            extract_timeout: self.extract_timeout,
+            clear_styles: self.clear_styles.clone(),
+            ocr: self.ocr.clone(),
+            output: self.output,
+            embed_default: self.embed_default,
+            naming: self.naming.clone(),
+            overwrite: self.overwrite,
+            backup_sidecars: self.backup_sidecars,
+            language_check: self.language_check,
+            detection: Arc::clone(&self.detection),
+            strip_furigana: self.strip_furigana,
+            strip_romaji: self.strip_romaji,
+            reflow: self.reflow,
+            pinyin: self.pinyin.clone(),
+        };
+        let mut report = ProcessingReport::new(&job.media_file);
+        let result = subtitle::extract_and_merge(&context, &mut report);
+        if let Err(error) = &result {
+            report.error = Some(error.to_string());
+        }
+        self.keep_report(&report);
+
+        let written = match result {
+            Err(Error::NoSuitableSubtitles { path })
+                if self.no_subtitles == NoSubtitles::Succeed =>
+            {
+                info!(media_file = %path.display(), "no suitable subtitles, nothing to do");
+                return Ok(Vec::new());
+            }
+            result => result?,
+        };
+
+        if written.is_empty() || self.notifier.is_empty() {
+            return Ok(written);
+        }
+        let request = RescanRequest {
+            media_file: &job.media_file,
+            series_id: job.series_id,
+            movie_id: job.movie_id,
+        };
+        // Subtitles are already written at this point, so a failed rescan
+        // should not fail the import
+        if let Err(error) = self.notifier.notify(&request) {
+            warn!(?error, "failed notifying media servers");
+        }
+
+        Ok(written)
+    }
+
+    /// Keeps the report with the extracted subtitles and writes it to the
+    /// report file, if any. Reports are not worth failing the import over.
+    fn keep_report(&self, report: &ProcessingReport) {
+        // There is no entry if processing failed before extracting anything
+        if let Ok(entry_dir) = self.cache.entry_dir(&report.media_file)
+            && entry_dir.is_dir()
+            && let Err(error) = report.save(&entry_dir)
+        {
+            warn!(?error, "failed keeping processing report");
+        }
+        if let Some(output) = &self.report
+            && let Err(error) = report.emit(output)
+        {
+            warn!(?error, "failed writing processing report");
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, clap::ValueEnum)]
+#[clap(rename_all = "pascal_case")]
+#[non_exhaustive]
+pub enum EventType {
+    Test,
+    Import,
+}
+
+/// A single imported media file to process, whether it came from Custom Script
+/// environment variables or a webhook.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ImportJob {
+    pub media_file: PathBuf,
+    pub series_id: Option<u64>,
+    pub movie_id: Option<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use clap::Parser;
+    use rstest::rstest;
+
+    use super::*;
+    use crate::jobs::JobState;
+    use crate::process::FakeRunner;
+    use crate::process::ProcessOutput;
+
+    #[derive(clap::Parser)]
+    struct Cli {
+        #[clap(flatten)]
+        pipeline: PipelineArgs,
+    }
+
+    fn args(job_store: &Path, args: &[&str]) -> PipelineArgs {
+        let job_store = ["--job-store", job_store.to_str().unwrap()];
+        Cli::try_parse_from(["sonarr-script"].iter().chain(&job_store).chain(args))
+            .unwrap()
+            .pipeline
+    }
+
+    #[rstest]
+    #[case("jobs.json", &["--sonarr-url", "http://sonarr:8989"])]
+    // The job store's directory cannot be created under a file
+    #[case("episode.mkv/jobs.json", &[])]
+    fn test_build_reports_config_errors(#[case] job_store: &str, #[case] options: &[&str]) {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("episode.mkv"), "").unwrap();
+
+        let Err(error) = args(&dir.path().join(job_store), options).build() else {
+            panic!("built a pipeline with invalid options");
+        };
+
+        assert!(matches!(error, Error::Config(_)), "{error:?}");
+        assert_eq!(error.exit_code(), 3);
+    }
+
+    #[rstest]
+    #[case("succeed", None, JobState::Succeeded)]
+    #[case("fail", Some(5), JobState::Pending)]
+    fn test_run_without_english_subtitles(
+        #[case] no_subtitles: &str,
+        #[case] should_exit_with: Option<u8>,
+        #[case] should_state: JobState,
+    ) {
+        let dir = tempfile::tempdir().unwrap();
+        let media_file = dir.path().join("episode.mp4");
+        std::fs::write(&media_file, "").unwrap();
+        let runner = FakeRunner::new([Ok(ProcessOutput {
+            stdout: String::new(),
+            stderr: "Input #0, mov,mp4,m4a,3gp,3g2,mj2, from 'episode.mp4':\n".to_owned(),
+        })]);
+        let pipeline = Pipeline {
+            runner: Arc::new(runner),
+            ..args(
+                &dir.path().join("jobs.json"),
+                &["--no-subtitles", no_subtitles],
+            )
+            .build()
+            .unwrap()
+        };
+        let job = ImportJob {
+            media_file,
+            series_id: None,
+            movie_id: None,
+        };
+
+        let result = pipeline.run(&job);
+
+        match should_exit_with {
+            None => assert!(result.unwrap().is_empty()),
+            Some(code) => assert_eq!(result.unwrap_err().exit_code(), code),
+        }
+        assert_eq!(pipeline.store.list().unwrap()[0].state, should_state);
+    }
+}
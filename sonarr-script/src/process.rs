@@ -0,0 +1,351 @@
+//! Runs external programs such as ffmpeg and ffprobe, and tells why they
+//! failed.
+
+use std::ffi::OsStr;
+use std::ffi::OsString;
+use std::fmt;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::process::Command;
+use std::process::Stdio;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use wait_timeout::ChildExt;
+
+/// An external program invocation, such as ffmpeg or ffprobe.
+#[derive(Debug, Clone)]
+pub struct ProcessCommand {
+    pub program: String,
+    pub args: Vec<OsString>,
+    pub timeout: Duration,
+}
+
+impl ProcessCommand {
+    pub fn new(program: &str, timeout: Duration) -> Self {
+        Self {
+            program: program.to_owned(),
+            args: Vec::new(),
+            timeout,
+        }
+    }
+
+    pub fn arg(mut self, arg: impl AsRef<OsStr>) -> Self {
+        self.args.push(arg.as_ref().to_owned());
+        self
+    }
+
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        self.args
+            .extend(args.into_iter().map(|a| a.as_ref().to_owned()));
+        self
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ProcessOutput {
+    pub stdout: String,
+    pub stderr: String,
+}
+
+/// Why a program failed, as far as can be told from its stderr.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FailureKind {
+    PermissionDenied,
+    NoSpace,
+    FileNotFound,
+    InvalidData,
+    UnsupportedCodec,
+    /// Anything else, with the last line of stderr
+    Other(String),
+}
+
+impl FailureKind {
+    pub fn from_stderr(stderr: &str) -> Self {
+        let patterns = [
+            ("Permission denied", FailureKind::PermissionDenied),
+            ("No space left on device", FailureKind::NoSpace),
+            ("No such file or directory", FailureKind::FileNotFound),
+            (
+                "Invalid data found when processing input",
+                FailureKind::InvalidData,
+            ),
+            (
+                "Subtitle encoding currently only possible",
+                FailureKind::UnsupportedCodec,
+            ),
+            ("Unknown encoder", FailureKind::UnsupportedCodec),
+            ("Encoder not found", FailureKind::UnsupportedCodec),
+            ("Decoder not found", FailureKind::UnsupportedCodec),
+        ];
+        for (pattern, kind) in patterns {
+            if stderr.contains(pattern) {
+                return kind;
+            }
+        }
+        let last_line = stderr
+            .lines()
+            .rev()
+            .find(|line| !line.trim().is_empty())
+            .unwrap_or_default();
+        FailureKind::Other(last_line.trim().to_owned())
+    }
+}
+
+impl fmt::Display for FailureKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FailureKind::PermissionDenied => write!(f, "permission denied"),
+            FailureKind::NoSpace => write!(f, "no space left on device"),
+            FailureKind::FileNotFound => write!(f, "file not found"),
+            FailureKind::InvalidData => write!(f, "invalid or corrupt input"),
+            FailureKind::UnsupportedCodec => write!(f, "unsupported codec"),
+            FailureKind::Other(line) => write!(f, "{line}"),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProcessError {
+    #[error("{program} was not found, is it installed?")]
+    NotFound { program: String },
+
+    #[error("failed running {program}: {source}")]
+    Io {
+        program: String,
+        source: std::io::Error,
+    },
+
+    #[error("{program} timed out after {timeout:?}")]
+    Timeout { program: String, timeout: Duration },
+
+    #[error("{program} exited with {}: {kind}", code.map_or("a signal".to_owned(), |c| format!("code {c}")))]
+    Failed {
+        program: String,
+        code: Option<i32>,
+        kind: FailureKind,
+        stderr: String,
+    },
+}
+
+/// Runs external programs. Swappable so tests do not need ffmpeg installed.
+pub trait CommandRunner: Send + Sync {
+    /// Runs the command to completion, failing if it exits unsuccessfully or
+    /// outlives its timeout. Each line of stdout is passed to `on_stdout_line`
+    /// as it is produced.
+    fn run(
+        &self,
+        command: &ProcessCommand,
+        on_stdout_line: &mut dyn FnMut(&str),
+    ) -> Result<ProcessOutput, ProcessError>;
+}
+
+/// Runs commands as real child processes.
+pub struct SystemRunner;
+
+impl CommandRunner for SystemRunner {
+    fn run(
+        &self,
+        command: &ProcessCommand,
+        on_stdout_line: &mut dyn FnMut(&str),
+    ) -> Result<ProcessOutput, ProcessError> {
+        let program = &command.program;
+        let io_error = |source: std::io::Error| ProcessError::Io {
+            program: program.clone(),
+            source,
+        };
+
+        let deadline = Instant::now() + command.timeout;
+        let mut child = Command::new(program)
+            .args(&command.args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|source| match source.kind() {
+                std::io::ErrorKind::NotFound => ProcessError::NotFound {
+                    program: program.clone(),
+                },
+                _ => io_error(source),
+            })?;
+
+        // Both pipes are drained on their own threads so a chatty child can
+        // never block on a full pipe
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                if tx.send(line).is_err() {
+                    return;
+                }
+            }
+        });
+        let mut stderr = child.stderr.take().expect("stderr is piped");
+        let stderr = thread::spawn(move || {
+            let mut buf = String::new();
+            let _ = stderr.read_to_string(&mut buf);
+            buf
+        });
+
+        let mut output = ProcessOutput::default();
+        let mut timed_out = false;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match rx.recv_timeout(remaining) {
+                Ok(line) => {
+                    on_stdout_line(&line);
+                    output.stdout.push_str(&line);
+                    output.stdout.push('\n');
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    timed_out = true;
+                    break;
+                }
+            }
+        }
+
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let status = match timed_out {
+            true => None,
+            false => child.wait_timeout(remaining).map_err(io_error)?,
+        };
+        let Some(status) = status else {
+            child.kill().map_err(io_error)?;
+            child.wait().map_err(io_error)?;
+            return Err(ProcessError::Timeout {
+                program: program.clone(),
+                timeout: command.timeout,
+            });
+        };
+
+        output.stderr = stderr.join().unwrap_or_default();
+        if !status.success() {
+            return Err(ProcessError::Failed {
+                program: program.clone(),
+                code: status.code(),
+                kind: FailureKind::from_stderr(&output.stderr),
+                stderr: output.stderr,
+            });
+        }
+
+        Ok(output)
+    }
+}
+
+/// Replays canned results and records the commands it was asked to run.
+#[cfg(test)]
+#[derive(Default)]
+pub struct FakeRunner {
+    pub results: std::sync::Mutex<std::collections::VecDeque<Result<ProcessOutput, ProcessError>>>,
+    pub commands: std::sync::Mutex<Vec<ProcessCommand>>,
+}
+
+#[cfg(test)]
+impl FakeRunner {
+    pub fn new(results: impl IntoIterator<Item = Result<ProcessOutput, ProcessError>>) -> Self {
+        Self {
+            results: std::sync::Mutex::new(results.into_iter().collect()),
+            commands: Default::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl CommandRunner for FakeRunner {
+    fn run(
+        &self,
+        command: &ProcessCommand,
+        on_stdout_line: &mut dyn FnMut(&str),
+    ) -> Result<ProcessOutput, ProcessError> {
+        self.commands.lock().unwrap().push(command.clone());
+        let result = self
+            .results
+            .lock()
+            .unwrap()
+            .pop_front()
+            .expect("fake runner ran out of results");
+        if let Ok(output) = &result {
+            output.stdout.lines().for_each(on_stdout_line);
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    fn sh(script: &str, timeout: Duration) -> Result<ProcessOutput, ProcessError> {
+        let command = ProcessCommand::new("sh", timeout).args(["-c", script]);
+        SystemRunner.run(&command, &mut |_| {})
+    }
+
+    #[rstest]
+    #[case("out.srt: Permission denied", FailureKind::PermissionDenied)]
+    #[case("Error writing trailer: No space left on device", FailureKind::NoSpace)]
+    #[case(
+        "Subtitle encoding currently only possible from text to text or bitmap to bitmap",
+        FailureKind::UnsupportedCodec
+    )]
+    #[case(
+        "in.mkv: Invalid data found when processing input",
+        FailureKind::InvalidData
+    )]
+    #[case("first\nsomething odd\n\n", FailureKind::Other("something odd".into()))]
+    fn test_failure_kind_from_stderr(#[case] stderr: &str, #[case] should: FailureKind) {
+        assert_eq!(FailureKind::from_stderr(stderr), should);
+    }
+
+    #[test]
+    fn test_system_runner_captures_output() {
+        let mut lines = Vec::new();
+        let command = ProcessCommand::new("sh", Duration::from_secs(10))
+            .args(["-c", "echo one; echo two; echo err >&2"]);
+        let output = SystemRunner
+            .run(&command, &mut |line| lines.push(line.to_owned()))
+            .unwrap();
+
+        assert_eq!(lines, ["one", "two"]);
+        assert_eq!(output.stderr, "err\n");
+    }
+
+    #[test]
+    fn test_system_runner_failure() {
+        let error = sh(
+            "echo 'x: No space left on device' >&2; exit 3",
+            Duration::from_secs(10),
+        )
+        .unwrap_err();
+
+        let ProcessError::Failed { code, kind, .. } = error else {
+            panic!("unexpected error: {error}");
+        };
+        assert_eq!(code, Some(3));
+        assert_eq!(kind, FailureKind::NoSpace);
+    }
+
+    #[test]
+    fn test_system_runner_timeout() {
+        let error = sh("sleep 5", Duration::from_millis(200)).unwrap_err();
+
+        assert!(matches!(error, ProcessError::Timeout { .. }));
+    }
+
+    #[test]
+    fn test_system_runner_not_found() {
+        let command = ProcessCommand::new("definitely-not-installed", Duration::from_secs(1));
+        let error = SystemRunner.run(&command, &mut |_| {}).unwrap_err();
+
+        assert!(matches!(error, ProcessError::NotFound { .. }));
+    }
+}
@@ -0,0 +1,175 @@
+//! Breaks subtitle text into lines that fit on screen.
+
+use unicode_width::UnicodeWidthChar;
+use unicode_width::UnicodeWidthStr;
+
+/// Punctuation that ends a clause, which lines are best broken after
+const CLAUSE_ENDS: &str = ",.;:!?…，。！？；：、";
+
+/// Punctuation that may not start a line
+const CLOSING: &str = "，。！？；：、）」』】〉》…ー";
+
+/// Punctuation that may not end a line
+const OPENING: &str = "（「『【〈《";
+
+/// How to rebreak the lines of subtitle events.
+#[derive(Debug, Clone, Copy)]
+pub struct ReflowOptions {
+    /// Widest a line may be, in columns, where East Asian characters take two
+    pub max_width: usize,
+    pub max_lines: usize,
+    /// Whether events that do not fit in `max_lines` are split into two
+    /// events, each shown for as long as its share of the text
+    pub split_events: bool,
+}
+
+/// Where a line can be broken.
+#[derive(Debug)]
+struct Break {
+    /// Where the line before the break ends
+    end: usize,
+    /// Where the line after the break starts, past any space
+    start: usize,
+    /// Width of the line before the break
+    width: usize,
+    after_clause: bool,
+}
+
+/// Where the text can be broken: at spaces, and between East Asian
+/// characters, which are written without spaces, as long as punctuation is
+/// not left at the start or end of a line.
+fn breaks(text: &str) -> Vec<Break> {
+    let mut breaks = Vec::new();
+    let mut previous: Option<char> = None;
+    for (i, c) in text.char_indices() {
+        let Some(p) = previous else {
+            previous = Some(c);
+            continue;
+        };
+        let wide = |c: char| c.width() == Some(2);
+        let at_space = c == ' ' && p != ' ';
+        let between_wide = p != ' '
+            && c != ' '
+            && (wide(p) || wide(c))
+            && !CLOSING.contains(c)
+            && !OPENING.contains(p);
+        if at_space || between_wide {
+            let start = i + text[i..].len() - text[i..].trim_start().len();
+            breaks.push(Break {
+                end: i,
+                start,
+                width: text[..i].width(),
+                after_clause: CLAUSE_ENDS.contains(p),
+            });
+        }
+        previous = Some(c);
+    }
+    breaks
+}
+
+/// Joins lines back into one, with a space between them unless they meet
+/// between East Asian characters.
+pub fn join_lines(lines: &[String]) -> String {
+    let mut joined = String::new();
+    for line in lines
+        .iter()
+        .map(|line| line.trim())
+        .filter(|l| !l.is_empty())
+    {
+        let wide = |c: Option<char>| c.and_then(|c| c.width()) == Some(2);
+        let between_wide = wide(joined.chars().last()) && wide(line.chars().next());
+        if !joined.is_empty() && !between_wide {
+            joined.push(' ');
+        }
+        joined.push_str(line);
+    }
+    joined
+}
+
+/// How many lines the text needs to fit in `max_width`.
+pub fn lines_needed(text: &str, max_width: usize) -> usize {
+    text.width().div_ceil(max_width.max(1)).max(1)
+}
+
+/// Breaks text on one line into as few lines as fit in `max_width`, but no
+/// more than `max_lines`, in which case lines are wider than they should be.
+/// Lines are kept about as wide as each other, and broken after the end of a
+/// clause where one is near.
+pub fn break_lines(text: &str, max_width: usize, max_lines: usize) -> Vec<String> {
+    let lines = lines_needed(text, max_width).min(max_lines.max(1));
+    break_into(text.trim(), lines, max_width)
+}
+
+/// Breaks text on one line into two halves about as wide as each other.
+pub fn halve(text: &str) -> (String, String) {
+    let mut halves = break_into(text.trim(), 2, usize::MAX).into_iter();
+    let first = halves.next().unwrap_or_default();
+    (first, halves.next().unwrap_or_default())
+}
+
+fn break_into(text: &str, lines: usize, max_width: usize) -> Vec<String> {
+    let width = text.width();
+    if lines <= 1 || width == 0 {
+        return vec![text.to_owned()];
+    }
+    let target = width / lines;
+    let cost = |b: &Break| {
+        let rest = width.saturating_sub(b.width);
+        let mut cost = b.width.abs_diff(target) as i64;
+        if b.width > max_width || rest > max_width.saturating_mul(lines - 1) {
+            cost += width as i64;
+        }
+        if b.after_clause {
+            cost -= (target / 2) as i64;
+        }
+        cost
+    };
+    let Some(best) = breaks(text).into_iter().min_by_key(cost) else {
+        return vec![text.to_owned()];
+    };
+    let mut broken = vec![text[..best.end].to_owned()];
+    broken.extend(break_into(&text[best.start..], lines - 1, max_width));
+    broken
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case("We have to find him.", 42, &["We have to find him."])]
+    #[case(
+        "Curses were springing up like maggots, and nobody knew why.",
+        42,
+        &["Curses were springing up like maggots,", "and nobody knew why."]
+    )]
+    #[case(
+        "If we don't find him before nightfall the curse will spread to the whole town.",
+        42,
+        &["If we don't find him before nightfall", "the curse will spread to the whole town."]
+    )]
+    #[case("诅咒像蛆虫一样不断涌现，没有人知道为什么。", 30, &["诅咒像蛆虫一样不断涌现，", "没有人知道为什么。"])]
+    #[case("我们必须在天黑之前找到他否则诅咒就会蔓延", 30, &["我们必须在天黑之前找", "到他否则诅咒就会蔓延"])]
+    fn test_break_lines(#[case] text: &str, #[case] max_width: usize, #[case] should: &[&str]) {
+        assert_eq!(break_lines(text, max_width, 2), should);
+    }
+
+    #[test]
+    fn test_break_lines_keeps_punctuation_off_line_starts() {
+        let got = break_lines("好。好。好。好。好。好。", 6, 4);
+
+        assert!(got.iter().all(|line| !line.starts_with('。')), "{got:?}");
+    }
+
+    #[rstest]
+    #[case(&["We have", "to find him."], "We have to find him.")]
+    #[case(&["我们必须", "找到他。"], "我们必须找到他。")]
+    #[case(&["我们必须找到他。", "We have to find him."], "我们必须找到他。 We have to find him.")]
+    fn test_join_lines(#[case] lines: &[&str], #[case] should: &str) {
+        let lines: Vec<String> = lines.iter().map(|l| l.to_string()).collect();
+
+        assert_eq!(join_lines(&lines), should);
+    }
+}
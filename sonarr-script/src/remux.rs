@@ -0,0 +1,358 @@
+//! Embeds merged subtitles into the media file itself, for players that
+//! ignore sidecar files.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::Instant;
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::bail;
+use serde::Deserialize;
+use tracing::info;
+
+use crate::atomic;
+use crate::process::CommandRunner;
+use crate::process::ProcessCommand;
+
+/// How long ffprobe may spend probing a media file before it is killed
+const PROBE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How far apart, in seconds, the durations of the original and remuxed
+/// files may be
+const DURATION_TOLERANCE: f64 = 1.0;
+
+/// Where merged subtitles are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputMode {
+    /// Sidecar files next to the media file
+    Sidecar,
+    /// New subtitle streams in the media file
+    Embed,
+    /// Both of the above
+    Both,
+}
+
+impl OutputMode {
+    pub fn sidecars(self) -> bool {
+        matches!(self, Self::Sidecar | Self::Both)
+    }
+
+    pub fn embeds(self) -> bool {
+        matches!(self, Self::Embed | Self::Both)
+    }
+}
+
+/// A subtitle file to add to the media file as a new stream.
+#[derive(Debug, Clone)]
+pub struct EmbeddedSubtitle {
+    pub path: PathBuf,
+    /// ISO 639-2 language of the stream
+    pub language: &'static str,
+    /// Title of the stream. Existing subtitle streams with the same title are
+    /// replaced, so that running again does not pile up streams.
+    pub title: &'static str,
+}
+
+#[derive(Debug, Deserialize)]
+struct Probe {
+    streams: Vec<ProbeStream>,
+    format: ProbeFormat,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProbeStream {
+    index: usize,
+    codec_type: String,
+    #[serde(default)]
+    tags: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ProbeFormat {
+    duration: Option<String>,
+}
+
+impl ProbeStream {
+    fn title(&self) -> Option<&str> {
+        self.tags.get("title").map(String::as_str)
+    }
+}
+
+impl Probe {
+    fn duration(&self) -> Option<f64> {
+        self.format.duration.as_deref()?.parse().ok()
+    }
+}
+
+fn probe(runner: &dyn CommandRunner, media_file: &Path) -> Result<Probe> {
+    let command = ProcessCommand::new("ffprobe", PROBE_TIMEOUT)
+        .args(["-v", "error", "-of", "json", "-show_entries"])
+        .arg("stream=index,codec_type:stream_tags=title,language:format=duration")
+        .arg(media_file);
+    let output = runner
+        .run(&command, &mut |_| {})
+        .context("failed probing media file")?;
+    serde_json::from_str(&output.stdout).context("invalid ffprobe output")
+}
+
+/// Adds the subtitles to the media file as new streams without re-encoding
+/// anything else. The file is remuxed next to the original, checked, and only
+/// then moved over it, so the original is left untouched if anything fails.
+///
+/// If `make_default` is set, the first new stream becomes the default and
+/// every other subtitle stream loses that flag.
+pub fn embed_subtitles(
+    runner: &dyn CommandRunner,
+    media_file: &Path,
+    subtitles: &[EmbeddedSubtitle],
+    make_default: bool,
+    timeout: Duration,
+) -> Result<()> {
+    let format = match media_file
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase())
+        .as_deref()
+    {
+        Some("mkv") => "matroska",
+        Some("mp4" | "m4v") => "mp4",
+        _ => bail!("can only embed subtitles into mkv and mp4 files"),
+    };
+    let original = probe(runner, media_file)?;
+    let replaced = |s: &ProbeStream| {
+        s.codec_type == "subtitle" && subtitles.iter().any(|e| s.title() == Some(e.title))
+    };
+    let kept: Vec<&ProbeStream> = original.streams.iter().filter(|s| !replaced(s)).collect();
+    let kept_subtitles = kept.iter().filter(|s| s.codec_type == "subtitle").count();
+
+    let mut command = ProcessCommand::new("ffmpeg", timeout)
+        .args(["-nostdin", "-y", "-loglevel", "error"])
+        .arg("-i")
+        .arg(media_file);
+    for subtitle in subtitles {
+        command = command.arg("-i").arg(&subtitle.path);
+    }
+    for s in &kept {
+        command = command.args(["-map".to_owned(), format!("0:{}", s.index)]);
+    }
+    for input in 1..=subtitles.len() {
+        command = command.args(["-map".to_owned(), format!("{input}:0")]);
+    }
+    command = command.args(["-c", "copy"]);
+    for (i, subtitle) in subtitles.iter().enumerate() {
+        let stream = kept_subtitles + i;
+        if format == "mp4" {
+            command = command.args([format!("-c:s:{stream}"), "mov_text".to_owned()]);
+        }
+        command = command.args([
+            format!("-metadata:s:s:{stream}"),
+            format!("language={}", subtitle.language),
+            format!("-metadata:s:s:{stream}"),
+            format!("title={}", subtitle.title),
+        ]);
+    }
+    for stream in 0..kept_subtitles + subtitles.len() {
+        let disposition = if stream == kept_subtitles && make_default {
+            Some("default")
+        } else if stream >= kept_subtitles || make_default {
+            Some("0")
+        } else {
+            // Existing streams keep their flags
+            None
+        };
+        if let Some(disposition) = disposition {
+            command = command.args([format!("-disposition:s:{stream}"), disposition.to_owned()]);
+        }
+    }
+    command = command.args(["-f", format]);
+
+    let started = Instant::now();
+    atomic::replace_with(media_file, |remuxed| {
+        runner
+            .run(&command.arg(remuxed), &mut |_| {})
+            .context("failed remuxing media file")?;
+        verify(runner, &original, remuxed, kept.len(), subtitles)?;
+        // The remuxed file should look like the original to whoever manages it
+        if let Ok(metadata) = std::fs::metadata(media_file) {
+            let _ = std::fs::set_permissions(remuxed, metadata.permissions());
+        }
+        Ok(())
+    })?;
+    info!(elapsed = ?started.elapsed(), count = subtitles.len(), "remuxed subtitles into media file");
+    Ok(())
+}
+
+/// Checks that the remuxed file has every stream it should, and is as long as
+/// the original.
+fn verify(
+    runner: &dyn CommandRunner,
+    original: &Probe,
+    remuxed: &Path,
+    kept: usize,
+    subtitles: &[EmbeddedSubtitle],
+) -> Result<()> {
+    let probe = probe(runner, remuxed).context("failed probing remuxed media file")?;
+    if probe.streams.len() != kept + subtitles.len() {
+        bail!(
+            "remuxed media file has {} streams instead of {}",
+            probe.streams.len(),
+            kept + subtitles.len()
+        );
+    }
+    for (s, subtitle) in probe.streams[kept..].iter().zip(subtitles) {
+        if s.codec_type != "subtitle" || s.title() != Some(subtitle.title) {
+            bail!(
+                "remuxed media file lacks subtitle stream {}",
+                subtitle.title
+            );
+        }
+    }
+    if let (Some(original), Some(remuxed)) = (original.duration(), probe.duration())
+        && (original - remuxed).abs() > DURATION_TOLERANCE
+    {
+        bail!("remuxed media file lasts {remuxed}s instead of {original}s");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::FakeRunner;
+    use crate::process::ProcessOutput;
+
+    fn probe_output(streams: &[(&str, Option<&str>)], duration: &str) -> ProcessOutput {
+        let streams: Vec<_> = streams
+            .iter()
+            .enumerate()
+            .map(|(index, (codec_type, title))| {
+                let tags = match title {
+                    Some(title) => serde_json::json!({ "title": title }),
+                    None => serde_json::json!({}),
+                };
+                serde_json::json!({ "index": index, "codec_type": codec_type, "tags": tags })
+            })
+            .collect();
+        ProcessOutput {
+            stdout: serde_json::json!({ "streams": streams, "format": { "duration": duration } })
+                .to_string(),
+            stderr: String::new(),
+        }
+    }
+
+    fn args(command: &ProcessCommand) -> Vec<String> {
+        command
+            .args
+            .iter()
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    const SUBTITLE: EmbeddedSubtitle = EmbeddedSubtitle {
+        path: PathBuf::new(),
+        language: "chi",
+        title: "Chinese (Simplified) / English",
+    };
+
+    #[test]
+    fn test_embed_subtitles() {
+        let dir = tempfile::tempdir().unwrap();
+        let media_file = dir.path().join("episode.mkv");
+        std::fs::write(&media_file, "original").unwrap();
+        // ffmpeg does not run, so the remuxed file is written beforehand
+        std::fs::write(atomic::temp_path(&media_file).unwrap(), "remuxed").unwrap();
+        let subtitle = EmbeddedSubtitle {
+            path: dir.path().join("merged.srt"),
+            ..SUBTITLE
+        };
+        let runner = FakeRunner::new([
+            // A previous run already embedded a stream with the same title
+            Ok(probe_output(
+                &[
+                    ("video", None),
+                    ("subtitle", Some("English")),
+                    ("subtitle", Some(SUBTITLE.title)),
+                ],
+                "1420.0",
+            )),
+            Ok(ProcessOutput::default()),
+            Ok(probe_output(
+                &[
+                    ("video", None),
+                    ("subtitle", Some("English")),
+                    ("subtitle", Some(SUBTITLE.title)),
+                ],
+                "1420.1",
+            )),
+        ]);
+
+        embed_subtitles(
+            &runner,
+            &media_file,
+            &[subtitle],
+            true,
+            Duration::from_secs(1),
+        )
+        .unwrap();
+
+        assert_eq!(std::fs::read_to_string(&media_file).unwrap(), "remuxed");
+        let commands = runner.commands.lock().unwrap();
+        let ffmpeg = args(&commands[1]);
+        let expected = [
+            "-map",
+            "0:0",
+            "-map",
+            "0:1",
+            "-map",
+            "1:0",
+            "-c",
+            "copy",
+            "-metadata:s:s:1",
+            "language=chi",
+            "-metadata:s:s:1",
+            "title=Chinese (Simplified) / English",
+            "-disposition:s:0",
+            "0",
+            "-disposition:s:1",
+            "default",
+            "-f",
+            "matroska",
+        ];
+        assert!(ffmpeg.windows(expected.len()).any(|w| w == expected));
+    }
+
+    #[test]
+    fn test_embed_subtitles_keeps_original_on_failed_verification() {
+        let dir = tempfile::tempdir().unwrap();
+        let media_file = dir.path().join("episode.mp4");
+        std::fs::write(&media_file, "original").unwrap();
+        let remuxed = atomic::temp_path(&media_file).unwrap();
+        std::fs::write(&remuxed, "truncated").unwrap();
+        let runner = FakeRunner::new([
+            Ok(probe_output(&[("video", None)], "1420.0")),
+            Ok(ProcessOutput::default()),
+            Ok(probe_output(
+                &[("video", None), ("subtitle", Some(SUBTITLE.title))],
+                "600.0",
+            )),
+        ]);
+
+        let error = embed_subtitles(
+            &runner,
+            &media_file,
+            &[SUBTITLE],
+            false,
+            Duration::from_secs(1),
+        )
+        .unwrap_err();
+
+        assert!(error.to_string().contains("lasts"), "{error:?}");
+        assert_eq!(std::fs::read_to_string(&media_file).unwrap(), "original");
+        assert!(!remuxed.exists());
+        let ffmpeg = args(&runner.commands.lock().unwrap()[1]);
+        assert!(ffmpeg.windows(2).any(|w| w == ["-c:s:0", "mov_text"]));
+    }
+}
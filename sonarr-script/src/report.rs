@@ -0,0 +1,349 @@
+//! A record of what processing a media file found, chose and wrote, so that
+//! runs can be looked into after the fact rather than pieced together from
+//! logs.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Instant;
+
+use anyhow::Context;
+use anyhow::Result;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::atomic;
+use crate::jobs::now;
+
+/// Name of the report kept in the cache entry of each media file
+pub const REPORT_FILE: &str = "report.json";
+
+/// What processing a media file came to.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ProcessingReport {
+    pub media_file: PathBuf,
+    /// Unix time processing started
+    pub started: u64,
+    /// Whether subtitles extracted by an earlier run were reused, in which
+    /// case streams, languages and cleaning are not reported again
+    pub reused: bool,
+    /// Subtitle streams in the media file
+    pub streams: Vec<StreamReport>,
+    /// Languages the text of the extracted streams turned out to be in
+    pub languages: Vec<LanguageReport>,
+    /// Options that changed which streams and events were kept, such as
+    /// `clear-styles=signs`
+    pub filters: Vec<String>,
+    /// Subtitle files that could be used for each language, best first
+    pub candidates: Vec<CandidateReport>,
+    /// Events removed or changed by each cleaning rule
+    pub cleaning: Vec<CleaningReport>,
+    pub outputs: Vec<PathBuf>,
+    pub timings: Vec<StageTiming>,
+    /// Why processing failed, if it did
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StreamReport {
+    pub stream_id: String,
+    pub language_code: String,
+    pub codec: String,
+    /// Whether the stream was extracted
+    pub selected: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LanguageReport {
+    pub stream_id: String,
+    /// Language the stream is tagged with
+    pub tagged: String,
+    /// Language most of the text is in
+    pub detected: Option<String>,
+    /// Share of the text in that language
+    pub share: Option<f64>,
+    pub confidence: Option<f64>,
+    /// What became of the stream, such as `keep` or `relabel as zh`
+    pub decision: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CandidateReport {
+    pub language_code: String,
+    /// Subtitle files in the language, the one used first
+    pub ranked: Vec<PathBuf>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CleaningReport {
+    pub file: PathBuf,
+    pub rule: String,
+    pub events: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StageTiming {
+    pub stage: String,
+    pub millis: u64,
+}
+
+impl ProcessingReport {
+    pub fn new(media_file: &Path) -> Self {
+        Self {
+            media_file: media_file.to_owned(),
+            started: now(),
+            ..Self::default()
+        }
+    }
+
+    /// Records how long a stage took since it started.
+    pub fn timed(&mut self, stage: &str, started: Instant) {
+        self.timings.push(StageTiming {
+            stage: stage.to_owned(),
+            millis: started.elapsed().as_millis() as u64,
+        });
+    }
+
+    /// Records the events a cleaning rule removed or changed, if any.
+    pub fn cleaned(&mut self, file: &Path, rule: &str, events: usize) {
+        if events == 0 {
+            return;
+        }
+        self.cleaning.push(CleaningReport {
+            file: file.to_owned(),
+            rule: rule.to_owned(),
+            events,
+        });
+    }
+
+    /// Keeps the report in the cache entry, replacing the last one.
+    pub fn save(&self, entry_dir: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+        atomic::write(&entry_dir.join(REPORT_FILE), json)
+    }
+
+    /// Writes the report as a line of JSON to stdout if `output` is `-`, or
+    /// appends it to the file otherwise, so that a file can collect the
+    /// reports of a whole library.
+    pub fn emit(&self, output: &Path) -> Result<()> {
+        let line = serde_json::to_string(self)? + "\n";
+        if output == Path::new("-") {
+            std::io::stdout().write_all(line.as_bytes())?;
+            return Ok(());
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(output)
+            .and_then(|mut file| file.write_all(line.as_bytes()))
+            .with_context(|| format!("failed writing report to {}", output.display()))
+    }
+
+    /// Reads the report kept in a cache entry.
+    pub fn load(entry_dir: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(entry_dir.join(REPORT_FILE))?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    /// Reads the reports in a file of JSON lines, as written by `emit`.
+    pub fn load_lines(path: &Path) -> Result<Vec<Self>> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed reading reports from {}", path.display()))?;
+        content
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| Ok(serde_json::from_str(line)?))
+            .collect()
+    }
+}
+
+/// What the reports of a library add up to.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ReportSummary {
+    pub media_files: usize,
+    /// Media files whose subtitles were reused from an earlier run
+    pub reused: usize,
+    /// Media files processed without anything written, such as ones without
+    /// English subtitles
+    pub without_outputs: usize,
+    pub outputs: usize,
+    /// Media files with a subtitle file in each language
+    pub languages: BTreeMap<String, usize>,
+    /// Streams whose language tag was corrected or distrusted, by decision
+    pub decisions: BTreeMap<String, usize>,
+    /// Events removed or changed, by cleaning rule
+    pub cleaning: BTreeMap<String, usize>,
+    /// Mean time taken by each stage, in milliseconds
+    pub mean_millis: BTreeMap<String, u64>,
+    /// Media files that failed, with why
+    pub failed: Vec<(PathBuf, String)>,
+}
+
+impl ReportSummary {
+    /// Summarizes the latest report of every media file.
+    pub fn new(reports: impl IntoIterator<Item = ProcessingReport>) -> Self {
+        let mut latest: HashMap<PathBuf, ProcessingReport> = HashMap::new();
+        for report in reports {
+            match latest.get(&report.media_file) {
+                Some(seen) if seen.started > report.started => {}
+                _ => {
+                    latest.insert(report.media_file.clone(), report);
+                }
+            }
+        }
+        let mut reports: Vec<ProcessingReport> = latest.into_values().collect();
+        reports.sort_by(|a, b| a.media_file.cmp(&b.media_file));
+
+        let mut summary = Self::default();
+        let mut timings: BTreeMap<String, (u64, u64)> = BTreeMap::new();
+        for report in reports {
+            summary.media_files += 1;
+            summary.reused += usize::from(report.reused);
+            summary.outputs += report.outputs.len();
+            for candidate in &report.candidates {
+                *summary
+                    .languages
+                    .entry(candidate.language_code.clone())
+                    .or_default() += 1;
+            }
+            for language in &report.languages {
+                if language.decision != "keep" {
+                    *summary
+                        .decisions
+                        .entry(language.decision.clone())
+                        .or_default() += 1;
+                }
+            }
+            for cleaning in &report.cleaning {
+                *summary.cleaning.entry(cleaning.rule.clone()).or_default() += cleaning.events;
+            }
+            for timing in &report.timings {
+                let (total, count) = timings.entry(timing.stage.clone()).or_default();
+                *total += timing.millis;
+                *count += 1;
+            }
+            match report.error {
+                Some(error) => summary.failed.push((report.media_file, error)),
+                None if report.outputs.is_empty() => summary.without_outputs += 1,
+                None => {}
+            }
+        }
+        summary.mean_millis = timings
+            .into_iter()
+            .map(|(stage, (total, count))| (stage, total / count))
+            .collect();
+        summary
+    }
+}
+
+impl fmt::Display for ReportSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} media files", self.media_files)?;
+        writeln!(f, "  {} failed", self.failed.len())?;
+        writeln!(f, "  {} without anything written", self.without_outputs)?;
+        writeln!(f, "  {} reusing earlier extractions", self.reused)?;
+        writeln!(f, "{} subtitle files written", self.outputs)?;
+        let sections = [
+            ("Subtitles found, by language", &self.languages),
+            ("Streams with distrusted tags, by decision", &self.decisions),
+            ("Events cleaned, by rule", &self.cleaning),
+        ];
+        for (title, counts) in sections {
+            if counts.is_empty() {
+                continue;
+            }
+            writeln!(f, "{title}:")?;
+            for (key, count) in counts {
+                writeln!(f, "  {key}: {count}")?;
+            }
+        }
+        if !self.mean_millis.is_empty() {
+            writeln!(f, "Mean time, by stage:")?;
+            for (stage, millis) in &self.mean_millis {
+                writeln!(f, "  {stage}: {millis}ms")?;
+            }
+        }
+        if !self.failed.is_empty() {
+            writeln!(f, "Failed:")?;
+            for (media_file, error) in &self.failed {
+                writeln!(f, "  {}: {error}", media_file.display())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(media_file: &str, started: u64) -> ProcessingReport {
+        ProcessingReport {
+            media_file: media_file.into(),
+            started,
+            ..ProcessingReport::default()
+        }
+    }
+
+    #[test]
+    fn test_summary() {
+        let mut done = report("/tv/a.mkv", 2);
+        done.outputs = vec!["/tv/a.en.srt".into(), "/tv/a.zh.srt".into()];
+        done.candidates = vec![CandidateReport {
+            language_code: "zh".to_owned(),
+            ranked: vec!["0_3.zh.ass".into()],
+        }];
+        done.cleaning = vec![CleaningReport {
+            file: "0_3.zh.ass".into(),
+            rule: "clear-styles".to_owned(),
+            events: 12,
+        }];
+        done.timings = vec![StageTiming {
+            stage: "extract".to_owned(),
+            millis: 300,
+        }];
+        let mut failed = report("/tv/a.mkv", 1);
+        failed.error = Some("failed probing /tv/a.mkv".to_owned());
+        let mut relabelled = report("/tv/b.mkv", 1);
+        relabelled.languages = vec![LanguageReport {
+            stream_id: "0:2".to_owned(),
+            tagged: "und".to_owned(),
+            detected: Some("en".to_owned()),
+            share: Some(0.9),
+            confidence: Some(0.8),
+            decision: "relabel as en".to_owned(),
+        }];
+        relabelled.timings = vec![StageTiming {
+            stage: "extract".to_owned(),
+            millis: 100,
+        }];
+
+        let summary = ReportSummary::new([failed, done, relabelled]);
+
+        // The earlier failure of a.mkv was superseded
+        assert_eq!(summary.media_files, 2);
+        assert!(summary.failed.is_empty());
+        assert_eq!(summary.without_outputs, 1);
+        assert_eq!(summary.outputs, 2);
+        assert_eq!(summary.languages["zh"], 1);
+        assert_eq!(summary.decisions["relabel as en"], 1);
+        assert_eq!(summary.cleaning["clear-styles"], 12);
+        assert_eq!(summary.mean_millis["extract"], 200);
+    }
+
+    #[test]
+    fn test_emit_appends_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("reports.jsonl");
+
+        report("/tv/a.mkv", 1).emit(&path).unwrap();
+        report("/tv/b.mkv", 2).emit(&path).unwrap();
+
+        let got = ProcessingReport::load_lines(&path).unwrap();
+        assert_eq!(got, [report("/tv/a.mkv", 1), report("/tv/b.mkv", 2)]);
+    }
+}
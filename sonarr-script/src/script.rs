@@ -0,0 +1,51 @@
+//! Tells apart the scripts East Asian text is written in.
+
+/// Scripts a CJK subtitle track can be written in, which tell its language
+/// apart more reliably than language detection does on short lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CjkScript {
+    SimplifiedChinese,
+    TraditionalChinese,
+    /// Kana, usually mixed with kanji
+    Japanese,
+    /// Hangul
+    Korean,
+}
+
+impl CjkScript {
+    /// The language code the pipeline uses for text in the script.
+    pub fn language_code(self) -> &'static str {
+        match self {
+            Self::SimplifiedChinese => "zh",
+            Self::TraditionalChinese => "zh-TW",
+            Self::Japanese => "ja",
+            Self::Korean => "ko",
+        }
+    }
+}
+
+/// Chinese characters, also used as Japanese kanji and Korean hanja.
+pub fn is_han(c: char) -> bool {
+    matches!(c,
+        '\u{3400}'..='\u{4DBF}' | '\u{4E00}'..='\u{9FFF}' // Ideographs
+        | '\u{F900}'..='\u{FAFF}' // Compatibility ideographs
+        | '\u{20000}'..='\u{2FA1F}' // Supplementary ideographs
+    )
+}
+
+/// Hiragana and katakana, which only Japanese is written in.
+pub fn is_kana(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{309F}' // Hiragana
+        | '\u{30A0}'..='\u{30FF}' | '\u{31F0}'..='\u{31FF}' // Katakana
+        | '\u{FF66}'..='\u{FF9F}' // Half width katakana
+    )
+}
+
+/// Hangul, which only Korean is written in.
+pub fn is_hangul(c: char) -> bool {
+    matches!(c,
+        '\u{1100}'..='\u{11FF}' | '\u{3130}'..='\u{318F}' // Jamo
+        | '\u{AC00}'..='\u{D7AF}' // Syllables
+    )
+}
@@ -1,21 +1,49 @@
+//! Subtitle tracks, loaded from any format into ASS, and the cleaning rules
+//! applied to them.
+
+use std::collections::BTreeSet;
 use std::collections::HashSet;
+use std::fmt::Write;
 use std::path::Path;
+use std::str::FromStr;
 
 use anyhow::Context;
-use anyhow::Result;
 use aspasia::AssSubtitle;
+use aspasia::Moment;
 use aspasia::SubRipSubtitle;
 use aspasia::Subtitle;
 use aspasia::TextEventInterface;
 use aspasia::TextSubtitle;
 use aspasia::TimedSubtitleFile;
+use aspasia::WebVttSubtitle;
+use aspasia::substation::ass::AssEvent;
 use counter::Counter;
 pub use lingua::Language;
-use lingua::LanguageDetectorBuilder;
+use regex::Regex;
+
+use crate::atomic;
+use crate::detect::LanguageDetection;
+use crate::detect::LanguageDistribution;
+use crate::error::Error;
+use crate::error::Result;
+use crate::metrics::display_width;
+use crate::metrics::visible_lines;
+use crate::metrics::visible_text;
+use crate::pinyin::AnnotationLayout;
+use crate::pinyin::PinyinAnnotation;
+use crate::reflow;
+use crate::reflow::ReflowOptions;
+use crate::script::CjkScript;
+use crate::script::is_han;
+use crate::script::is_hangul;
+use crate::script::is_kana;
 
 // TODO: Clear very short events
 // TODO: Clear drawings containing \\p\d (ie, \p1)
-// TODO: Clear .ass styles by name (ie, signs)
+
+/// Share of a track's CJK events that need kana or hangul in them for the
+/// track to be taken to be Japanese or Korean
+const MIN_SCRIPT_SHARE: f64 = 0.2;
 
 pub struct SubtitleTrack {
     inner: AssSubtitle,
@@ -25,23 +53,59 @@ impl SubtitleTrack {
     /// Loads a subtitle track from a file. It will automatically be converted
     /// into ASS in memory.
     pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
         let inner = TimedSubtitleFile::new(path)
-            .context("Failed loading subtitle file")?
+            .map_err(|error| Error::parse(path, error))?
             .into();
         Ok(Self { inner })
     }
 
+    /// The track's events, in file order.
+    pub fn events(&self) -> &[AssEvent] {
+        self.inner.events()
+    }
+
     /// Saves subtitle track to an ASS file.
     pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
-        self.inner
-            .export(path)
-            .context("Failed saving subtitle file")
+        let path = path.as_ref();
+        atomic::replace_with(path, |tmp| Ok(self.inner.export(tmp)?))
+            .map_err(|source| Error::write(path, source))
     }
 
-    /// Saves subtitle track to an SRT file.
-    pub fn save_srt(self, path: impl AsRef<Path>) -> Result<()> {
-        let srt: SubRipSubtitle = self.inner.into();
-        srt.export(path).context("Failed saving subtitle file")
+    /// Saves subtitle track to an SRT file. Events whose text was cleared are
+    /// left out.
+    pub fn save_srt(&self, path: impl AsRef<Path>) -> Result<()> {
+        let srt = SubRipSubtitle::from(&self.inner);
+        let events = srt
+            .events()
+            .iter()
+            .filter(|event| !event.text.is_empty())
+            .cloned()
+            .collect();
+        let mut srt = SubRipSubtitle::from_events(events);
+        srt.renumber();
+        let path = path.as_ref();
+        atomic::replace_with(path, |tmp| Ok(srt.export(tmp)?))
+            .map_err(|source| Error::write(path, source))
+    }
+
+    /// Saves subtitle track to a WebVTT file.
+    pub fn save_vtt(&self, path: impl AsRef<Path>) -> Result<()> {
+        let vtt = WebVttSubtitle::from(&self.inner);
+        let path = path.as_ref();
+        atomic::replace_with(path, |tmp| Ok(vtt.export(tmp)?))
+            .map_err(|source| Error::write(path, source))
+    }
+
+    /// Saves subtitle track in the format matching the file extension, which
+    /// is ASS unless it is `.srt` or `.vtt`.
+    pub fn save_as(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("srt") => self.save_srt(path),
+            Some("vtt") => self.save_vtt(path),
+            _ => self.save(path),
+        }
     }
 
     /// Detects if the subtitle text events contain traditional Chinese
@@ -51,18 +115,101 @@ impl SubtitleTrack {
         self.inner.events().iter().any(|event| f(&event.text))
     }
 
-    /// Detects the most frequently occurring language by events in the
-    /// subtitle track.
-    pub fn detect_predominant_language(&self, languages: &[Language]) -> Option<Language> {
-        let detector = LanguageDetectorBuilder::from_languages(languages).build();
-        let f = |text: &str| detector.detect_language_of(text);
-        let languages: Counter<_> = self
+    /// Detects the CJK script the track is written in. Japanese and Korean
+    /// are told by the share of events with kana or hangul in them, since
+    /// their lines can be all Chinese characters too. None if the track has
+    /// no CJK text.
+    pub fn detect_cjk_script(&self) -> Option<CjkScript> {
+        let (mut cjk, mut kana, mut hangul) = (0, 0, 0);
+        for event in self.inner.events() {
+            let text = visible_text(&event.text);
+            let has = |f: fn(char) -> bool| text.chars().any(f);
+            let (has_kana, has_hangul) = (has(is_kana), has(is_hangul));
+            if !(has_kana || has_hangul || has(is_han)) {
+                continue;
+            }
+            cjk += 1;
+            kana += usize::from(has_kana);
+            hangul += usize::from(has_hangul);
+        }
+        if cjk == 0 {
+            return None;
+        }
+        let enough = |count: usize| count as f64 >= MIN_SCRIPT_SHARE * cjk as f64;
+        let script = if enough(hangul) && hangul >= kana {
+            CjkScript::Korean
+        } else if enough(kana) {
+            CjkScript::Japanese
+        } else if self.detect_chinese_traditional() {
+            CjkScript::TraditionalChinese
+        } else {
+            CjkScript::SimplifiedChinese
+        };
+        Some(script)
+    }
+
+    /// The languages the track's events are in. Formatting is ignored.
+    pub fn detect_languages(&self, detection: &LanguageDetection) -> LanguageDistribution {
+        let texts: Vec<String> = self
             .inner
             .events()
             .iter()
-            .map(|event| f(&event.text))
+            .map(|event| visible_text(&event.text))
             .collect();
-        languages.k_most_common_ordered(1).first()?.0
+        detection.distribution(texts.iter().map(String::as_str))
+    }
+
+    /// Splits a track with several languages in it, such as a merged
+    /// "chs&eng" fansub track, into one track per language. Events are split
+    /// line by line, since such tracks often put both languages in one event.
+    /// Lines whose language cannot be told go with the other lines of
+    /// their event, or to every track if the event has no other lines.
+    pub fn split_languages(
+        &self,
+        detection: &LanguageDetection,
+    ) -> Result<Vec<(Language, SubtitleTrack)>> {
+        let events: Vec<Vec<(Option<Language>, &str)>> = self
+            .inner
+            .events()
+            .iter()
+            .map(|event| {
+                event
+                    .text
+                    .split("\\N")
+                    .map(|line| {
+                        let language = detection.detect(&visible_text(line));
+                        (language.map(|(language, _)| language), line)
+                    })
+                    .collect()
+            })
+            .collect();
+        let languages: BTreeSet<Language> = events
+            .iter()
+            .flatten()
+            .filter_map(|(language, _)| *language)
+            .collect();
+
+        let mut tracks = Vec::new();
+        for language in languages {
+            // Styles and fonts are kept, so the parts still render the same
+            let mut inner = AssSubtitle::from_str(&self.inner.to_string())
+                .context("Failed copying subtitle track")?;
+            for (event, lines) in inner.events_mut().iter_mut().zip(&events) {
+                let has_language = lines.iter().any(|(l, _)| *l == Some(language));
+                let has_none = lines.iter().all(|(l, _)| l.is_none());
+                let text: Vec<&str> = lines
+                    .iter()
+                    .filter(|(l, _)| match l {
+                        Some(l) => *l == language,
+                        None => has_language || has_none,
+                    })
+                    .map(|(_, line)| *line)
+                    .collect();
+                event.set_text(text.join("\\N"));
+            }
+            tracks.push((language, Self { inner }));
+        }
+        Ok(tracks)
     }
 
     /// Removes formatting directives and styles.
@@ -70,29 +217,227 @@ impl SubtitleTrack {
         self.inner.strip_formatting();
     }
 
-    /// Sets the text of lines longer than some threshold to the empty string.
-    pub fn clear_long_lines(&mut self, max_chars: usize) {
-        self.inner.events_mut().iter_mut().for_each(|event| {
-            if event.text.len() > max_chars {
+    /// Removes furigana written inline after kanji, such as `漢字（かんじ）`.
+    /// Returns the number of events changed.
+    pub fn strip_furigana(&mut self) -> usize {
+        let furigana =
+            Regex::new(r"(\p{Han})[（(][\p{Hiragana}\p{Katakana}ー・]+[）)]").expect("valid regex");
+        let mut changed = 0;
+        for event in self.inner.events_mut() {
+            if let std::borrow::Cow::Owned(text) = furigana.replace_all(&event.text, "$1") {
+                event.set_text(text);
+                changed += 1;
+            }
+        }
+        changed
+    }
+
+    /// Removes lines in Latin letters without any Japanese in them, such as
+    /// romaji song lyrics. Only meant for Japanese tracks, where such lines
+    /// are transliterations. Returns the number of events changed.
+    pub fn strip_romaji(&mut self) -> usize {
+        let mut changed = 0;
+        for event in self.inner.events_mut() {
+            let lines: Vec<&str> = event
+                .text
+                .split("\\N")
+                .filter(|line| {
+                    let line = visible_text(line);
+                    let latin = line.chars().any(|c| c.is_ascii_alphabetic());
+                    !latin || line.chars().any(|c| is_han(c) || is_kana(c))
+                })
+                .collect();
+            let text = lines.join("\\N");
+            if text != event.text {
+                event.set_text(text);
+                changed += 1;
+            }
+        }
+        changed
+    }
+
+    /// Sets the text of events wider on screen than some threshold to the
+    /// empty string. Override tags and line breaks are not counted, and East
+    /// Asian characters count twice, as they are twice as wide as Latin ones.
+    /// Returns the number of events cleared.
+    pub fn clear_long_lines(&mut self, max_width: usize) -> usize {
+        let mut cleared = 0;
+        for event in self.inner.events_mut() {
+            if display_width(&event.text) > max_width {
                 event.set_text(String::default());
+                cleared += 1;
             }
-        })
+        }
+        cleared
+    }
+
+    /// Rebreaks the lines of events to fit on screen, splitting events that
+    /// do not fit into two if asked to. Override tags at the start of an
+    /// event are kept, while events with tags further in are left alone, as
+    /// their tags could end up on the wrong line.
+    pub fn reflow(&mut self, options: &ReflowOptions) -> Result<()> {
+        let mut added = Vec::new();
+        for (i, event) in self.inner.events_mut().iter_mut().enumerate() {
+            let (tags, body) = event.text.split_at(leading_tags_len(&event.text));
+            if body.contains('{') {
+                continue;
+            }
+            let tags = tags.to_owned();
+            let text = reflow::join_lines(&visible_lines(body));
+            if text.is_empty() {
+                continue;
+            }
+            let break_lines = |text: &str| {
+                let lines = reflow::break_lines(text, options.max_width, options.max_lines);
+                format!("{tags}{}", lines.join("\\N"))
+            };
+
+            if !options.split_events
+                || reflow::lines_needed(&text, options.max_width) <= options.max_lines
+            {
+                event.set_text(break_lines(&text));
+                continue;
+            }
+            let (first, second) = reflow::halve(&text);
+            let (start, end) = (i64::from(event.start), i64::from(event.end));
+            let first_width = display_width(&first) as f64;
+            let share = first_width / (first_width + display_width(&second) as f64);
+            let middle = Moment::from(start + ((end - start) as f64 * share).round() as i64);
+            added.push((
+                i,
+                AssEvent {
+                    kind: event.kind,
+                    layer: event.layer,
+                    start: middle,
+                    end: event.end,
+                    style: event.style.clone(),
+                    name: event.name.clone(),
+                    margin_l: event.margin_l,
+                    margin_r: event.margin_r,
+                    margin_v: event.margin_v,
+                    effect: event.effect.clone(),
+                    text: break_lines(&second),
+                },
+            ));
+            event.end = middle;
+            event.set_text(break_lines(&first));
+        }
+        if !added.is_empty() {
+            self.insert_events(added)?;
+        }
+        Ok(())
+    }
+
+    /// Adds the reading of the Chinese in each event, either as a line above
+    /// each line of Chinese or as an italic event of its own on a layer
+    /// above. Events with override tags past their start are left alone.
+    pub fn annotate(&mut self, annotation: &PinyinAnnotation) -> Result<()> {
+        let mut added = Vec::new();
+        for (i, event) in self.inner.events_mut().iter_mut().enumerate() {
+            let (tags, body) = event.text.split_at(leading_tags_len(&event.text));
+            if body.contains('{') {
+                continue;
+            }
+            let lines = visible_lines(body);
+            let readings: Vec<Option<String>> =
+                lines.iter().map(|line| annotation.reading(line)).collect();
+            if readings.iter().all(Option::is_none) {
+                continue;
+            }
+            let tags = tags.to_owned();
+
+            match annotation.layout {
+                AnnotationLayout::Line => {
+                    let annotated: Vec<&str> = lines
+                        .iter()
+                        .zip(&readings)
+                        .flat_map(|(line, reading)| {
+                            reading.as_deref().into_iter().chain([line.as_str()])
+                        })
+                        .collect();
+                    event.set_text(format!("{tags}{}", annotated.join("\\N")));
+                }
+                AnnotationLayout::Layer => {
+                    let readings: Vec<String> = readings.into_iter().flatten().collect();
+                    added.push((
+                        i,
+                        AssEvent {
+                            kind: event.kind,
+                            layer: event.layer + 1,
+                            start: event.start,
+                            end: event.end,
+                            style: event.style.clone(),
+                            name: event.name.clone(),
+                            margin_l: event.margin_l,
+                            margin_r: event.margin_r,
+                            margin_v: event.margin_v,
+                            effect: event.effect.clone(),
+                            text: format!("{tags}{{\\i1}}{}", readings.join("\\N")),
+                        },
+                    ));
+                }
+            }
+        }
+        if !added.is_empty() {
+            self.insert_events(added)?;
+        }
+        Ok(())
+    }
+
+    /// Inserts each event right after the event at its index. aspasia cannot
+    /// add events to a parsed subtitle, so the track is written out with the
+    /// events in place and parsed again.
+    fn insert_events(&mut self, added: Vec<(usize, AssEvent)>) -> anyhow::Result<()> {
+        let text = self.inner.to_string();
+        let events_start = text
+            .find("[Events]")
+            .context("Subtitle track has no events section")?;
+        let mut rebuilt = text[..events_start].to_owned();
+        rebuilt.push_str("[Events]\n");
+        rebuilt.push_str(
+            "Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text\n",
+        );
+        let mut added = added.into_iter().peekable();
+        for (i, event) in self.inner.events().iter().enumerate() {
+            writeln!(rebuilt, "{event}")?;
+            while let Some((_, event)) = added.next_if(|(after, _)| *after == i) {
+                writeln!(rebuilt, "{event}")?;
+            }
+        }
+        let others = self
+            .inner
+            .pictures()
+            .iter()
+            .chain(self.inner.sounds())
+            .chain(self.inner.movies())
+            .chain(self.inner.commands());
+        for event in others {
+            writeln!(rebuilt, "{event}")?;
+        }
+        self.inner = AssSubtitle::from_str(&rebuilt).context("Failed rebuilding subtitle track")?;
+        Ok(())
     }
 
     /// Sets the text of events with rejected styles names to the empty string.
-    pub fn clear_events_with_styles(&mut self, style_names: HashSet<String>) {
+    /// Returns the number of events cleared.
+    pub fn clear_events_with_styles(&mut self, style_names: &HashSet<String>) -> usize {
+        let mut cleared = 0;
         for event in self.inner.events_mut() {
             if let Some(style) = &event.style
                 && style_names.contains(&style.to_lowercase())
+                && !event.text.is_empty()
             {
                 event.set_text(String::default());
+                cleared += 1;
             }
         }
+        cleared
     }
 
     /// Assumes that other rules have caught pretty offending style names, and
-    /// so rejects the rest of their events too.
-    pub fn clear_events_whose_style_has_many_existing_blanks(&mut self) {
+    /// so rejects the rest of their events too. Returns the number of events
+    /// cleared.
+    pub fn clear_events_whose_style_has_many_existing_blanks(&mut self) -> usize {
         let styles_by_blanks: Counter<_> = self
             .inner
             .events()
@@ -100,6 +445,7 @@ impl SubtitleTrack {
             .filter(|event| event.text.is_empty())
             .filter_map(|event| event.style.to_owned())
             .collect();
+        let mut cleared = 0;
         for event in self.inner.events_mut() {
             let Some(style) = &event.style else {
                 continue;
@@ -107,10 +453,30 @@ impl SubtitleTrack {
             let Some(blanks) = styles_by_blanks.get(style) else {
                 continue;
             };
-            if *blanks > 20 {
+            if *blanks > 20 && !event.text.is_empty() {
                 event.set_text(String::default());
+                cleared += 1;
             }
         }
+        cleared
+    }
+}
+
+/// Length of the override blocks at the start of the text, such as `{\an8}`.
+fn leading_tags_len(text: &str) -> usize {
+    let mut len = 0;
+    while text[len..].starts_with('{') {
+        let Some(close) = text[len..].find('}') else {
+            break;
+        };
+        len += close + 1;
+    }
+    len
+}
+
+impl From<AssSubtitle> for SubtitleTrack {
+    fn from(inner: AssSubtitle) -> Self {
+        Self { inner }
     }
 }
 
@@ -118,27 +484,34 @@ impl SubtitleTrack {
 mod tests {
     use std::path::PathBuf;
     use std::str::FromStr;
+    use std::sync::Arc;
 
     use rstest::rstest;
 
     use super::*;
+    use crate::detect::DEFAULT_MIN_CONFIDENCE;
+    use crate::detect::DEFAULT_MIN_LETTERS;
+    use crate::pinyin::PinyinDictionary;
+    use crate::pinyin::Romanization;
+
+    const ASS_HEADER: &str = "[Script Info]
+ScriptType: v4.00+
+
+[Events]
+Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text
+";
 
     #[rstest]
     #[case("../test/jjk_s02e01/extracted.en.ass", Language::English)]
     #[case("../test/jjk_s02e01/extracted.zh.ass", Language::Chinese)]
     #[case("../test/jjk_s02e01/extracted.zh-TW.ass", Language::Chinese)]
-    fn test_detect_predominant_language(
-        #[case] path: &str,
-        #[case] language_should: impl Into<Option<Language>>,
-    ) {
-        let path = PathBuf::from_str(path).unwrap();
-        let language_should = language_should.into();
-
-        let subtitle = SubtitleTrack::load(&path).unwrap();
-        let language =
-            subtitle.detect_predominant_language(&[Language::English, Language::Chinese]);
+    fn test_detect_languages(#[case] path: &str, #[case] language_should: Language) {
+        let subtitle = SubtitleTrack::load(Path::new(path)).unwrap();
+        let distribution = subtitle.detect_languages(&LanguageDetection::default());
 
-        assert_eq!(language, language_should);
+        let top = distribution.top().unwrap();
+        assert_eq!(top.language, language_should);
+        assert!(top.share > 0.6, "{distribution:?}");
     }
 
     #[rstest]
@@ -154,6 +527,56 @@ mod tests {
         assert_eq!(got, should);
     }
 
+    #[rstest]
+    #[case("../test/jjk_s02e01/extracted.en.ass", None)]
+    #[case(
+        "../test/jjk_s02e01/extracted.zh.ass",
+        Some(CjkScript::SimplifiedChinese)
+    )]
+    #[case(
+        "../test/jjk_s02e01/extracted.zh-TW.ass",
+        Some(CjkScript::TraditionalChinese)
+    )]
+    #[case("../test/cjk/ja.srt", Some(CjkScript::Japanese))]
+    #[case("../test/cjk/ko.srt", Some(CjkScript::Korean))]
+    fn test_detect_cjk_script(#[case] path: &str, #[case] should: Option<CjkScript>) {
+        let subtitle = SubtitleTrack::load(Path::new(path)).unwrap();
+
+        assert_eq!(subtitle.detect_cjk_script(), should);
+    }
+
+    #[test]
+    fn test_strip_readings() {
+        let mut subtitle = SubtitleTrack::load("../test/cjk/ja.srt").unwrap();
+
+        subtitle.strip_furigana();
+        subtitle.strip_romaji();
+
+        let texts: Vec<&str> = subtitle.events().iter().map(|e| e.text.as_str()).collect();
+        assert_eq!(
+            texts,
+            [
+                "呪霊が蛆虫のように湧いていた。",
+                "彼を見つけなければならない。",
+                "♪ 夢を見ていた",
+                "五条先生",
+            ]
+        );
+    }
+
+    #[rstest]
+    #[case("We have to find him.", 30, false)]
+    #[case("我们必须找到他，不然就来不及了。", 30, true)]
+    fn test_clear_long_lines(#[case] text: &str, #[case] max_width: usize, #[case] cleared: bool) {
+        let srt = format!("1\n00:00:01,000 --> 00:00:03,000\n{text}\n");
+        let mut subtitle =
+            SubtitleTrack::from(AssSubtitle::from(SubRipSubtitle::from_str(&srt).unwrap()));
+
+        subtitle.clear_long_lines(max_width);
+
+        assert_eq!(subtitle.events()[0].text.is_empty(), cleared);
+    }
+
     #[rstest]
     #[case("../test/jjk_s02e01/extracted.en.ass")]
     #[case("../test/jjk_s02e01/extracted.zh.ass")]
@@ -169,4 +592,104 @@ mod tests {
         subtitle.clear_events_whose_style_has_many_existing_blanks();
         subtitle.save(&out).unwrap();
     }
+
+    #[rstest]
+    #[case(2, false, &[(1000, 5000, r"{\an8}Curses were springing up like maggots,\Nand nobody knew why.")])]
+    #[case(1, true, &[
+        (1000, 3620, r"{\an8}Curses were springing up like maggots,"),
+        (3620, 5000, r"{\an8}and nobody knew why."),
+    ])]
+    fn test_reflow(
+        #[case] max_lines: usize,
+        #[case] split_events: bool,
+        #[case] should: &[(i64, i64, &str)],
+    ) {
+        let ass = ASS_HEADER.to_owned()
+            + r"Dialogue: 0,0:00:01.00,0:00:05.00,Default,,0,0,0,,{\an8}Curses were springing\Nup like maggots, and nobody knew why.";
+        let mut subtitle = SubtitleTrack::from(AssSubtitle::from_str(&ass).unwrap());
+        let options = ReflowOptions {
+            max_width: 42,
+            max_lines,
+            split_events,
+        };
+
+        subtitle.reflow(&options).unwrap();
+
+        let got: Vec<(i64, i64, &str)> = subtitle
+            .events()
+            .iter()
+            .map(|e| (i64::from(e.start), i64::from(e.end), e.text.as_str()))
+            .collect();
+        assert_eq!(got, should);
+    }
+
+    #[rstest]
+    #[case(AnnotationLayout::Line, &[r"{\an8}wǒmen bìxū\N我们必须\Nzhǎodào tā.\N找到他。"])]
+    #[case(AnnotationLayout::Layer, &[r"{\an8}我们必须\N找到他。", r"{\an8}{\i1}wǒmen bìxū\Nzhǎodào tā."])]
+    fn test_annotate(#[case] layout: AnnotationLayout, #[case] should: &[&str]) {
+        let ass = ASS_HEADER.to_owned()
+            + "Dialogue: 0,0:00:01.00,0:00:05.00,Default,,0,0,0,,{\\an8}我们必须\\N找到他。\n"
+            + "Dialogue: 0,0:00:06.00,0:00:07.00,Default,,0,0,0,,We have to find him.";
+        let mut subtitle = SubtitleTrack::from(AssSubtitle::from_str(&ass).unwrap());
+        let dictionary = PinyinDictionary::parse(
+            "我們 我们 [wo3 men5] /we/\n必須 必须 [bi4 xu1] /must/\n找到 找到 [zhao3 dao4] /to find/\n他 他 [ta1] /he/",
+        );
+        let annotation = PinyinAnnotation {
+            dictionary: Arc::new(dictionary),
+            romanization: Romanization::ToneMarks,
+            layout,
+        };
+
+        subtitle.annotate(&annotation).unwrap();
+
+        let got: Vec<&str> = subtitle.events().iter().map(|e| e.text.as_str()).collect();
+        let (english, chinese) = got.split_last().unwrap();
+        assert_eq!(chinese, should);
+        assert_eq!(*english, "We have to find him.");
+    }
+
+    #[test]
+    fn test_split_languages() {
+        let track = SubtitleTrack::load("../test/mixed/chs_eng.ass").unwrap();
+        let detection = LanguageDetection::new(
+            &[Language::English, Language::Chinese],
+            DEFAULT_MIN_LETTERS,
+            DEFAULT_MIN_CONFIDENCE,
+        )
+        .unwrap();
+
+        let got: Vec<(Language, Vec<String>)> = track
+            .split_languages(&detection)
+            .unwrap()
+            .into_iter()
+            .map(|(language, track)| {
+                let texts = track.events().iter().map(|e| e.text.clone()).collect();
+                (language, texts)
+            })
+            .collect();
+
+        assert_eq!(
+            got,
+            [
+                (
+                    Language::Chinese,
+                    vec![
+                        "诅咒像蛆虫一样不断涌现。".to_owned(),
+                        "我们必须找到他。".to_owned(),
+                        String::new(),
+                        "♪".to_owned(),
+                    ]
+                ),
+                (
+                    Language::English,
+                    vec![
+                        "Curses were springing up like maggots.".to_owned(),
+                        String::new(),
+                        "We have to find him.".to_owned(),
+                        "♪".to_owned(),
+                    ]
+                ),
+            ]
+        );
+    }
 }
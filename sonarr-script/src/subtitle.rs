@@ -1,108 +1,691 @@
+//! Probes media files for subtitle streams, extracts them, and merges them
+//! into bilingual subtitles.
+
 use std::collections::HashSet;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
 
 use anyhow::Context;
 use anyhow::Result;
 use anyhow::bail;
 use aspasia::SubRipSubtitle;
 use aspasia::Subtitle;
-use aspasia::TextEvent;
 use aspasia::TextEventInterface;
 use aspasia::TextSubtitle;
 use aspasia::TimedSubtitleFile;
 use aspasia::subrip::SubRipEvent;
 use regex::Regex;
 use tracing::info;
+use tracing::warn;
+
+use crate::atomic;
+use crate::cache::SubtitleCache;
+use crate::detect::LanguageDetection;
+use crate::error::Error;
+#[cfg(feature = "matroska")]
+use crate::matroska;
+use crate::naming::SidecarNaming;
+#[cfg(feature = "ocr")]
+use crate::ocr;
+use crate::overwrite::OverwritePolicy;
+use crate::overwrite::SidecarWriter;
+use crate::pinyin::PinyinAnnotation;
+use crate::process::CommandRunner;
+use crate::process::ProcessCommand;
+use crate::reflow::ReflowOptions;
+use crate::remux;
+use crate::remux::EmbeddedSubtitle;
+use crate::remux::OutputMode;
+use crate::report::CandidateReport;
+use crate::report::LanguageReport;
+use crate::report::ProcessingReport;
+use crate::report::StreamReport;
+use crate::script::CjkScript;
+use crate::sub::Language;
+use crate::sub::SubtitleTrack;
+
+/// How long ffprobe may spend probing a media file before it is killed
+const PROBE_TIMEOUT: Duration = Duration::from_secs(60);
 
 pub struct SubtitleMergeContext {
     pub media_file: PathBuf,
+    pub runner: Arc<dyn CommandRunner>,
+    /// Where extracted subtitles are kept between runs
+    pub cache: SubtitleCache,
+    /// How long ffmpeg may spend extracting subtitles before it is killed
+    pub extract_timeout: Duration,
+    /// Lowercase ASS style names whose events are cleared, eg. signs
+    pub clear_styles: HashSet<String>,
+    /// How to recognize bitmap subtitles, which are left alone if not set
+    pub ocr: Option<OcrOptions>,
+    /// Whether merged subtitles go into sidecars, the media file, or both
+    pub output: OutputMode,
+    /// Whether the first embedded subtitle becomes the default stream
+    pub embed_default: bool,
+    /// Whether the language tags of streams are checked against their text
+    pub language_check: LanguageCheck,
+    pub detection: Arc<LanguageDetection>,
+    /// Whether furigana written inline after kanji is removed from Japanese
+    pub strip_furigana: bool,
+    /// Whether romaji lines are removed from Japanese
+    pub strip_romaji: bool,
+    /// How lines are rebroken, which they are not if not set
+    pub reflow: Option<ReflowOptions>,
+    /// How Chinese is annotated with its reading, which it is not if not set
+    pub pinyin: Option<PinyinAnnotation>,
+    pub naming: SidecarNaming,
+    /// What to do with sidecars that are already there
+    pub overwrite: OverwritePolicy,
+    /// Whether sidecars that did not come from us are backed up before they
+    /// are overwritten
+    pub backup_sidecars: bool,
+}
+
+/// What to do about subtitle streams whose language tag disagrees with the
+/// language their text is in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum LanguageCheck {
+    /// Trust the tags
+    Off,
+    /// Log a warning, but trust the tags
+    Flag,
+    /// Go by the text, which also picks up untagged streams
+    Override,
+}
+
+/// What became of a stream after checking its language.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum LanguageDecision {
+    /// The tag is right, or the text is too mixed to tell
+    Keep,
+    /// The text is in another language, but the tag is trusted
+    Flag(String),
+    /// The text is in another wanted language
+    Relabel(String),
+    /// The text is not in a wanted language
+    Drop,
+}
+
+/// Share of a stream's events that have to be in one language for the
+/// stream to be taken to be in it
+const MIN_LANGUAGE_SHARE: f64 = 0.6;
+
+fn decide_language(
+    check: LanguageCheck,
+    tagged: &str,
+    detected: Option<(&str, f64)>,
+) -> LanguageDecision {
+    let confident = detected.filter(|(_, share)| *share >= MIN_LANGUAGE_SHARE);
+    let tagged_wanted = WANTED_LANGUAGES.contains(&tagged);
+    match confident {
+        Some((code, _)) if code == tagged => LanguageDecision::Keep,
+        Some((code, _)) => match check {
+            LanguageCheck::Override if WANTED_LANGUAGES.contains(&code) => {
+                LanguageDecision::Relabel(code.to_owned())
+            }
+            LanguageCheck::Off | LanguageCheck::Flag if tagged_wanted => {
+                LanguageDecision::Flag(code.to_owned())
+            }
+            _ => LanguageDecision::Drop,
+        },
+        None if tagged_wanted => LanguageDecision::Keep,
+        None => LanguageDecision::Drop,
+    }
+}
+
+/// Checks the language of every dumped subtitle file against its stream's
+/// tag, renaming files whose language turned out to be another wanted one
+/// and removing the ones not in a wanted language.
+fn check_languages(
+    check: LanguageCheck,
+    detection: &LanguageDetection,
+    dumped: Vec<(SubtitleStream, PathBuf)>,
+    report: &mut ProcessingReport,
+) -> Result<Vec<(SubtitleStream, PathBuf)>> {
+    if check == LanguageCheck::Off {
+        return Ok(dumped);
+    }
+    let mut checked = Vec::new();
+    for (mut s, dumped) in dumped {
+        let track = SubtitleTrack::load(&dumped)?;
+        let distribution = track.detect_languages(detection);
+        let detected = distribution
+            .top()
+            .map(|top| (language_code(top.language), top.share));
+        let decision = decide_language(
+            check,
+            &s.language_code,
+            detected
+                .as_ref()
+                .map(|(code, share)| (code.as_str(), *share)),
+        );
+        info!(
+            stream_id = %s.stream_id,
+            tagged = %s.language_code,
+            detected = ?detected.as_ref().map(|(code, _)| code),
+            share = detected.as_ref().map(|(_, share)| *share),
+            confidence = distribution.top().map(|top| top.confidence),
+            unknown = distribution.unknown,
+            ?decision,
+            "checked subtitle language"
+        );
+        report.languages.push(LanguageReport {
+            stream_id: s.stream_id.clone(),
+            tagged: s.language_code.clone(),
+            detected: detected.as_ref().map(|(code, _)| code.clone()),
+            share: detected.as_ref().map(|(_, share)| *share),
+            confidence: distribution.top().map(|top| top.confidence),
+            decision: match &decision {
+                LanguageDecision::Keep => "keep".to_owned(),
+                LanguageDecision::Flag(code) => format!("flag as {code}"),
+                LanguageDecision::Relabel(code) => format!("relabel as {code}"),
+                LanguageDecision::Drop => "drop".to_owned(),
+            },
+        });
+        match decision {
+            LanguageDecision::Keep => checked.push((s, dumped)),
+            LanguageDecision::Flag(code) => {
+                warn!(stream_id = %s.stream_id, tagged = %s.language_code, detected = %code, "subtitle stream seems mislabelled");
+                checked.push((s, dumped));
+            }
+            LanguageDecision::Relabel(code) => {
+                let relabelled = relabelled_path(&dumped, &s.language_code, &code)?;
+                std::fs::rename(&dumped, &relabelled)?;
+                s.language_code = code;
+                checked.push((s, relabelled));
+            }
+            LanguageDecision::Drop => std::fs::remove_file(&dumped)?,
+        }
+    }
+    Ok(checked)
+}
+
+/// Share of a mixed track's events that have to be in a language for the
+/// track to be split into it
+const MIN_MIXED_SHARE: f64 = 0.2;
+
+/// Splits tracks with both wanted languages in them, such as merged
+/// "chs&eng" fansub tracks, into one file per language. This is only done
+/// when English or every language paired with it is left without a track of
+/// its own, since mixed tracks are a last resort.
+fn split_mixed_tracks(
+    detection: &LanguageDetection,
+    dumped: Vec<(SubtitleStream, PathBuf)>,
+    report: &mut ProcessingReport,
+) -> Result<Vec<(SubtitleStream, PathBuf)>> {
+    let tagged: HashSet<&str> = dumped
+        .iter()
+        .map(|(s, _)| s.language_code.as_str())
+        .collect();
+    if tagged.contains("en") && PAIRED_LANGUAGES.iter().any(|l| tagged.contains(l)) {
+        return Ok(dumped);
+    }
+    let mut split = Vec::new();
+    for (s, dumped) in dumped {
+        let track = SubtitleTrack::load(&dumped)?;
+        let spoken = track
+            .events()
+            .iter()
+            .filter(|e| !e.text.trim().is_empty())
+            .count();
+        let parts: Vec<(String, SubtitleTrack)> = track
+            .split_languages(detection)?
+            .into_iter()
+            .map(|(language, part)| (language_code(language), part))
+            .filter(|(code, part)| {
+                let events = part
+                    .events()
+                    .iter()
+                    .filter(|e| !e.text.trim().is_empty())
+                    .count();
+                WANTED_LANGUAGES.contains(&code.as_str())
+                    && events as f64 >= MIN_MIXED_SHARE * spoken as f64
+            })
+            .collect();
+        if parts.len() < 2 {
+            split.push((s, dumped));
+            continue;
+        }
+        info!(
+            stream_id = %s.stream_id,
+            languages = ?parts.iter().map(|(code, _)| code).collect::<Vec<_>>(),
+            "splitting mixed-language subtitle stream"
+        );
+        let codes: Vec<&str> = parts.iter().map(|(code, _)| code.as_str()).collect();
+        report.languages.push(LanguageReport {
+            stream_id: s.stream_id.clone(),
+            tagged: s.language_code.clone(),
+            detected: None,
+            share: None,
+            confidence: None,
+            decision: format!("split into {}", codes.join(", ")),
+        });
+        std::fs::remove_file(&dumped)?;
+        for (code, part) in parts {
+            let path = relabelled_path(&dumped, &s.language_code, &code)?;
+            part.save_as(&path)?;
+            let stream = SubtitleStream {
+                language_code: code,
+                ..s.clone()
+            };
+            split.push((stream, path));
+        }
+    }
+    Ok(split)
 }
 
-pub fn extract_and_merge(context: &SubtitleMergeContext) -> anyhow::Result<()> {
+/// Path of a dumped subtitle file with `.{from}.` in its name replaced by
+/// `.{to}.`.
+fn relabelled_path(path: &Path, from: &str, to: &str) -> Result<PathBuf> {
+    let file_name = path
+        .file_name()
+        .context("subtitle file has no name")?
+        .to_string_lossy()
+        .replacen(&format!(".{from}."), &format!(".{to}."), 1);
+    Ok(path.with_file_name(file_name))
+}
+
+/// The language code the pipeline uses for a detected language.
+fn language_code(language: Language) -> String {
+    map_language_code(&language.iso_code_639_1().to_string())
+}
+
+/// How to run Tesseract on bitmap subtitles.
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "ocr"), allow(dead_code))]
+pub struct OcrOptions {
+    /// Directory holding the `.traineddata` files, instead of Tesseract's own
+    pub tessdata_dir: Option<PathBuf>,
+    /// Events recognized with a lower mean confidence, out of 100, are dropped
+    pub min_confidence: f32,
+    pub timeout: Duration,
+}
+
+/// Extracts subtitles from the media file and writes sidecar subtitles next to
+/// it, returning the paths of the sidecars written. What was found and done
+/// along the way goes into `report`, also when this fails.
+pub fn extract_and_merge(
+    context: &SubtitleMergeContext,
+    report: &mut ProcessingReport,
+) -> Result<Vec<PathBuf>, Error> {
     let media_file = &context.media_file;
 
     info!(media_file = %media_file.to_string_lossy(), "download event");
+    report.filters = filters(context);
 
-    let media_file_stem = media_file
-        .file_stem()
-        .context("unable to get media file stem")?;
-    let subtitle_dir = media_file
-        .parent()
-        .context("unable to get media file")?
-        .join(".subtitles")
-        .join(media_file_stem);
-    std::fs::create_dir_all(&subtitle_dir)?;
-
-    let lang_filter = HashSet::from(["zh", "en"]);
-    let codec_filter = HashSet::from(["srt", "subrip", "ass", "ssa", "mov_text", "webvtt", "ttml"]); // ffmpeg -codecs
-
-    let subtitle_streams = get_subtitle_streams(media_file)?;
-    for ref s in subtitle_streams {
-        info!(stream_id = %s.stream_id, language_code = %s.language_code, codec = %s.codec, "found subtitle stream");
-        if !lang_filter.contains(s.language_code.as_str())
-            || !codec_filter.contains(s.codec.as_str())
-        {
-            continue;
+    let subtitle_dir = context.cache.entry_dir(media_file)?;
+    if context.cache.is_fresh(&subtitle_dir, media_file) {
+        info!(subtitle_dir = %subtitle_dir.display(), "subtitles already extracted, reusing them");
+        report.reused = true;
+        // What the extraction found is only known to the run that did it
+        if let Ok(previous) = ProcessingReport::load(&subtitle_dir) {
+            report.streams = previous.streams;
+            report.languages = previous.languages;
+            report.cleaning = previous.cleaning;
         }
+    } else {
+        context.cache.reset(&subtitle_dir)?;
+        std::fs::create_dir_all(&subtitle_dir)
+            .map_err(|source| Error::write(&subtitle_dir, source))?;
+
+        let started = Instant::now();
+        let dumped = extract_subtitle_files(context, &subtitle_dir, report)?;
+        report.timed("extract", started);
 
-        info!(stream_id = %s.stream_id, language_code = %s.language_code, codec = %s.codec, "dumping subtitle file");
-        let dumped = dump_subtitle_file(s, &subtitle_dir)?;
+        let started = Instant::now();
+        for (_, dumped) in &dumped {
+            info!(file = %dumped.to_string_lossy(), "classifying subtitle file");
+            for (rule, events) in classify_subtitle_file(dumped, &context.clear_styles)? {
+                report.cleaned(dumped, rule, events);
+            }
+        }
+        report.timed("classify", started);
 
-        info!(file = %dumped.to_string_lossy(), "cleaning subtitle file");
-        clean_subtitle_file(&dumped)?;
+        let started = Instant::now();
+        let dumped = split_mixed_tracks(&context.detection, dumped, report)?;
+        let dumped = check_languages(context.language_check, &context.detection, dumped, report)?;
+        report.timed("languages", started);
 
-        if s.language_code == "zh" {
-            info!(file = %dumped.to_string_lossy(), "ensuring chinese character classification");
-            ensure_hanzi(&dumped)?;
+        let started = Instant::now();
+        for (s, dumped) in dumped {
+            if !PAIRED_LANGUAGES.contains(&s.language_code.as_str()) {
+                continue;
+            }
+            info!(file = %dumped.to_string_lossy(), "ensuring cjk script classification");
+            let (language_code, relabelled) = ensure_cjk_script(&dumped, &s.language_code)?;
+            if relabelled != dumped {
+                report.languages.push(LanguageReport {
+                    stream_id: s.stream_id.clone(),
+                    tagged: s.language_code.clone(),
+                    detected: Some(language_code.clone()),
+                    share: None,
+                    confidence: None,
+                    decision: format!("relabel as {language_code}"),
+                });
+            }
+            if language_code == "ja" {
+                let stripped =
+                    strip_readings(&relabelled, context.strip_furigana, context.strip_romaji)?;
+                for (rule, events) in stripped {
+                    report.cleaned(&relabelled, rule, events);
+                }
+            }
         }
+        report.timed("scripts", started);
+        context.cache.record(&subtitle_dir, media_file)?;
     }
 
-    let Some(en) = get_best_srt_en(&subtitle_dir) else {
-        return Ok(());
+    let en_candidates = ranked_candidates(&subtitle_dir, "en")?;
+    report.candidates.push(CandidateReport {
+        language_code: "en".to_owned(),
+        ranked: en_candidates.clone(),
+    });
+    let Some(en) = en_candidates.into_iter().next() else {
+        return Err(Error::NoSuitableSubtitles {
+            path: media_file.clone(),
+        });
     };
-    let live_en = media_file.with_extension("en.srt");
-    std::fs::copy(&en, live_en)?;
+    let started = Instant::now();
+    let mut written = Vec::new();
+    // Styling is kept in the subtitle directory, but sidecars are plain SRT
+    let en = downconvert(&en, context.reflow.as_ref(), None)?;
+    let mut sidecars = SidecarWriter::open(
+        context.overwrite,
+        context.backup_sidecars,
+        context.cache.sidecar_record(media_file)?,
+    )?;
+    if context.output.sidecars() {
+        let live_en = context.naming.path(media_file, "en");
+        if sidecars.write(&en, &live_en)? {
+            written.push(live_en);
+        }
+    }
 
-    // FIXME: Extract the repeated extension stuff
-    if let Some(chs) = get_best_srt_chs(&subtitle_dir) {
-        let merged = merge_subtitle_files(&chs, &en)?;
-        let live_chs = media_file.with_extension("zh.srt");
-        std::fs::copy(&merged, live_chs)?;
+    // Only the bilingual subtitles are embedded, as English is usually in
+    // the media file already
+    let mut embedded = Vec::new();
+    for pairing in PAIRINGS {
+        let candidates = ranked_candidates(&subtitle_dir, pairing.language_code)?;
+        let Some(paired) = candidates.first().cloned() else {
+            continue;
+        };
+        report.candidates.push(CandidateReport {
+            language_code: pairing.language_code.to_owned(),
+            ranked: candidates,
+        });
+        let pinyin = context
+            .pinyin
+            .as_ref()
+            .filter(|_| pairing.language_code.starts_with("zh"));
+        let paired = downconvert(&paired, context.reflow.as_ref(), pinyin)?;
+        let merged = merge_subtitle_files(&paired, &en)?;
+        if context.output.sidecars() {
+            let live = context.naming.path(media_file, pairing.language_code);
+            if sidecars.write(&merged, &live)? {
+                written.push(live);
+            }
+        }
+        embedded.push(EmbeddedSubtitle {
+            path: merged,
+            language: pairing.stream_language,
+            title: pairing.title,
+        });
     }
-    if let Some(cht) = get_best_srt_cht(&subtitle_dir) {
-        let merged = merge_subtitle_files(&cht, &en)?;
-        let live_cht = media_file.with_extension("zh-TW.srt");
-        std::fs::copy(&merged, live_cht)?;
+    sidecars.save()?;
+    report.timed("merge", started);
+
+    if context.output.embeds() && !embedded.is_empty() {
+        let started = Instant::now();
+        info!(
+            count = embedded.len(),
+            "embedding subtitles into media file"
+        );
+        remux::embed_subtitles(
+            context.runner.as_ref(),
+            media_file,
+            &embedded,
+            context.embed_default,
+            context.extract_timeout,
+        )?;
+        // The media file changed, but the subtitles extracted from it did not
+        context.cache.record(&subtitle_dir, media_file)?;
+        written.push(media_file.clone());
+        report.timed("embed", started);
     }
 
-    Ok(())
+    report.outputs = written.clone();
+    Ok(written)
+}
+
+/// The options that change which streams and events are kept, or how they
+/// read, in the form `name=value`.
+fn filters(context: &SubtitleMergeContext) -> Vec<String> {
+    let mut filters = vec![format!(
+        "language-check={}",
+        match context.language_check {
+            LanguageCheck::Off => "off",
+            LanguageCheck::Flag => "flag",
+            LanguageCheck::Override => "override",
+        }
+    )];
+    if context.ocr.is_some() {
+        filters.push("ocr".to_owned());
+    }
+    if !context.clear_styles.is_empty() {
+        let mut styles: Vec<&str> = context.clear_styles.iter().map(String::as_str).collect();
+        styles.sort();
+        filters.push(format!("clear-styles={}", styles.join(",")));
+    }
+    if context.strip_furigana {
+        filters.push("strip-furigana".to_owned());
+    }
+    if context.strip_romaji {
+        filters.push("strip-romaji".to_owned());
+    }
+    if context.reflow.is_some() {
+        filters.push("reflow".to_owned());
+    }
+    if context.pinyin.is_some() {
+        filters.push("pinyin".to_owned());
+    }
+    filters
+}
+
+/// Writes the wanted subtitle streams of the media file to the subtitle
+/// directory. Matroska files are read natively when built with the
+/// `matroska` feature, falling back to ffmpeg if that fails.
+fn extract_subtitle_files(
+    context: &SubtitleMergeContext,
+    subtitle_dir: &Path,
+    report: &mut ProcessingReport,
+) -> Result<Vec<(SubtitleStream, PathBuf)>> {
+    let media_file = &context.media_file;
+
+    #[cfg(feature = "matroska")]
+    if matroska::is_matroska(media_file) {
+        match dump_matroska_subtitle_files(context, subtitle_dir, report) {
+            Ok(dumped) => return Ok(dumped),
+            Err(error) => {
+                warn!(
+                    ?error,
+                    "failed reading matroska file, falling back to ffmpeg"
+                );
+                report.streams.clear();
+            }
+        }
+    }
+
+    let runner = context.runner.as_ref();
+    let subtitle_streams = get_subtitle_streams(runner, media_file)?;
+    for s in &subtitle_streams {
+        info!(stream_id = %s.stream_id, language_code = %s.language_code, codec = %s.codec, "found subtitle stream");
+    }
+    let wanted = wanted_streams(
+        &subtitle_streams,
+        context.ocr.is_some(),
+        context.language_check,
+    );
+    report.streams = stream_reports(&subtitle_streams, &wanted);
+
+    info!(count = wanted.len(), "dumping subtitle files");
+    let dumped = dump_subtitle_files(runner, &wanted, subtitle_dir, context.extract_timeout)?;
+
+    #[cfg(feature = "ocr")]
+    if let Some(options) = &context.ocr {
+        return Ok(recognize_dumped_bitmaps(runner, options, dumped));
+    }
+    Ok(dumped)
+}
+
+/// The streams found, and whether they were among the wanted ones.
+fn stream_reports(streams: &[SubtitleStream], wanted: &[&SubtitleStream]) -> Vec<StreamReport> {
+    streams
+        .iter()
+        .map(|s| StreamReport {
+            stream_id: s.stream_id.clone(),
+            language_code: s.language_code.clone(),
+            codec: s.codec.clone(),
+            selected: wanted.iter().any(|w| w.stream_id == s.stream_id),
+        })
+        .collect()
 }
 
 #[derive(Debug, Clone)]
-struct SubtitleStream {
+pub struct SubtitleStream {
     pub source_file: PathBuf,
     pub stream_id: String,
     pub language_code: String,
     pub codec: String,
 }
 
-fn get_subtitle_streams(media_file: impl AsRef<Path>) -> anyhow::Result<Vec<SubtitleStream>> {
-    let re =
-        Regex::new(r"Stream #(?<stream>\d+:\d+).*?\((?<lang>\w+)\).*?Subtitle: (?<codec>\w+)")?;
+impl SubtitleStream {
+    /// Whether the stream is in a language and text codec that the pipeline
+    /// extracts.
+    pub fn is_wanted(&self) -> bool {
+        WANTED_LANGUAGES.contains(&self.language_code.as_str()) && self.is_text()
+    }
+
+    /// Whether the stream is in a text codec that the pipeline can read.
+    pub fn is_text(&self) -> bool {
+        let codec_filter =
+            HashSet::from(["srt", "subrip", "ass", "ssa", "mov_text", "webvtt", "ttml"]); // ffmpeg -codecs
+        codec_filter.contains(self.codec.as_str())
+    }
 
-    let output = std::process::Command::new("ffprobe")
-        .args(["-i", &media_file.as_ref().to_string_lossy()])
-        .output()?;
-    let mediainfo = String::from_utf8(output.stderr)?;
+    /// Whether the stream is a bitmap codec whose text can be recognized.
+    /// DVB subtitles are not supported.
+    pub fn is_bitmap(&self) -> bool {
+        matches!(self.codec.as_str(), "hdmv_pgs_subtitle" | "dvd_subtitle")
+    }
+}
+
+/// Languages the pipeline writes sidecars for
+const WANTED_LANGUAGES: [&str; 4] = ["en", "zh", "ja", "ko"];
+
+/// Languages merged with English into bilingual sidecars
+const PAIRED_LANGUAGES: [&str; 3] = ["zh", "ja", "ko"];
+
+/// A bilingual sidecar, with English on top of another language.
+struct Pairing {
+    /// Language code of the sidecar, which tells simplified and traditional
+    /// Chinese apart
+    language_code: &'static str,
+    /// ISO 639-2 language of the stream when embedded
+    stream_language: &'static str,
+    title: &'static str,
+}
+
+const PAIRINGS: [Pairing; 4] = [
+    Pairing {
+        language_code: "zh",
+        stream_language: "chi",
+        title: "Chinese (Simplified) / English",
+    },
+    Pairing {
+        language_code: "zh-TW",
+        stream_language: "chi",
+        title: "Chinese (Traditional) / English",
+    },
+    Pairing {
+        language_code: "ja",
+        stream_language: "jpn",
+        title: "Japanese / English",
+    },
+    Pairing {
+        language_code: "ko",
+        stream_language: "kor",
+        title: "Korean / English",
+    },
+];
+
+/// Language codes of the bilingual sidecars, such as `zh-TW`.
+pub fn paired_sidecar_languages() -> impl Iterator<Item = &'static str> {
+    PAIRINGS.iter().map(|pairing| pairing.language_code)
+}
+
+/// The streams the pipeline extracts: the wanted text streams and, if `ocr`
+/// is set, the bitmap streams in wanted languages that have no text stream.
+/// When language tags are overridden by the text, every text stream is
+/// wanted, since any of them could turn out to be mislabelled.
+pub fn wanted_streams(
+    streams: &[SubtitleStream],
+    ocr: bool,
+    language_check: LanguageCheck,
+) -> Vec<&SubtitleStream> {
+    let mut wanted: Vec<&SubtitleStream> = match language_check {
+        LanguageCheck::Override => streams.iter().filter(|s| s.is_text()).collect(),
+        LanguageCheck::Off | LanguageCheck::Flag => {
+            streams.iter().filter(|s| s.is_wanted()).collect()
+        }
+    };
+    if !ocr {
+        return wanted;
+    }
+    let text_languages: HashSet<&str> = wanted
+        .iter()
+        .filter(|s| s.is_wanted())
+        .map(|s| s.language_code.as_str())
+        .collect();
+    wanted.extend(streams.iter().filter(|s| {
+        s.is_bitmap()
+            && WANTED_LANGUAGES.contains(&s.language_code.as_str())
+            && !text_languages.contains(s.language_code.as_str())
+    }));
+    wanted
+}
+
+pub fn get_subtitle_streams(
+    runner: &dyn CommandRunner,
+    media_file: impl AsRef<Path>,
+) -> Result<Vec<SubtitleStream>, Error> {
+    // The stream ID in brackets is only there for some containers, and the
+    // language only for tagged streams
+    let re = Regex::new(
+        r"Stream #(?<stream>\d+:\d+)(?:\[\w+\])?(?:\((?<lang>\w+)\))?: Subtitle: (?<codec>\w+)",
+    )
+    .expect("valid regex");
+
+    let command = ProcessCommand::new("ffprobe", PROBE_TIMEOUT)
+        .arg("-i")
+        .arg(media_file.as_ref());
+    let mediainfo = runner
+        .run(&command, &mut |_| {})
+        .map_err(|source| Error::Probe {
+            path: media_file.as_ref().to_owned(),
+            source,
+        })?
+        .stderr;
 
     let subtitle_streams: Vec<SubtitleStream> = re
         .captures_iter(&mediainfo)
         .flat_map(|capture| {
             let stream_id = capture.name("stream")?;
-            let language_code = capture.name("lang")?;
+            let language_code = capture.name("lang").map_or("und", |lang| lang.as_str());
             let codec = capture.name("codec")?;
-            let language_code = map_language_code(language_code.as_str());
+            let language_code = map_language_code(language_code);
             Some(SubtitleStream {
                 source_file: PathBuf::from(media_file.as_ref()),
                 stream_id: stream_id.as_str().to_string(),
@@ -115,39 +698,267 @@ fn get_subtitle_streams(media_file: impl AsRef<Path>) -> anyhow::Result<Vec<Subt
     Ok(subtitle_streams)
 }
 
-fn dump_subtitle_file(
-    subtitle_stream: &SubtitleStream,
-    destination_dir: impl AsRef<Path>,
-) -> anyhow::Result<PathBuf> {
-    let stream = subtitle_stream.stream_id.replace(":", "_");
-    let lang = &subtitle_stream.language_code;
-    let name = format!("{stream}.{lang}.srt");
-    let sub_file = destination_dir.as_ref().join(name);
+/// Dumps every given stream in a single ffmpeg invocation, so the media
+/// file is only read once no matter how many streams are wanted. All streams
+/// must come from the same media file. Streams that come out empty are left
+/// out of the result.
+fn dump_subtitle_files(
+    runner: &dyn CommandRunner,
+    subtitle_streams: &[&SubtitleStream],
+    destination_dir: &Path,
+    timeout: Duration,
+) -> anyhow::Result<Vec<(SubtitleStream, PathBuf)>> {
+    let Some(first) = subtitle_streams.first() else {
+        return Ok(Vec::new());
+    };
 
-    let _output = std::process::Command::new("ffmpeg")
+    let sub_files: Vec<PathBuf> = subtitle_streams
+        .iter()
+        .map(|s| dumped_file(destination_dir, s))
+        .collect();
+
+    let mut command = ProcessCommand::new("ffmpeg", timeout)
         .args([
-            "-i",
-            &subtitle_stream.source_file.to_string_lossy(),
-            "-map",
-            &subtitle_stream.stream_id,
-            "-c:s",
-            "srt",
-            &sub_file.to_string_lossy(),
+            "-nostdin",
+            "-y",
+            "-loglevel",
+            "error",
+            "-progress",
+            "pipe:1",
         ])
-        .output()?;
+        .arg("-i")
+        .arg(&first.source_file);
+    for (s, sub_file) in subtitle_streams.iter().zip(&sub_files) {
+        command = command
+            .args(["-map", &s.stream_id, "-c:s", cache_format(&s.codec).1])
+            .arg(sub_file);
+    }
+
+    let started = Instant::now();
+    let mut last_logged = Instant::now();
+    runner
+        .run(&command, &mut |line| {
+            // ffmpeg `-progress` output, logged every few seconds
+            if let Some(out_time) = line.strip_prefix("out_time=")
+                && last_logged.elapsed() >= Duration::from_secs(10)
+            {
+                info!(%out_time, "extracting subtitles");
+                last_logged = Instant::now();
+            }
+        })
+        .context("failed extracting subtitles")?;
+    info!(elapsed = ?started.elapsed(), count = sub_files.len(), "dumped subtitle files");
+
+    let mut dumped = Vec::new();
+    for (s, sub_file) in subtitle_streams.iter().zip(sub_files) {
+        let size = std::fs::metadata(&sub_file)
+            .with_context(|| format!("ffmpeg did not write {}", sub_file.display()))?
+            .len();
+        if size == 0 {
+            warn!(stream_id = %s.stream_id, "extracted subtitle file is empty, skipping");
+            continue;
+        }
+        dumped.push(((*s).clone(), sub_file));
+    }
 
-    Ok(sub_file)
+    Ok(dumped)
+}
+
+/// Same as `dump_subtitle_files`, but reading the Matroska file directly.
+#[cfg(feature = "matroska")]
+fn dump_matroska_subtitle_files(
+    context: &SubtitleMergeContext,
+    destination_dir: &Path,
+    report: &mut ProcessingReport,
+) -> anyhow::Result<Vec<(SubtitleStream, PathBuf)>> {
+    let media_file = &context.media_file;
+    let started = Instant::now();
+    let file = matroska::read_matroska(media_file, context.ocr.is_some())?;
+    let found = |index, language, codec: &str, name, default, forced| {
+        let s = SubtitleStream {
+            source_file: media_file.to_owned(),
+            stream_id: format!("0:{index}"),
+            language_code: map_language_code(language),
+            codec: codec.to_owned(),
+        };
+        info!(
+            stream_id = %s.stream_id,
+            language_code = %s.language_code,
+            codec = %s.codec,
+            name = ?name,
+            default,
+            forced,
+            "found subtitle stream"
+        );
+        s
+    };
+    let streams: Vec<SubtitleStream> = file
+        .subtitles
+        .iter()
+        .map(|t| {
+            found(
+                t.index,
+                &t.language,
+                t.codec(),
+                &t.name,
+                t.default,
+                t.forced,
+            )
+        })
+        .chain(file.bitmaps.iter().map(|t| {
+            found(
+                t.index,
+                &t.language,
+                t.codec(),
+                &t.name,
+                t.default,
+                t.forced,
+            )
+        }))
+        .collect();
+    let wanted = wanted_streams(&streams, context.ocr.is_some(), context.language_check);
+    report.streams = stream_reports(&streams, &wanted);
+    let wanted: HashSet<&str> = wanted.into_iter().map(|s| s.stream_id.as_str()).collect();
+    #[cfg_attr(not(feature = "ocr"), allow(unused_variables))]
+    let (text_streams, bitmap_streams) = streams.split_at(file.subtitles.len());
+
+    let mut dumped = Vec::new();
+    for (subtitle, s) in file.subtitles.iter().zip(text_streams) {
+        if !wanted.contains(s.stream_id.as_str()) {
+            continue;
+        }
+        if subtitle.track.events().is_empty() {
+            warn!(stream_id = %s.stream_id, "subtitle stream is empty, skipping");
+            continue;
+        }
+        let sub_file = dumped_file(destination_dir, s);
+        subtitle.track.save_as(&sub_file)?;
+        dumped.push((s.clone(), sub_file));
+    }
+
+    #[cfg(feature = "ocr")]
+    if let Some(options) = &context.ocr {
+        for (subtitle, s) in file.bitmaps.iter().zip(bitmap_streams) {
+            if !wanted.contains(s.stream_id.as_str()) {
+                continue;
+            }
+            let sub_file = dumped_file(destination_dir, s);
+            match recognize_bitmap_subtitle(
+                context.runner.as_ref(),
+                options,
+                s,
+                subtitle,
+                &sub_file,
+            ) {
+                Ok(Some(sub_file)) => dumped.push((s.clone(), sub_file)),
+                Ok(None) => {}
+                Err(error) => {
+                    warn!(stream_id = %s.stream_id, ?error, "failed recognizing bitmap subtitles")
+                }
+            }
+        }
+    }
+    info!(elapsed = ?started.elapsed(), count = dumped.len(), "dumped subtitle files from matroska");
+
+    Ok(dumped)
+}
+
+/// Recognizes the text of the bitmap streams that ffmpeg copied into
+/// Matroska files, replacing them with SRT files. Streams that fail are
+/// left out, so that a missing OCR engine does not cost the other sidecars.
+#[cfg(feature = "ocr")]
+fn recognize_dumped_bitmaps(
+    runner: &dyn CommandRunner,
+    options: &OcrOptions,
+    dumped: Vec<(SubtitleStream, PathBuf)>,
+) -> Vec<(SubtitleStream, PathBuf)> {
+    let mut recognized = Vec::new();
+    for (s, sub_file) in dumped {
+        if !s.is_bitmap() {
+            recognized.push((s, sub_file));
+            continue;
+        }
+        let result = matroska::read_matroska(&sub_file, true).and_then(|file| {
+            let subtitle = file
+                .bitmaps
+                .first()
+                .context("ffmpeg did not write a bitmap subtitle track")?;
+            recognize_bitmap_subtitle(runner, options, &s, subtitle, &sub_file)
+        });
+        let _ = std::fs::remove_file(&sub_file);
+        match result {
+            Ok(Some(sub_file)) => recognized.push((s, sub_file)),
+            Ok(None) => {}
+            Err(error) => {
+                warn!(stream_id = %s.stream_id, ?error, "failed recognizing bitmap subtitles")
+            }
+        }
+    }
+    recognized
+}
+
+/// Recognizes the text of a bitmap subtitle stream and saves it as SRT next
+/// to where the stream is dumped, returning the path saved to. Nothing is
+/// saved if no text was recognized.
+#[cfg(feature = "ocr")]
+fn recognize_bitmap_subtitle(
+    runner: &dyn CommandRunner,
+    options: &OcrOptions,
+    s: &SubtitleStream,
+    subtitle: &matroska::MatroskaBitmapSubtitle,
+    sub_file: &Path,
+) -> Result<Option<PathBuf>> {
+    let work_dir = sub_file.with_extension("ocr");
+    let (track, report) =
+        ocr::recognize_subtitle(runner, subtitle, &s.language_code, options, &work_dir)?;
+    info!(
+        stream_id = %s.stream_id,
+        events = report.events,
+        dropped = report.dropped,
+        mean_confidence = report.mean_confidence,
+        "recognized bitmap subtitles"
+    );
+    if track.events().is_empty() {
+        warn!(stream_id = %s.stream_id, "no text recognized in bitmap subtitles, skipping");
+        return Ok(None);
+    }
+    let srt = sub_file.with_extension("srt");
+    track.save_as(&srt)?;
+    Ok(Some(srt))
+}
+
+/// Where a subtitle stream is dumped to in the subtitle directory.
+fn dumped_file(destination_dir: &Path, s: &SubtitleStream) -> PathBuf {
+    let stream = s.stream_id.replace(":", "_");
+    let lang = &s.language_code;
+    let extension = cache_format(&s.codec).0;
+    destination_dir.join(format!("{stream}.{lang}.{extension}"))
+}
+
+/// The file extension and ffmpeg encoder a stream is dumped with. ASS and
+/// WebVTT keep their own format so their styles survive until cleaning,
+/// bitmap streams are copied into Matroska for OCR, and anything else
+/// becomes SRT.
+fn cache_format(codec: &str) -> (&'static str, &'static str) {
+    match codec {
+        "ass" | "ssa" => ("ass", "ass"),
+        "webvtt" => ("vtt", "webvtt"),
+        "hdmv_pgs_subtitle" | "dvd_subtitle" => ("mks", "copy"),
+        _ => ("srt", "srt"),
+    }
 }
 
 fn map_language_code(input: &str) -> String {
     match input {
         "zh" | "zho" | "chi" => "zh".into(),
         "en" | "eng" => "en".into(),
+        "ja" | "jpn" => "ja".into(),
+        "ko" | "kor" => "ko".into(),
         other => other.into(),
     }
 }
 
-pub fn clean_subtitle_file(subtitle_file: &Path) -> Result<()> {
+pub fn clean_subtitle_file(subtitle_file: &Path) -> Result<(), Error> {
     let mut subtitle = TimedSubtitleFile::new(subtitle_file)
         .context("error opening subtitle file for cleaning")?;
 
@@ -159,51 +970,138 @@ pub fn clean_subtitle_file(subtitle_file: &Path) -> Result<()> {
         TimedSubtitleFile::MicroDvd(s) => s.strip_formatting(),
     }
 
-    subtitle
-        .export(subtitle_file)
+    atomic::replace_with(subtitle_file, |tmp| Ok(subtitle.export(tmp)?))
         .context("error writing cleaned subtitle file")?;
 
     Ok(())
 }
 
-pub fn ensure_hanzi(srt_file: impl AsRef<Path>) -> anyhow::Result<()> {
-    let srt_file = srt_file.as_ref();
-    let srt = SubRipSubtitle::from_path(srt_file)?;
-    for event in srt.events() {
-        if event.as_plaintext().contains("們") {
-            let new_file = srt_file.to_string_lossy().replace(".zh.srt", ".zh-TW.srt");
-            std::fs::rename(srt_file, new_file)?;
-            break;
-        }
+/// Clears events that are not dialogue, such as signs, while the subtitle file
+/// still has the styles to tell them apart. The file keeps its format.
+/// Returns the number of events each rule cleared.
+pub fn classify_subtitle_file(
+    subtitle_file: &Path,
+    clear_styles: &HashSet<String>,
+) -> Result<Vec<(&'static str, usize)>, Error> {
+    // Only ASS has styles to go by
+    if subtitle_file.extension().is_none_or(|e| e != "ass") {
+        return Ok(Vec::new());
     }
-    Ok(())
+    let mut track = SubtitleTrack::load(subtitle_file)?;
+    let cleared = vec![
+        ("clear-styles", track.clear_events_with_styles(clear_styles)),
+        (
+            "many-blanks",
+            track.clear_events_whose_style_has_many_existing_blanks(),
+        ),
+    ];
+    track.save_as(subtitle_file)?;
+    Ok(cleared)
 }
 
-// TODO: Find the largest instead of just the first
-fn get_best_srt(subtitle_dir: impl AsRef<Path>, suffix: &str) -> anyhow::Result<PathBuf> {
-    let paths = std::fs::read_dir(subtitle_dir.as_ref())?;
-    for path in paths {
-        let path = path?;
-        if path.file_name().to_string_lossy().ends_with(suffix) {
-            return Ok(path.path());
+/// Writes a plain SRT copy of the subtitle file next to it, with styling and
+/// formatting stripped, cleared events left out, and lines rebroken and
+/// annotated with their reading if asked to.
+fn downconvert(
+    subtitle_file: &Path,
+    reflow: Option<&ReflowOptions>,
+    pinyin: Option<&PinyinAnnotation>,
+) -> Result<PathBuf> {
+    let output = subtitle_file.with_extension("plain.srt");
+    let mut track = SubtitleTrack::load(subtitle_file)?;
+    track.strip_formatting();
+    if let Some(reflow) = reflow {
+        track.reflow(reflow)?;
+    }
+    if let Some(pinyin) = pinyin {
+        track.annotate(pinyin)?;
+    }
+    track.save_srt(&output)?;
+    Ok(output)
+}
+
+/// Renames a CJK subtitle file after the script it turns out to be written
+/// in, such as from `.zh.` to `.zh-TW.` for traditional Chinese, or to `.ja.`
+/// if it has kana. Returns its language code and path.
+pub fn ensure_cjk_script(
+    subtitle_file: &Path,
+    language_code: &str,
+) -> Result<(String, PathBuf), Error> {
+    let track = SubtitleTrack::load(subtitle_file)?;
+    let script = track.detect_cjk_script().map(CjkScript::language_code);
+    let Some(script) = script.filter(|script| *script != language_code) else {
+        return Ok((language_code.to_owned(), subtitle_file.to_owned()));
+    };
+    info!(file = %subtitle_file.display(), from = %language_code, to = %script, "relabelling subtitle file by its script");
+    let relabelled = relabelled_path(subtitle_file, language_code, script)?;
+    std::fs::rename(subtitle_file, &relabelled)
+        .map_err(|source| Error::write(&relabelled, source))?;
+    Ok((script.to_owned(), relabelled))
+}
+
+/// Strips furigana and romaji from a Japanese subtitle file, if asked to.
+/// Returns the number of events each changed.
+fn strip_readings(
+    subtitle_file: &Path,
+    furigana: bool,
+    romaji: bool,
+) -> Result<Vec<(&'static str, usize)>> {
+    if !furigana && !romaji {
+        return Ok(Vec::new());
+    }
+    let mut track = SubtitleTrack::load(subtitle_file)?;
+    let mut changed = Vec::new();
+    if furigana {
+        changed.push(("strip-furigana", track.strip_furigana()));
+    }
+    if romaji {
+        changed.push(("strip-romaji", track.strip_romaji()));
+    }
+    track.save_as(subtitle_file)?;
+    Ok(changed)
+}
+
+/// Extensions of subtitle files in the subtitle directory, richest first.
+const CACHE_EXTENSIONS: [&str; 3] = ["ass", "vtt", "srt"];
+
+/// The subtitle files in the language, in the order they would be used.
+// TODO: Rank the largest first instead of by extension alone
+fn ranked_candidates(subtitle_dir: &Path, language_code: &str) -> Result<Vec<PathBuf>> {
+    let mut found = Vec::new();
+    for path in std::fs::read_dir(subtitle_dir)? {
+        let path = path?.path();
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let rank = CACHE_EXTENSIONS
+            .iter()
+            .position(|e| file_name.ends_with(&format!(".{language_code}.{e}")));
+        if let Some(rank) = rank {
+            found.push((rank, path));
         }
     }
-    bail!("unable to find an srt with suffix: {suffix}");
+    found.sort();
+    Ok(found.into_iter().map(|(_, path)| path).collect())
 }
 
-fn get_best_srt_en(subtitle_dir: impl AsRef<Path>) -> Option<PathBuf> {
-    get_best_srt(subtitle_dir, ".en.srt").ok()
+fn get_best(subtitle_dir: impl AsRef<Path>, language_code: &str) -> anyhow::Result<PathBuf> {
+    match ranked_candidates(subtitle_dir.as_ref(), language_code)?
+        .into_iter()
+        .next()
+    {
+        Some(path) => Ok(path),
+        None => bail!("unable to find a subtitle file for language: {language_code}"),
+    }
 }
 
-fn get_best_srt_chs(subtitle_dir: impl AsRef<Path>) -> Option<PathBuf> {
-    get_best_srt(subtitle_dir, ".zh.srt").ok()
+pub fn get_best_en(subtitle_dir: impl AsRef<Path>) -> Option<PathBuf> {
+    get_best(subtitle_dir, "en").ok()
 }
 
-fn get_best_srt_cht(subtitle_dir: impl AsRef<Path>) -> Option<PathBuf> {
-    get_best_srt(subtitle_dir, ".zh-TW.srt").ok()
+/// The best subtitle file in the language, such as `zh-TW` or `ja`.
+pub fn get_best_language(subtitle_dir: impl AsRef<Path>, language_code: &str) -> Option<PathBuf> {
+    get_best(subtitle_dir, language_code).ok()
 }
 
-pub fn merge_subtitle_files(bottom: &Path, top: &Path) -> Result<PathBuf> {
+pub fn merge_subtitle_files(bottom: &Path, top: &Path) -> Result<PathBuf, Error> {
     info!(
         bottom = %bottom.to_string_lossy(),
         top = %top.to_string_lossy(),
@@ -229,12 +1127,372 @@ pub fn merge_subtitle_files(bottom: &Path, top: &Path) -> Result<PathBuf> {
     let mut output_events: Vec<SubRipEvent> = Vec::new();
     output_events.extend_from_slice(bottom_subs.events());
     output_events.extend_from_slice(top_subs.events());
-    output_events.sort_by(|a, b| a.start.cmp(&b.start));
+    output_events.sort_by_key(|event| event.start);
     let mut output_srt = SubRipSubtitle::from_events(output_events);
     output_srt.renumber();
 
-    output_srt.export(&output)?;
+    atomic::replace_with(&output, |tmp| Ok(output_srt.export(tmp)?))?;
     info!(output = %output.to_string_lossy(), "Wrote merged subtitle file");
 
     Ok(output)
 }
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+    #[cfg(feature = "matroska")]
+    use crate::cache::CacheArgs;
+    use crate::process::FailureKind;
+    use crate::process::FakeRunner;
+    use crate::process::ProcessError;
+    use crate::process::ProcessOutput;
+
+    const FFPROBE_STDERR: &str = "Input #0, matroska,webm, from 'episode.mkv':
+  Stream #0:0: Video: hevc (Main 10), yuv420p10le(tv), 1920x1080
+  Stream #0:1(jpn): Audio: aac (LC), 48000 Hz, stereo, fltp (default)
+  Stream #0:2(eng): Subtitle: ass (ssa) (default)
+  Stream #0:3(chi): Subtitle: subrip (srt)
+  Stream #0:4(jpn): Subtitle: hdmv_pgs_subtitle (pgssub)
+  Stream #0:5: Subtitle: subrip (srt)
+  Stream #0:6[0x7](eng): Subtitle: mov_text (tx3g / 0x67337874)
+";
+
+    #[test]
+    fn test_get_subtitle_streams() {
+        let runner = FakeRunner::new([Ok(ProcessOutput {
+            stdout: String::new(),
+            stderr: FFPROBE_STDERR.to_owned(),
+        })]);
+
+        let streams = get_subtitle_streams(&runner, "episode.mkv").unwrap();
+        let got: Vec<_> = streams
+            .iter()
+            .map(|s| {
+                (
+                    s.stream_id.as_str(),
+                    s.language_code.as_str(),
+                    s.codec.as_str(),
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            got,
+            [
+                ("0:2", "en", "ass"),
+                ("0:3", "zh", "subrip"),
+                ("0:4", "ja", "hdmv_pgs_subtitle"),
+                ("0:5", "und", "subrip"),
+                ("0:6", "en", "mov_text")
+            ]
+        );
+        assert_eq!(runner.commands.lock().unwrap()[0].program, "ffprobe");
+    }
+
+    #[test]
+    fn test_dump_subtitle_files_surfaces_ffmpeg_failure() {
+        let dir = tempfile::tempdir().unwrap();
+        let runner = FakeRunner::new([Err(ProcessError::Failed {
+            program: "ffmpeg".into(),
+            code: Some(1),
+            kind: FailureKind::NoSpace,
+            stderr: "No space left on device".into(),
+        })]);
+        let stream = SubtitleStream {
+            source_file: "episode.mkv".into(),
+            stream_id: "0:2".into(),
+            language_code: "en".into(),
+            codec: "ass".into(),
+        };
+
+        let error = dump_subtitle_files(&runner, &[&stream], dir.path(), Duration::from_secs(1))
+            .unwrap_err();
+
+        let Some(ProcessError::Failed { kind, .. }) = error.downcast_ref() else {
+            panic!("unexpected error: {error:#}");
+        };
+        assert_eq!(*kind, FailureKind::NoSpace);
+    }
+
+    #[test]
+    fn test_dump_subtitle_files_single_pass() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("0_2.en.ass"), "[Script Info]\n").unwrap();
+        std::fs::write(dir.path().join("0_3.zh.srt"), "").unwrap();
+        let runner = FakeRunner::new([Ok(ProcessOutput::default())]);
+        let streams =
+            [("0:2", "en", "ass"), ("0:3", "zh", "mov_text")].map(|(stream_id, lang, codec)| {
+                SubtitleStream {
+                    source_file: "episode.mkv".into(),
+                    stream_id: stream_id.into(),
+                    language_code: lang.into(),
+                    codec: codec.into(),
+                }
+            });
+        let streams: Vec<&SubtitleStream> = streams.iter().collect();
+
+        let dumped =
+            dump_subtitle_files(&runner, &streams, dir.path(), Duration::from_secs(1)).unwrap();
+
+        // One ffmpeg run for both streams, ASS kept as is, and the empty one
+        // is dropped
+        let commands = runner.commands.lock().unwrap();
+        assert_eq!(commands.len(), 1);
+        let args: Vec<_> = commands[0]
+            .args
+            .iter()
+            .map(|a| a.to_string_lossy())
+            .collect();
+        assert_eq!(args.iter().filter(|a| *a == "-map").count(), 2);
+        assert!(args.join(" ").contains("-map 0:2 -c:s ass"));
+        assert!(args.join(" ").contains("-map 0:3 -c:s srt"));
+        assert_eq!(dumped.len(), 1);
+        assert_eq!(dumped[0].1, dir.path().join("0_2.en.ass"));
+    }
+
+    fn merge_context(media_file: &Path, runner: Arc<FakeRunner>) -> SubtitleMergeContext {
+        SubtitleMergeContext {
+            media_file: media_file.to_owned(),
+            runner,
+            cache: SubtitleCache::default(),
+            extract_timeout: Duration::from_secs(1),
+            clear_styles: HashSet::from(["signs".to_owned()]),
+            ocr: None,
+            output: OutputMode::Sidecar,
+            embed_default: false,
+            language_check: LanguageCheck::Override,
+            detection: Arc::new(LanguageDetection::default()),
+            strip_furigana: false,
+            strip_romaji: false,
+            reflow: None,
+            pinyin: None,
+            naming: SidecarNaming::default(),
+            overwrite: OverwritePolicy::Always,
+            backup_sidecars: false,
+        }
+    }
+
+    #[cfg(feature = "matroska")]
+    #[test]
+    fn test_extract_and_merge_reads_matroska_natively() {
+        let dir = tempfile::tempdir().unwrap();
+        let media_file = dir.path().join("episode.mkv");
+        std::fs::copy("../test/jjk_s02e01/input.mks", &media_file).unwrap();
+        // Runs out of results, failing the test, if ffmpeg or ffprobe is used
+        let runner = Arc::new(FakeRunner::new([]));
+        let context = merge_context(&media_file, runner.clone());
+
+        let mut report = ProcessingReport::new(&media_file);
+        let written = extract_and_merge(&context, &mut report).unwrap();
+
+        assert!(runner.commands.lock().unwrap().is_empty());
+        assert_eq!(
+            written,
+            ["en", "zh", "zh-TW"].map(|lang| dir.path().join(format!("episode.{lang}.srt")))
+        );
+        // Styles were still around to clear signs by
+        let subtitle_dir = context.cache.entry_dir(&media_file).unwrap();
+        assert!(subtitle_dir.join("0_0.en.ass").exists());
+        let en = std::fs::read_to_string(&written[0]).unwrap();
+        assert!(en.contains("Curses were springing up like maggots."));
+        assert!(!en.contains("TRASH"));
+        assert!(report.streams.iter().all(|s| s.selected));
+        assert_eq!(report.candidates[0].language_code, "en");
+        assert!(
+            report
+                .cleaning
+                .iter()
+                .any(|c| c.rule == "clear-styles" && c.events > 0)
+        );
+        assert_eq!(report.outputs, written);
+    }
+
+    #[cfg(feature = "matroska")]
+    #[test]
+    fn test_extract_and_merge_overwrites_own_sidecars_of_upgraded_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let media_file = dir.path().join("episode.mkv");
+        std::fs::copy("../test/jjk_s02e01/input.mks", &media_file).unwrap();
+        let context = SubtitleMergeContext {
+            cache: SubtitleCache::new(&CacheArgs {
+                cache_dir: Some(dir.path().join("cache")),
+            }),
+            overwrite: OverwritePolicy::IfOurs,
+            ..merge_context(&media_file, Arc::new(FakeRunner::new([])))
+        };
+        let first = extract_and_merge(&context, &mut ProcessingReport::new(&media_file)).unwrap();
+
+        // An upgrade is a different file, with an entry of its own
+        let mut upgraded = std::fs::read(&media_file).unwrap();
+        upgraded.extend([0xEC, 0x81, 0x00]);
+        std::fs::write(&media_file, upgraded).unwrap();
+        let again = extract_and_merge(&context, &mut ProcessingReport::new(&media_file)).unwrap();
+
+        assert_eq!(again, first);
+    }
+
+    #[test]
+    fn test_extract_and_merge_without_english() {
+        let dir = tempfile::tempdir().unwrap();
+        let media_file = dir.path().join("episode.mp4");
+        std::fs::write(&media_file, "").unwrap();
+        let runner = Arc::new(FakeRunner::new([Ok(ProcessOutput {
+            stdout: String::new(),
+            stderr: "Input #0, mov,mp4,m4a,3gp,3g2,mj2, from 'episode.mp4':\n".to_owned(),
+        })]));
+
+        let mut report = ProcessingReport::new(&media_file);
+        let error =
+            extract_and_merge(&merge_context(&media_file, runner), &mut report).unwrap_err();
+
+        assert!(matches!(error, Error::NoSuitableSubtitles { path } if path == media_file));
+    }
+
+    #[rstest]
+    #[case(false, LanguageCheck::Flag, &["0:2"])]
+    #[case(true, LanguageCheck::Flag, &["0:2", "0:4"])]
+    #[case(false, LanguageCheck::Override, &["0:2", "0:7"])]
+    fn test_wanted_streams(
+        #[case] ocr: bool,
+        #[case] language_check: LanguageCheck,
+        #[case] should: &[&str],
+    ) {
+        let stream = |index, language_code: &str, codec: &str| SubtitleStream {
+            source_file: PathBuf::from("episode.mkv"),
+            stream_id: format!("0:{index}"),
+            language_code: language_code.into(),
+            codec: codec.into(),
+        };
+        let streams = [
+            stream(2, "en", "ass"),
+            // English already has a text stream
+            stream(3, "en", "hdmv_pgs_subtitle"),
+            stream(4, "zh", "dvd_subtitle"),
+            stream(5, "jpn", "hdmv_pgs_subtitle"),
+            stream(6, "zh", "dvb_subtitle"),
+            stream(7, "und", "subrip"),
+        ];
+
+        let got: Vec<_> = wanted_streams(&streams, ocr, language_check)
+            .into_iter()
+            .map(|s| s.stream_id.as_str())
+            .collect();
+
+        assert_eq!(got, should);
+    }
+
+    #[rstest]
+    #[case(LanguageCheck::Override, "zh", Some(("zh", 0.9)), LanguageDecision::Keep)]
+    #[case(LanguageCheck::Override, "und", Some(("zh", 0.9)), LanguageDecision::Relabel("zh".into()))]
+    #[case(LanguageCheck::Override, "en", Some(("fr", 0.9)), LanguageDecision::Drop)]
+    #[case(LanguageCheck::Override, "en", Some(("zh", 0.4)), LanguageDecision::Keep)]
+    #[case(LanguageCheck::Override, "und", None, LanguageDecision::Drop)]
+    #[case(LanguageCheck::Flag, "en", Some(("zh", 0.9)), LanguageDecision::Flag("zh".into()))]
+    fn test_decide_language(
+        #[case] check: LanguageCheck,
+        #[case] tagged: &str,
+        #[case] detected: Option<(&str, f64)>,
+        #[case] should: LanguageDecision,
+    ) {
+        assert_eq!(decide_language(check, tagged, detected), should);
+    }
+
+    #[test]
+    fn test_check_languages_relabels_untagged_stream() {
+        let dir = tempfile::tempdir().unwrap();
+        let dumped = dir.path().join("0_3.und.ass");
+        std::fs::copy("../test/jjk_s02e01/extracted.zh.ass", &dumped).unwrap();
+        let stream = SubtitleStream {
+            source_file: PathBuf::from("episode.mkv"),
+            stream_id: "0:3".into(),
+            language_code: "und".into(),
+            codec: "ass".into(),
+        };
+        let mut report = ProcessingReport::default();
+
+        let got = check_languages(
+            LanguageCheck::Override,
+            &LanguageDetection::default(),
+            vec![(stream, dumped)],
+            &mut report,
+        )
+        .unwrap();
+
+        assert_eq!(report.languages[0].decision, "relabel as zh");
+        assert_eq!(got.len(), 1);
+        assert_eq!(got[0].0.language_code, "zh");
+        assert_eq!(got[0].1, dir.path().join("0_3.zh.ass"));
+        assert!(got[0].1.exists());
+    }
+
+    #[rstest]
+    #[case("../test/cjk/ja.srt", "zh", "ja")]
+    #[case("../test/cjk/ko.srt", "ko", "ko")]
+    #[case("../test/jjk_s02e01/extracted.zh-TW.ass", "zh", "zh-TW")]
+    fn test_ensure_cjk_script(#[case] fixture: &str, #[case] tagged: &str, #[case] should: &str) {
+        let dir = tempfile::tempdir().unwrap();
+        let extension = Path::new(fixture).extension().unwrap().to_string_lossy();
+        let dumped = dir.path().join(format!("0_3.{tagged}.{extension}"));
+        std::fs::copy(fixture, &dumped).unwrap();
+
+        let (language_code, path) = ensure_cjk_script(&dumped, tagged).unwrap();
+
+        assert_eq!(language_code, should);
+        assert_eq!(path, dir.path().join(format!("0_3.{should}.{extension}")));
+        assert!(path.exists());
+    }
+
+    #[test]
+    fn test_split_mixed_tracks() {
+        let dir = tempfile::tempdir().unwrap();
+        let dumped = dir.path().join("0_3.zh.ass");
+        std::fs::copy("../test/mixed/chs_eng.ass", &dumped).unwrap();
+        let stream = SubtitleStream {
+            source_file: PathBuf::from("episode.mkv"),
+            stream_id: "0:3".into(),
+            language_code: "zh".into(),
+            codec: "ass".into(),
+        };
+
+        let mut report = ProcessingReport::default();
+
+        let got = split_mixed_tracks(
+            &LanguageDetection::default(),
+            vec![(stream, dumped)],
+            &mut report,
+        )
+        .unwrap();
+
+        let got: Vec<_> = got
+            .iter()
+            .map(|(s, path)| (s.language_code.as_str(), path.clone()))
+            .collect();
+        assert_eq!(
+            got,
+            [
+                ("zh", dir.path().join("0_3.zh.ass")),
+                ("en", dir.path().join("0_3.en.ass")),
+            ]
+        );
+        let en = std::fs::read_to_string(dir.path().join("0_3.en.ass")).unwrap();
+        assert!(en.contains("We have to find him."));
+        assert!(!en.contains("我们必须找到他。"));
+    }
+
+    #[rstest]
+    #[case(&["0_2.en.srt", "0_3.en.ass"], Some("0_3.en.ass"))]
+    #[case(&["0_2.en.srt", "0_2.en.plain.srt"], Some("0_2.en.srt"))]
+    #[case(&["0_2.zh.ass", "0_3.zh-TW.ass"], None)]
+    fn test_get_best_en(#[case] files: &[&str], #[case] should: Option<&str>) {
+        let dir = tempfile::tempdir().unwrap();
+        for file in files {
+            std::fs::write(dir.path().join(file), "").unwrap();
+        }
+
+        let got = get_best_en(dir.path());
+
+        assert_eq!(got, should.map(|file| dir.path().join(file)));
+    }
+}
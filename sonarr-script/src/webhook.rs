@@ -0,0 +1,121 @@
+//! Sonarr and Radarr webhook payloads.
+
+use std::path::PathBuf;
+
+use anyhow::Context;
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::pipeline::EventType;
+use crate::pipeline::ImportJob;
+
+/// The subset of a Sonarr or Radarr webhook payload needed to locate the
+/// imported media file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookPayload {
+    pub event_type: String,
+    #[serde(default)]
+    pub is_upgrade: bool,
+    pub series: Option<WebhookItem>,
+    pub episode_file: Option<WebhookMediaFile>,
+    pub movie: Option<WebhookItem>,
+    pub movie_file: Option<WebhookMediaFile>,
+}
+
+/// A Sonarr series or a Radarr movie.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookItem {
+    pub id: u64,
+    /// Series root folder in Sonarr
+    pub path: Option<PathBuf>,
+    /// Movie root folder in Radarr
+    pub folder_path: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookMediaFile {
+    pub path: Option<PathBuf>,
+    pub relative_path: Option<PathBuf>,
+}
+
+impl WebhookPayload {
+    /// Maps the webhook event onto the Custom Script event types. Events
+    /// that have no Custom Script counterpart map to `None`.
+    pub fn event_type(&self) -> Option<EventType> {
+        match self.event_type.as_str() {
+            "Test" => Some(EventType::Test),
+            "Download" => Some(EventType::Import),
+            _ => None,
+        }
+    }
+
+    /// Builds the import job for a `Download` event.
+    pub fn import_job(&self) -> Result<ImportJob> {
+        let (item, file) = match (
+            &self.series,
+            &self.episode_file,
+            &self.movie,
+            &self.movie_file,
+        ) {
+            (Some(series), Some(file), _, _) => (series, file),
+            (_, _, Some(movie), Some(file)) => (movie, file),
+            _ => anyhow::bail!("payload has neither an episode file nor a movie file"),
+        };
+
+        let media_file = match (&file.path, &file.relative_path) {
+            (Some(path), _) => path.clone(),
+            (None, Some(relative_path)) => item
+                .path
+                .as_ref()
+                .or(item.folder_path.as_ref())
+                .context("payload has a relative media path but no root folder")?
+                .join(relative_path),
+            (None, None) => anyhow::bail!("payload media file has no path"),
+        };
+
+        Ok(ImportJob {
+            media_file,
+            series_id: self.series.as_ref().map(|series| series.id),
+            movie_id: self.movie.as_ref().map(|movie| movie.id),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[rstest]
+    #[case(
+        r#"{"eventType":"Download","isUpgrade":false,"series":{"id":3,"path":"/tv/Show"},"episodeFile":{"relativePath":"Season 1/episode.mkv","path":"/tv/Show/Season 1/episode.mkv"}}"#,
+        ImportJob { media_file: "/tv/Show/Season 1/episode.mkv".into(), series_id: Some(3), movie_id: None },
+    )]
+    #[case(
+        r#"{"eventType":"Download","series":{"id":3,"path":"/tv/Show"},"episodeFile":{"relativePath":"Season 1/episode.mkv"}}"#,
+        ImportJob { media_file: "/tv/Show/Season 1/episode.mkv".into(), series_id: Some(3), movie_id: None },
+    )]
+    #[case(
+        r#"{"eventType":"Download","movie":{"id":9,"folderPath":"/movies/Movie (2020)"},"movieFile":{"relativePath":"movie.mkv"}}"#,
+        ImportJob { media_file: "/movies/Movie (2020)/movie.mkv".into(), series_id: None, movie_id: Some(9) },
+    )]
+    fn test_import_job(#[case] payload: &str, #[case] should: ImportJob) {
+        let payload: WebhookPayload = serde_json::from_str(payload).unwrap();
+
+        assert_eq!(payload.event_type(), Some(EventType::Import));
+        assert_eq!(payload.import_job().unwrap(), should);
+    }
+
+    #[rstest]
+    #[case(r#"{"eventType":"Test"}"#, Some(EventType::Test))]
+    #[case(r#"{"eventType":"Grab"}"#, None)]
+    fn test_event_type(#[case] payload: &str, #[case] should: Option<EventType>) {
+        let payload: WebhookPayload = serde_json::from_str(payload).unwrap();
+
+        assert_eq!(payload.event_type(), should);
+    }
+}